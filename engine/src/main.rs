@@ -1,11 +1,12 @@
 use std::process::exit;
 
 use include_dir::{Dir, include_dir};
-use langjam_gamejam_lang::{BinaryOperator, Declaration, DisplayConfig, Expression, InputReport, Interpreter, Pixel, Statement, Tone, parse};
+use langjam_gamejam_lang::{BinaryOperator, DebugMessage, Declaration, DisplayConfig, Expression, InputReport, Interpreter, Pixel, Statement, TimeConfig, parse_file_reporting_all};
 use raylib::prelude::*;
 
-use crate::tone_player::TonePlayer;
+use crate::{storage::SaveFile, tone_player::TonePlayer};
 
+mod storage;
 mod tone_player;
 
 const PIXEL_SIZE: i32 = 10;
@@ -13,6 +14,36 @@ const PIXEL_SIZE: i32 = 10;
 const WINDOW_WIDTH: i32 = 640;
 const WINDOW_HEIGHT: i32 = 480;
 
+const FPS: u32 = 30;
+
+/// How many statements and expressions the game may evaluate in each tick or draw, so that an
+/// infinite loop shows an error instead of freezing the window
+const STEP_LIMIT: usize = 2_000_000;
+
+/// Where values from the game's `Storage` are saved, relative to the working directory
+const SAVE_FILE_PATH: &str = "save.txt";
+
+/// Colours which sprite pixels can be set to, by index.
+/// Index 0 is black, so that `#` pixels are drawn the same as they always have been.
+const PALETTE: [Color; 16] = [
+    Color::new(0, 0, 0, 255),       // 0: black
+    Color::new(29, 43, 83, 255),    // 1: dark blue
+    Color::new(126, 37, 83, 255),   // 2: dark purple
+    Color::new(0, 135, 81, 255),    // 3: dark green
+    Color::new(171, 82, 54, 255),   // 4: brown
+    Color::new(95, 87, 79, 255),    // 5: dark grey
+    Color::new(194, 195, 199, 255), // 6: light grey
+    Color::new(255, 241, 232, 255), // 7: white
+    Color::new(255, 0, 77, 255),    // 8: red
+    Color::new(255, 163, 0, 255),   // 9: orange
+    Color::new(255, 236, 39, 255),  // a: yellow
+    Color::new(0, 228, 54, 255),    // b: green
+    Color::new(41, 173, 255, 255),  // c: blue
+    Color::new(131, 118, 156, 255), // d: lavender
+    Color::new(255, 119, 168, 255), // e: pink
+    Color::new(255, 204, 170, 255), // f: peach
+];
+
 const GAME_FILES: Dir = include_dir!("$CARGO_MANIFEST_DIR/../game");
 
 fn main() {
@@ -20,7 +51,7 @@ fn main() {
         .size(WINDOW_WIDTH, WINDOW_HEIGHT)
         .title("SPACE BLASTER")
         .build();
-    rl.set_target_fps(30);
+    rl.set_target_fps(FPS);
 
     let mut audio_initialised = false;
     let mut tone_player = TonePlayer::new();
@@ -33,26 +64,61 @@ fn main() {
     files.sort_by_key(|f| f.path().file_name().unwrap().to_ascii_lowercase());
     println!("{files:?}");
 
+    // Report errors from every file before giving up
     let mut declarations = vec![];
+    let mut any_errors = false;
     for file in files {
-        match parse(file.contents_utf8().unwrap()) {
+        match parse_file_reporting_all(file.contents_utf8().unwrap(), &file.path().to_string_lossy()) {
             Ok(decls) => declarations.extend(decls),
-            Err(err) => {
-                println!("Error loading `{}`: {}", file.path().to_string_lossy(), err);
-                exit(1);
+            Err(errs) => {
+                for err in errs {
+                    println!("Error loading `{}`: {}", file.path().to_string_lossy(), err);
+                }
+                any_errors = true;
             }
         }
     }
+    if any_errors {
+        exit(1);
+    }
 
     let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.set_step_limit(Some(STEP_LIMIT));
 
     interpreter.update_display_config(DisplayConfig {
         width: (WINDOW_WIDTH / PIXEL_SIZE) as usize,
         height: (WINDOW_HEIGHT / PIXEL_SIZE) as usize,
+        palette_size: PALETTE.len(),
+    });
+    interpreter.update_time_config(TimeConfig {
+        ticks_per_second: FPS as f64,
     });
 
-    interpreter.execute_init().unwrap();
+    let mut save_file = SaveFile::load(SAVE_FILE_PATH);
+    interpreter.load_storage(save_file.values().clone());
+
+    // Once the game hits a runtime error it stops, and the error is shown in the window
+    let mut error = interpreter.execute_init().err();
+    if let Some(err) = &error {
+        println!("{err}");
+    }
     while !rl.window_should_close() {
+        if let Some(err) = &error {
+            let mut d = rl.begin_drawing(&thread);
+            d.clear_background(Color::WHITE);
+            d.draw_text(&err.to_string(), 10, 10, 10, Color::RED);
+            continue;
+        }
+
+        // Restarting is done between frames, since the game can't reset itself while it's ticking
+        if interpreter.take_restart_request()
+            && let Err(err) = interpreter.reset()
+        {
+            println!("{err}");
+            error = Some(err);
+            continue;
+        }
+
         interpreter.update_input_report(InputReport {
             up: rl.is_key_down(KeyboardKey::KEY_UP),
             down: rl.is_key_down(KeyboardKey::KEY_DOWN),
@@ -61,6 +127,11 @@ fn main() {
 
             x: rl.is_key_down(KeyboardKey::KEY_X),
             z: rl.is_key_down(KeyboardKey::KEY_Z),
+
+            mouse_x: (rl.get_mouse_x() / PIXEL_SIZE) as f64,
+            mouse_y: (rl.get_mouse_y() / PIXEL_SIZE) as f64,
+            mouse_left: rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT),
+            mouse_right: rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_RIGHT),
         });
 
         // Because of The Web (TM), we're only allowed to initialise audio once there's been a user
@@ -74,10 +145,31 @@ fn main() {
             audio_initialised = true;
         }
 
-        let sounds = interpreter.execute_tick().unwrap();
+        let sounds = match interpreter.execute_tick() {
+            Ok(sounds) => sounds,
+            Err(err) => {
+                println!("{err}");
+                error = Some(err);
+                continue;
+            }
+        };
         for sound in sounds {
-            let Tone { note, duration } = sound;
-            tone_player.play_sound(note, (duration * 1000.0) as usize);
+            tone_player.play_sound(sound);
+        }
+
+        // Only show debug messages in debug builds
+        for message in interpreter.take_debug_messages() {
+            if cfg!(debug_assertions) {
+                match message {
+                    DebugMessage::Log(message) => println!("{message}"),
+                    DebugMessage::Warning(message) => println!("Warning: {message}"),
+                }
+            }
+        }
+
+        // Losing a save isn't worth crashing the game over
+        if let Err(err) = save_file.write(interpreter.take_storage_writes()) {
+            println!("Error writing save file: {err}");
         }
 
         let fps = rl.get_fps();
@@ -85,17 +177,25 @@ fn main() {
         let mut d = rl.begin_drawing(&thread);
         d.clear_background(Color::WHITE);
 
-        for draw_op in interpreter.execute_draw().unwrap() {
+        let draw_ops = match interpreter.execute_draw() {
+            Ok(draw_ops) => draw_ops,
+            Err(err) => {
+                println!("{err}");
+                error = Some(err);
+                continue;
+            }
+        };
+        for draw_op in draw_ops {
             let base_x = draw_op.x as i32 * PIXEL_SIZE;
             let base_y = draw_op.y as i32 * PIXEL_SIZE;
             
             for dx in 0..draw_op.sprite.width {
                 for dy in 0..draw_op.sprite.height {
-                    if draw_op.sprite.pixels[dy * draw_op.sprite.width + dx] == Pixel::Set {
+                    if let Pixel::Set(colour) = draw_op.sprite.pixels[dy * draw_op.sprite.width + dx] {
                         let canvas_x = base_x + dx as i32 * PIXEL_SIZE;
                         let canvas_y = base_y + dy as i32 * PIXEL_SIZE;
             
-                        d.draw_rectangle(canvas_x, canvas_y, PIXEL_SIZE, PIXEL_SIZE, Color::BLACK);
+                        d.draw_rectangle(canvas_x, canvas_y, PIXEL_SIZE, PIXEL_SIZE, PALETTE[colour as usize]);
                     }
                 }
             }
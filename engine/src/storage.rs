@@ -0,0 +1,93 @@
+//! Persists the game's `Storage` values to a file, so that things like high scores survive the
+//! window being closed.
+//!
+//! The file has one value per line, as `key<TAB>type:value`, where the type is `n` (number),
+//! `b` (boolean) or `s` (string). Tabs, newlines and backslashes in keys and strings are escaped.
+
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use langjam_gamejam_lang::Object;
+
+pub struct SaveFile {
+    path: PathBuf,
+    values: HashMap<String, Object>,
+}
+
+impl SaveFile {
+    /// Loads the save file at `path`, or starts an empty one if it doesn't exist yet.
+    /// Lines which can't be understood are skipped, rather than losing the whole save.
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let values = fs::read_to_string(&path)
+            .map(|contents| contents.lines().filter_map(parse_line).collect())
+            .unwrap_or_default();
+
+        Self { path, values }
+    }
+
+    pub fn values(&self) -> &HashMap<String, Object> {
+        &self.values
+    }
+
+    /// Merges new values into the save, and writes the file if anything changed.
+    pub fn write(&mut self, writes: HashMap<String, Object>) -> io::Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        self.values.extend(writes);
+
+        // Sort so that the file doesn't get shuffled around on every write
+        let mut lines = self.values.iter()
+            .filter_map(|(key, value)| Some(format!("{}\t{}", escape(key), format_value(value)?)))
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        fs::write(&self.path, lines.join("\n"))
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, Object)> {
+    let (key, value) = line.split_once('\t')?;
+    let value = match value.split_once(':')? {
+        ("n", n) => Object::Number(n.parse().ok()?),
+        ("b", b) => Object::Boolean(b.parse().ok()?),
+        ("s", s) => Object::String(unescape(s)?),
+        _ => return None,
+    };
+
+    Some((unescape(key)?, value))
+}
+
+fn format_value(value: &Object) -> Option<String> {
+    match value {
+        Object::Number(n) => Some(format!("n:{n}")),
+        Object::Boolean(b) => Some(format!("b:{b}")),
+        Object::String(s) => Some(format!("s:{}", escape(s))),
+
+        // `Storage` doesn't allow anything else to be stored
+        _ => None,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            result.push(match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                _ => return None,
+            });
+        } else {
+            result.push(c);
+        }
+    }
+
+    Some(result)
+}
@@ -4,11 +4,11 @@
 
 use std::{collections::HashMap, f64::consts::PI, ffi::c_void};
 
-use langjam_gamejam_lang::Note;
+use langjam_gamejam_lang::{Note, Tone};
 use raylib::{audio::RaylibAudio, ffi};
 
 pub struct TonePlayer {
-    sounds: HashMap<(Note, usize), ffi::Sound>,
+    sounds: HashMap<(Note, Option<Note>, usize, usize), ffi::Sound>,
 }
 
 const SAMPLE_RATE: u32 = 44100;
@@ -20,21 +20,25 @@ impl TonePlayer {
         }
     }
 
-    pub fn play_sound(&mut self, note: Note, duration_millis: usize) {
-        let sound = self.make_sound(note, duration_millis);
+    pub fn play_sound(&mut self, tone: Tone) {
+        let sound = self.make_sound(tone);
         unsafe { ffi::PlaySound(sound); }
     }
 
-    fn make_sound(&mut self, note: Note, duration_millis: usize) -> ffi::Sound {
+    fn make_sound(&mut self, tone: Tone) -> ffi::Sound {
+        let duration_millis = (tone.duration * 1000.0) as usize;
+        let volume_percent = (tone.volume * 100.0).round() as usize;
+        let key = (tone.note, tone.end_note, duration_millis, volume_percent);
+
         // Cache waves to:
         //   - Avoid recalculation for sounds which have been played before
         //   - "Solve" lifetime issues by making them effectively static
-        if let Some(sound) = self.sounds.get(&(note, duration_millis)) {
+        if let Some(sound) = self.sounds.get(&key) {
             return sound.clone();
         }
 
-        let frequency = note.frequency();
         let duration = (duration_millis as f64) / 1000.0;
+        let volume = (volume_percent as f64) / 100.0;
         let num_samples = (SAMPLE_RATE as f64 * duration) as usize;
 
         // Without a fade, there's a sharp "click" at the beginning of some notes - I'm not enough
@@ -43,9 +47,13 @@ impl TonePlayer {
 
         // Claude special :(
         let mut samples: Vec<i16> = vec![0; num_samples];
+        let mut phase: f64 = 0.0;
         for i in 0..num_samples {
-            let t = i as f64 / SAMPLE_RATE as f64;
-            let sample = (2.0 * PI * frequency * t).sin();
+            // Accumulate phase rather than using `frequency * t`, so that the wave stays continuous
+            // if the frequency changes over the duration of the tone
+            let frequency = tone.frequency_at(i as f64 / num_samples as f64);
+            let sample = phase.sin();
+            phase += 2.0 * PI * frequency / SAMPLE_RATE as f64;
             
             let envelope = if i < fade_samples {
                 // Fade in
@@ -56,7 +64,7 @@ impl TonePlayer {
             } else {
                 1.0
             };
-            samples[i] = (sample * envelope * i16::MAX as f64 * 0.25) as i16;
+            samples[i] = (sample * envelope * volume * i16::MAX as f64 * 0.25) as i16;
         }
         
         // `sounds` hash ensures we don't leak any more memory than we need to
@@ -70,7 +78,7 @@ impl TonePlayer {
         };
 
         let sound = unsafe { ffi::LoadSoundFromWave(wave) };
-        self.sounds.insert((note, duration_millis), sound.clone());
+        self.sounds.insert(key, sound.clone());
 
         sound
     }
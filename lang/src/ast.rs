@@ -1,3 +1,7 @@
+use std::{fmt::Display, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone)]
 pub enum Declaration {
     EntityDeclaration {
@@ -8,13 +12,38 @@ pub enum Declaration {
         body: Vec<Statement>,
     },
     TickDeclaration {
+        /// Given with `tick priority N`. Lower priorities tick first
+        priority: Option<f64>,
         body: Vec<Statement>,
     },
     DrawDeclaration {
         body: Vec<Statement>,
     },
+    DestroyDeclaration {
+        body: Vec<Statement>,
+    },
+    PressDeclaration {
+        key: String,
+        body: Vec<Statement>,
+    },
+    ReleaseDeclaration {
+        key: String,
+        body: Vec<Statement>,
+    },
+    CollisionDeclaration {
+        /// The name of the local which the other colliding entity is bound to
+        parameter: String,
+        body: Vec<Statement>,
+    },
+    MessageDeclaration {
+        /// The names of the locals which the message's name and its array of arguments are bound to
+        name_parameter: String,
+        arguments_parameter: String,
+        body: Vec<Statement>,
+    },
     InstanceVarDeclaration {
-        names: Vec<String>,
+        /// Each instance variable, with an optional expression for its initial value
+        ivars: Vec<(String, Option<Expression>)>,
     },
     FunctionDeclaration {
         name: String,
@@ -23,6 +52,40 @@ pub enum Declaration {
     },
     UseDeclaration {
         name: String,
+        filter: UseFilter,
+    },
+    ConstDeclaration {
+        name: String,
+        value: Expression,
+    },
+}
+
+/// Which definitions a `use` declaration copies from its source entity. Names may refer to
+/// functions, or to the `constructor`, `tick`, `draw`, `on_destroy`, `on_collision`,
+/// `on_message`, `on_press` and `on_release` handlers.
+/// 
+/// Instance variables are always copied, since functions are likely to need them.
+#[derive(Debug, Clone)]
+pub enum UseFilter {
+    All,
+    Only(Vec<String>),
+    Except(Vec<String>),
+}
+
+impl UseFilter {
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            UseFilter::All => true,
+            UseFilter::Only(names) => names.iter().any(|n| n == name),
+            UseFilter::Except(names) => !names.iter().any(|n| n == name),
+        }
+    }
+
+    pub fn names(&self) -> &[String] {
+        match self {
+            UseFilter::All => &[],
+            UseFilter::Only(names) | UseFilter::Except(names) => names,
+        }
     }
 }
 
@@ -34,16 +97,67 @@ pub enum Statement {
         true_body: Vec<Statement>,
         false_body: Option<Vec<Statement>>,
     },
+    Match {
+        scrutinee: Expression,
+        arms: Vec<(Expression, Vec<Statement>)>,
+        else_body: Option<Vec<Statement>>,
+    },
     EachLoop {
         variable: String,
+        /// If given, a local which is set to the zero-based index of each item
+        index_variable: Option<String>,
         source: Expression,
         body: Vec<Statement>,
     },
+    /// `repeat { ... } until (condition);` - runs the body at least once
+    RepeatUntil {
+        body: Vec<Statement>,
+        condition: Expression,
+    },
     Assignment {
         target: Expression,
         value: Expression,
     },
+    /// `[a, b] = value;` - assigns each element of an array to the corresponding target
+    DestructuringAssignment {
+        targets: Vec<Expression>,
+        value: Expression,
+    },
     Return(Option<Expression>),
+
+    /// Records where the parser found `statement`, so that runtime errors can point to it. The
+    /// parser wraps every statement in one of these
+    Located {
+        span: Span,
+        statement: Box<Statement>,
+    },
+}
+
+/// A position in the source code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// The name of the file which the source came from, if it was parsed with
+    /// [`crate::parse_file_reporting_all`]
+    pub file: Option<Rc<str>>,
+
+    /// 1-indexed line number
+    pub line: usize,
+
+    /// 1-indexed column number, in characters
+    pub column: usize,
+
+    /// Byte offset from the start of the source. While parsing is still in progress, this is
+    /// instead the number of bytes until the end of the source
+    pub(crate) offset: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{file}:{}", self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -52,32 +166,53 @@ pub enum Expression {
     NullLiteral,
     NumberLiteral(f64),
     BooleanLiteral(bool),
+    StringLiteral(String),
     ArrayLiteral(Vec<Expression>),
     Identifier(String),
     InstanceVarIdentifier(String), // @var
+    ForeignInstanceVarIdentifier { // entity@var
+        target: Box<Expression>,
+        name: String,
+    },
 
     SpriteLiteral(Sprite),
     SoundLiteral(Tone),
+    MelodyLiteral(Vec<Tone>),
 
     FunctionCall {
         target: Box<Expression>,
         name: String,
         arguments: Vec<Expression>,
     },
+    /// A call without a target, like `move_left()`. Resolves to a function on the current entity,
+    /// or failing that, a host function.
+    BareFunctionCall {
+        name: String,
+        arguments: Vec<Expression>,
+    },
     BinaryOperation {
         left: Box<Expression>,
         right: Box<Expression>,
         operator: BinaryOperator,
     },
 
+    Range {
+        start: Box<Expression>,
+        end: Box<Expression>,
+        step: Option<Box<Expression>>,
+    },
+
     SpawnEntity {
         // TODO: constructor parameters probably necessary later
         name: String,
+
+        /// From `with { x: 1, y: 2 }`, values to assign to instance variables on the new entity
+        initializers: Vec<(String, Expression)>,
     },
 
     DestroyEntity(Box<Expression>),
 
-    Echo(Box<Expression>),
+    Echo(Vec<Expression>),
 }
 
 #[derive(Debug, Clone)]
@@ -86,6 +221,7 @@ pub enum BinaryOperator {
     Subtract,
     Multiply,
     Divide,
+    Power,
 
     Equals,
     NotEquals,
@@ -93,12 +229,16 @@ pub enum BinaryOperator {
     GreaterThan,
     LessThanOrEquals,
     GreaterThanOrEquals,
+    In,
 
     And,
     Or,
+
+    /// `??` - the left side, or the right side if the left side is null
+    NullCoalesce,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Sprite {
     pub width: usize,
     pub height: usize,
@@ -112,33 +252,184 @@ pub struct Sprite {
     pub pixels: Vec<Pixel>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl Sprite {
+    /// Builds a sprite by calling `pixel` with the `(x, y)` position of each pixel.
+    pub fn from_fn(width: usize, height: usize, mut pixel: impl FnMut(usize, usize) -> Pixel) -> Self {
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| pixel(x, y))
+            .collect();
+        Sprite { width, height, pixels }
+    }
+
+    /// The pixel at `(x, y)`, where `(0, 0)` is the top-left. Panics if out of bounds.
+    pub fn pixel(&self, x: usize, y: usize) -> Pixel {
+        assert!(x < self.width && y < self.height, "pixel ({x}, {y}) is outside of {}x{} sprite", self.width, self.height);
+        self.pixels[y * self.width + x]
+    }
+
+    /// Changes the pixel at `(x, y)`. Panics if out of bounds.
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: Pixel) {
+        assert!(x < self.width && y < self.height, "pixel ({x}, {y}) is outside of {}x{} sprite", self.width, self.height);
+        self.pixels[y * self.width + x] = pixel;
+    }
+
+    /// The `width` by `height` rectangle of this sprite whose top-left corner is at `(x, y)`. Panics
+    /// if the rectangle doesn't fit within the sprite.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Sprite {
+        assert!(x + width <= self.width && y + height <= self.height, "crop is outside of {}x{} sprite", self.width, self.height);
+        Sprite::from_fn(width, height, |dx, dy| self.pixel(x + dx, y + dy))
+    }
+
+    /// Stamps the set pixels of `other` onto this sprite, with its top-left corner at `(dx, dy)`.
+    /// Parts of `other` which fall outside of this sprite are clipped off, so the result is always
+    /// the same size as this sprite.
+    pub fn overlay(&self, other: &Sprite, dx: i64, dy: i64) -> Sprite {
+        Sprite::from_fn(self.width, self.height, |x, y| {
+            // Saturating keeps a far-away `other` outside of this sprite instead of overflowing
+            let (other_x, other_y) = ((x as i64).saturating_sub(dx), (y as i64).saturating_sub(dy));
+            if (0..other.width as i64).contains(&other_x) && (0..other.height as i64).contains(&other_y)
+                && let pixel @ Pixel::Set(_) = other.pixel(other_x as usize, other_y as usize)
+            {
+                pixel
+            } else {
+                self.pixel(x, y)
+            }
+        })
+    }
+
+    /// Whether any set pixels of this sprite and `other` are in the same place, when the top-left
+    /// corner of `other` is at `(dx, dy)`.
+    pub fn pixels_overlap(&self, other: &Sprite, dx: i64, dy: i64) -> bool {
+        // Only the area where the two sprites intersect needs checking. That's empty if `other` is
+        // so far away that its far edge can't be represented
+        let (start_x, end_x) = (dx.max(0), dx.saturating_add(other.width as i64).min(self.width as i64));
+        let (start_y, end_y) = (dy.max(0), dy.saturating_add(other.height as i64).min(self.height as i64));
+
+        (start_y..end_y).any(|y| (start_x..end_x).any(|x| {
+            matches!(self.pixel(x as usize, y as usize), Pixel::Set(_))
+                && matches!(other.pixel((x - dx) as usize, (y - dy) as usize), Pixel::Set(_))
+        }))
+    }
+
+    /// Mirrors the sprite horizontally, so the leftmost column becomes the rightmost.
+    pub fn flip_x(&self) -> Sprite {
+        Sprite::from_fn(self.width, self.height, |x, y| self.pixel(self.width - 1 - x, y))
+    }
+
+    /// Mirrors the sprite vertically, so the top row becomes the bottom.
+    pub fn flip_y(&self) -> Sprite {
+        Sprite::from_fn(self.width, self.height, |x, y| self.pixel(x, self.height - 1 - y))
+    }
+
+    /// Enlarges the sprite by turning each pixel into a `factor` by `factor` block.
+    pub fn scale(&self, factor: usize) -> Sprite {
+        Sprite::from_fn(self.width * factor, self.height * factor, |x, y| self.pixel(x / factor, y / factor))
+    }
+
+    /// Rotates the sprite a quarter-turn clockwise, swapping its width and height.
+    pub fn rotate90(&self) -> Sprite {
+        Sprite::from_fn(self.height, self.width, |x, y| self.pixel(y, self.height - 1 - x))
+    }
+
+    /// Rotates the sprite a half-turn.
+    pub fn rotate180(&self) -> Sprite {
+        Sprite::from_fn(self.width, self.height, |x, y| self.pixel(self.width - 1 - x, self.height - 1 - y))
+    }
+
+    /// Rotates the sprite a quarter-turn anticlockwise, swapping its width and height.
+    pub fn rotate270(&self) -> Sprite {
+        Sprite::from_fn(self.height, self.width, |x, y| self.pixel(self.width - 1 - y, x))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Pixel {
     Clear,
-    Set,
+
+    /// Set to a colour, as an index into the engine's 16-colour palette
+    Set(u8),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Note {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NoteName {
     A, B, C, D, E, F, G
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Note {
+    pub name: NoteName,
+    pub sharp: bool,
+
+    /// Scientific pitch notation octave, where the octave starting at middle C is 4
+    pub octave: i32,
+}
+
 impl Note {
+    /// The octave used when a note doesn't specify one.
+    pub const DEFAULT_OCTAVE: i32 = 4;
+
+    pub fn new(name: NoteName, sharp: bool, octave: i32) -> Self {
+        Note { name, sharp, octave }
+    }
+
+    /// How many semitones this note is above A4 (or below, if negative).
+    pub fn semitones_from_a4(self) -> i32 {
+        let semitones_from_c = match self.name {
+            NoteName::C => 0,
+            NoteName::D => 2,
+            NoteName::E => 4,
+            NoteName::F => 5,
+            NoteName::G => 7,
+            NoteName::A => 9,
+            NoteName::B => 11,
+        } + self.sharp as i32;
+
+        (self.octave - 4) * 12 + semitones_from_c - 9
+    }
+
+    /// The note which is `semitones` above this one (or below, if negative). Sharps are used for
+    /// notes between naturals.
+    pub fn transpose(self, semitones: i32) -> Note {
+        const NOTES: [(NoteName, bool); 12] = [
+            (NoteName::C, false), (NoteName::C, true), (NoteName::D, false), (NoteName::D, true),
+            (NoteName::E, false), (NoteName::F, false), (NoteName::F, true), (NoteName::G, false),
+            (NoteName::G, true), (NoteName::A, false), (NoteName::A, true), (NoteName::B, false),
+        ];
+
+        // Work in semitones from C4, so that octaves line up with `div_euclid`
+        let semitones_from_c4 = self.semitones_from_a4() + 9 + semitones;
+        let (name, sharp) = NOTES[semitones_from_c4.rem_euclid(12) as usize];
+        Note::new(name, sharp, 4 + semitones_from_c4.div_euclid(12))
+    }
+
+    /// The frequency of this note in 12-tone equal temperament, where A4 is concert pitch (440Hz).
     pub fn frequency(self) -> f64 {
-        match self {
-            Note::A => 440.0,
-            Note::B => 493.88,
-            Note::C => 261.63,
-            Note::D => 293.66,
-            Note::E => 329.63,
-            Note::F => 349.23,
-            Note::G => 392.0,
-        }
+        440.0 * 2.0_f64.powf(self.semitones_from_a4() as f64 / 12.0)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Tone {
     pub note: Note,
     pub duration: f64,
+
+    /// If set, the pitch bends linearly from `note` to this note over the tone's duration
+    pub end_note: Option<Note>,
+
+    /// How loud the tone is, from 0.0 (silent) to 1.0 (full volume)
+    pub volume: f64,
+}
+
+impl Tone {
+    pub const DEFAULT_VOLUME: f64 = 1.0;
+
+    /// The frequency of this tone at `progress` through its duration, from 0.0 to 1.0
+    pub fn frequency_at(&self, progress: f64) -> f64 {
+        let start = self.note.frequency();
+        match self.end_note {
+            Some(end_note) => start + (end_note.frequency() - start) * progress.clamp(0.0, 1.0),
+            None => start,
+        }
+    }
 }
@@ -0,0 +1,45 @@
+use crate::{Pixel, Sprite};
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+
+/// Blank columns between each glyph when rendering text
+const GLYPH_SPACING: usize = 1;
+
+/// Gets the built-in 3x5 font's glyph for a character, as rows of `#` (set) and `.` (clear)
+/// pixels, or `None` if the font doesn't have one.
+fn glyph(c: char) -> Option<[&'static str; GLYPH_HEIGHT]> {
+    Some(match c {
+        '0' => ["###", "#.#", "#.#", "#.#", "###"],
+        '1' => [".#.", "##.", ".#.", ".#.", "###"],
+        '2' => ["###", "..#", "###", "#..", "###"],
+        '3' => ["###", "..#", "###", "..#", "###"],
+        '4' => ["#.#", "#.#", "###", "..#", "..#"],
+        '5' => ["###", "#..", "###", "..#", "###"],
+        '6' => ["###", "#..", "###", "#.#", "###"],
+        '7' => ["###", "..#", "..#", "..#", "..#"],
+        '8' => ["###", "#.#", "###", "#.#", "###"],
+        '9' => ["###", "#.#", "###", "..#", "###"],
+        '-' => ["...", "...", "###", "...", "..."],
+        '.' => ["...", "...", "...", "...", ".#."],
+        _ => return None,
+    })
+}
+
+/// Renders a line of text into a black sprite using the built-in font.
+/// Returns the first character which the font can't draw as an error.
+pub fn render_text(text: &str) -> Result<Sprite, char> {
+    let glyphs = text.chars()
+        .map(|c| glyph(c).ok_or(c))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let width = (glyphs.len() * (GLYPH_WIDTH + GLYPH_SPACING)).saturating_sub(GLYPH_SPACING);
+    Ok(Sprite::from_fn(width, GLYPH_HEIGHT, |x, y| {
+        let (index, glyph_x) = (x / (GLYPH_WIDTH + GLYPH_SPACING), x % (GLYPH_WIDTH + GLYPH_SPACING));
+        if glyph_x < GLYPH_WIDTH && glyphs[index][y].as_bytes()[glyph_x] == b'#' {
+            Pixel::Set(0)
+        } else {
+            Pixel::Clear
+        }
+    }))
+}
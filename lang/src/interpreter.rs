@@ -1,103 +1,509 @@
-use std::{collections::{HashMap, HashSet}, error::Error, fmt::Display, ops::ControlFlow, rc::Rc, time::Instant};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, error::Error, fmt::Display, ops::ControlFlow, rc::Rc, time::Instant};
 
-use crate::{BinaryOperator, Declaration, Expression, Object, Sprite, Statement, Tone};
+use rand::{SeedableRng, rngs::StdRng};
+
+use crate::{ArrayCopier, BinaryOperator, Declaration, Expression, Object, Span, Sprite, Statement, Tone};
 
 pub struct Interpreter {
     top_level_constructor: Vec<Statement>,
 
-    pub(crate) entities: HashMap<EntityId, Entity>,
-    pub(crate) entities_by_kinds: HashMap<String, HashSet<EntityId>>,
-    next_entity_id: usize,
+    /// Entity IDs only ever increase, so ordering by ID means that entities are always handled
+    /// in the order they were spawned - both here and in `entities_by_kinds`
+    pub(crate) entities: BTreeMap<EntityId, Entity>,
+    pub(crate) entities_by_kinds: HashMap<String, BTreeSet<EntityId>>,
+    pub(crate) next_entity_id: usize,
     
     /// Entity destruction is delayed until a tick has finished, otherwise you encounter errors due
     /// to all of your instance variables disappearing underneath you!
-    entities_pending_destroy: HashSet<EntityId>,
+    pub(crate) entities_pending_destroy: BTreeSet<EntityId>,
+
+    /// Entities spawned during the current (or most recent) tick. These don't tick or draw until
+    /// the next frame, so that all entities spend their first frame in the same state
+    pub(crate) spawned_this_tick: BTreeSet<EntityId>,
+
+    /// Whether `execute_tick` is currently running
+    pub(crate) ticking: bool,
 
     /// Sounds that have been enqueued for play during this tick
     pub(crate) pending_sounds: Vec<Tone>,
 
-    entity_kinds: HashMap<String, Rc<EntityKind>>,
+    /// Messages sent with `send` or `send_all`, as the receiving entity, the message's name, and its
+    /// array of arguments. They're delivered in the order they were sent, once every entity has
+    /// ticked
+    pub(crate) pending_messages: Vec<(EntityId, String, Object)>,
+
+    /// Melodies which are currently playing, whose tones are added to `pending_sounds` as each one
+    /// becomes due
+    pub(crate) playing_melodies: Vec<MelodyPlayback>,
+
+    pub(crate) entity_kinds: HashMap<String, Rc<EntityKind>>,
+
+    /// Bodies of entity declarations given to [`Interpreter::with_declarations`] which haven't
+    /// been interpreted yet. A `use` of one of these interprets it early, so that entities can be
+    /// used before they are declared
+    pending_entity_declarations: HashMap<String, Vec<Declaration>>,
+
+    /// Names of the entity declarations currently being interpreted, innermost last, for
+    /// detecting circular `use`s
+    entities_being_declared: Vec<String>,
+
+    /// Values of top-level `const` declarations, evaluated once when they are declared
+    pub(crate) constants: HashMap<String, Object>,
+
+    /// Copies of `constants` as they were first declared. The arrays in `constants` can still be
+    /// changed, so [`Interpreter::reset`] puts these back
+    initial_constants: HashMap<String, Object>,
+
+    /// Game-wide state accessed through the `Global` singleton, which persists across ticks
+    pub(crate) globals: HashMap<String, Object>,
+
+    /// Position of the `Camera`, which is subtracted from the position of everything drawn
+    pub(crate) camera: (f64, f64),
+
+    /// Whether the entity currently being drawn has called `Camera.ignore()`, or `None` if no
+    /// `draw` block is running
+    pub(crate) draw_ignores_camera: Option<bool>,
+
+    /// Messages from the `Debug` singleton, which are held until the host takes them with
+    /// [`Interpreter::take_debug_messages`]
+    pub(crate) debug_messages: Vec<DebugMessage>,
+
+    /// Values accessed through the `Storage` singleton. The host is responsible for persisting
+    /// these between runs, using [`Interpreter::load_storage`] and
+    /// [`Interpreter::take_storage_writes`]
+    pub(crate) storage: HashMap<String, Object>,
+
+    /// Keys written to `storage` since the host last called [`Interpreter::take_storage_writes`]
+    pub(crate) storage_writes: HashSet<String>,
+
+    /// Native functions provided by the host, which are called through the `Host` singleton
+    pub(crate) host_functions: HashMap<String, Rc<HostFunction>>,
 
     pub(crate) input_report: InputReport,
+    pub(crate) previous_input_report: InputReport,
     pub(crate) display_config: DisplayConfig,
+    pub(crate) time_config: TimeConfig,
+
+    /// How many times `execute_tick` has been called, including the current tick if one is running
+    pub(crate) tick_count: u64,
+
+    /// How many statements and expressions may be evaluated by each call to `execute_init`,
+    /// `execute_tick` or `execute_draw` before it's aborted, or `None` for no limit
+    step_limit: Option<usize>,
+
+    /// Statements and expressions evaluated so far by the current `execute_*` call
+    steps: usize,
+
+    /// Whether arithmetic which results in infinity or NaN is an error
+    check_finite_numbers: bool,
+
+    /// Whether `Game.restart()` has been called since the host last called
+    /// [`Interpreter::take_restart_request`]
+    pub(crate) restart_requested: bool,
+
+    /// Names of the handlers and functions which are currently running, like `Enemy.tick`, with
+    /// the innermost last. Used to build the trace of a [`RuntimeError`]
+    call_stack: Vec<String>,
+
+    /// Source of randomness for `Math` functions. Seeded from the OS, unless replaced by a
+    /// deterministic one with `Math.seed`
+    pub(crate) rng: StdRng,
 }
 
+/// How many tones each playing melody may add to the sounds for a single tick.
+const MAX_TONES_PER_TICK: usize = 16;
+
 pub type InterpreterResult<T = ()> = Result<T, RuntimeError>;
 
+/// A native function registered by the host with [`Interpreter::register_function`].
+pub type HostFunction = dyn Fn(&mut Interpreter, Vec<Object>) -> InterpreterResult<Object>;
+
 impl Interpreter {
     pub fn new() -> Self {
         Self {
             top_level_constructor: vec![],
-            entities: HashMap::new(),
+            entities: BTreeMap::new(),
             entities_by_kinds: HashMap::new(),
 
             next_entity_id: 1,
-            entities_pending_destroy: HashSet::new(),
+            entities_pending_destroy: BTreeSet::new(),
+            spawned_this_tick: BTreeSet::new(),
+            ticking: false,
             pending_sounds: vec![],
+            pending_messages: vec![],
+            playing_melodies: vec![],
             entity_kinds: HashMap::new(),
+            pending_entity_declarations: HashMap::new(),
+            entities_being_declared: vec![],
+            constants: HashMap::new(),
+            initial_constants: HashMap::new(),
+            globals: HashMap::new(),
+            camera: (0.0, 0.0),
+            draw_ignores_camera: None,
+            debug_messages: vec![],
+            storage: HashMap::new(),
+            storage_writes: HashSet::new(),
+            host_functions: HashMap::new(),
             input_report: Default::default(),
+            previous_input_report: Default::default(),
             display_config: Default::default(),
+            time_config: Default::default(),
+            tick_count: 0,
+            step_limit: None,
+            check_finite_numbers: true,
+            restart_requested: false,
+            steps: 0,
+            call_stack: vec![],
+            rng: StdRng::from_os_rng(),
         }
     }
 
     pub fn with_declarations(declarations: &[Declaration]) -> InterpreterResult<Interpreter> {
         let mut interpreter = Self::new();
+
+        // Find every entity declaration first, so that `use` can refer to ones declared after it
         for decl in declarations {
+            if let Declaration::EntityDeclaration { name, body } = decl
+                && interpreter.pending_entity_declarations.insert(name.clone(), body.clone()).is_some()
+            {
+                return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("duplicate entity declaration `{name}`")));
+            }
+        }
+
+        for decl in declarations {
+            // Skip entities which were already interpreted early because of a `use`
+            if let Declaration::EntityDeclaration { name, .. } = decl
+                && !interpreter.pending_entity_declarations.contains_key(name)
+            {
+                continue;
+            }
+
             interpreter.interpret_declaration(decl, None)?;
         }
         Ok(interpreter)
     }
 
     pub fn execute_init(&mut self) -> InterpreterResult {
+        self.steps = 0;
+
         let mut frame = Frame {
             entity: None,
             locals: HashMap::new(),
         };
 
-        let _ = self.execute_statement_body(&self.top_level_constructor.clone(), &mut frame)?;
+        let _ = self.execute_named_body("top-level constructor".to_owned(), &self.top_level_constructor.clone(), &mut frame)?;
         
+        // Any keys held when the game starts count as having just been pressed
+        self.previous_input_report = InputReport::default();
+
         self.forbid_sound()?;
         Ok(())
     }
 
+    /// Puts the game back into the state it was in after it was first declared, and then runs
+    /// `execute_init` again, so that it can be restarted without parsing it again.
+    ///
+    /// Entities, globals, the camera, and everything queued for the host are cleared, and the tick
+    /// count starts again from zero. Constants go back to the values they were declared with.
+    /// Entity declarations are kept as they are, as are `Storage`, host functions, and
+    /// configuration from the host.
+    pub fn reset(&mut self) -> InterpreterResult {
+        self.clear_game_state();
+        self.constants = ArrayCopier::default().copy_map(&self.initial_constants);
+        self.execute_init()
+    }
+
+    /// Removes everything the game has created while running, ready for it to start again.
+    pub(crate) fn clear_game_state(&mut self) {
+        self.entities.clear();
+        self.entities_by_kinds.clear();
+        self.next_entity_id = 1;
+        self.entities_pending_destroy.clear();
+        self.spawned_this_tick.clear();
+        self.pending_sounds.clear();
+        self.pending_messages.clear();
+        self.playing_melodies.clear();
+        self.debug_messages.clear();
+        self.globals.clear();
+        self.camera = (0.0, 0.0);
+        self.draw_ignores_camera = None;
+        self.tick_count = 0;
+        self.call_stack.clear();
+        self.ticking = false;
+        self.restart_requested = false;
+    }
+
+    /// Whether the game has called `Game.restart()` since this was last called. The host should
+    /// respond by calling [`Interpreter::reset`] before the next tick.
+    pub fn take_restart_request(&mut self) -> bool {
+        std::mem::take(&mut self.restart_requested)
+    }
+
+    /// Sets which keys are held. This should be called once before each tick, since keys which are
+    /// "just pressed" are found by comparing against the previous report.
     pub fn update_input_report(&mut self, report: InputReport) {
-        self.input_report = report;
+        self.previous_input_report = std::mem::replace(&mut self.input_report, report);
+    }
+
+    /// Whether a key is held in the current input report, but wasn't in the previous one.
+    pub(crate) fn key_just_pressed(&self, key: &str) -> bool {
+        self.input_report.key(key) == Some(true) && self.previous_input_report.key(key) == Some(false)
+    }
+
+    /// Whether a key was held in the previous input report, but isn't in the current one.
+    pub(crate) fn key_just_released(&self, key: &str) -> bool {
+        self.input_report.key(key) == Some(false) && self.previous_input_report.key(key) == Some(true)
     }
 
     pub fn update_display_config(&mut self, config: DisplayConfig) {
         self.display_config = config;
     }
 
+    pub fn update_time_config(&mut self, config: TimeConfig) {
+        self.time_config = config;
+    }
+
+    /// Gets the messages logged with `Debug` since this was last called, so that the host can
+    /// choose whether to display them.
+    pub fn take_debug_messages(&mut self) -> Vec<DebugMessage> {
+        std::mem::take(&mut self.debug_messages)
+    }
+
+    /// Replaces the contents of `Storage` with values which the host has previously persisted.
+    pub fn load_storage(&mut self, storage: HashMap<String, Object>) {
+        self.storage = storage;
+        self.storage_writes.clear();
+    }
+
+    /// Gets the values which `Storage.set` has written since this was last called, so that the
+    /// host can persist them.
+    pub fn take_storage_writes(&mut self) -> HashMap<String, Object> {
+        self.storage_writes.drain()
+            .map(|key| {
+                let value = self.storage[&key].clone();
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// Registers a native function, which scripts can call as `Host.name(...)`.
+    /// 
+    /// This allows the language to be embedded in hosts other than the bundled engine.
+    pub fn register_function(&mut self, name: impl Into<String>, function: Box<HostFunction>) {
+        self.host_functions.insert(name.into(), Rc::from(function));
+    }
+
     pub fn execute_tick(&mut self) -> InterpreterResult<Vec<Tone>> {
-        self.entities_pending_destroy.clear();
+        // Cleared however the tick ends, so that an error doesn't leave the interpreter thinking
+        // it's still ticking
+        self.ticking = true;
+        let result = self.run_tick();
+        self.ticking = false;
+        result
+    }
 
-        let ids_and_kinds = self.entities.iter()
+    fn run_tick(&mut self) -> InterpreterResult<Vec<Tone>> {
+        self.tick_count += 1;
+        self.steps = 0;
+        self.spawned_this_tick.clear();
+
+        // Taken before any handlers run, so entities spawned during this tick don't tick until the
+        // next one
+        let mut ids_and_kinds = self.entities.iter()
             .map(|(id, entity)| (*id, entity.kind.clone()))
             .collect::<Vec<_>>();
 
+        // Input handlers run before `tick`, for keys which changed since the last input report
+        let pressed_keys = InputReport::KEYS.into_iter()
+            .filter(|key| self.key_just_pressed(key))
+            .collect::<Vec<_>>();
+        let released_keys = InputReport::KEYS.into_iter()
+            .filter(|key| self.key_just_released(key))
+            .collect::<Vec<_>>();
+
+        // Entities destroyed earlier in the tick have their handlers skipped, even though they
+        // exist until the end of it. So do entities which are asleep
+        for (id, kind) in &ids_and_kinds {
+            if self.skips_tick(id) {
+                continue;
+            }
+
+            let handlers = pressed_keys.iter().filter_map(|key| Some((format!("on_press {key}"), kind.press_handlers.get(*key)?)))
+                .chain(released_keys.iter().filter_map(|key| Some((format!("on_release {key}"), kind.release_handlers.get(*key)?))));
+
+            for (name, handler) in handlers {
+                if self.skips_tick(id) {
+                    break;
+                }
+
+                let _ = self.execute_handler(*id, &name, handler, HashMap::new())?;
+            }
+        }
+
+        // Lower priorities tick first. The sort is stable, so entities with the same priority still
+        // tick in spawn order
+        ids_and_kinds.sort_by(|(_, a), (_, b)| a.tick_priority.unwrap_or(0.0).total_cmp(&b.tick_priority.unwrap_or(0.0)));
         for (id, kind) in ids_and_kinds {
+            if self.skips_tick(&id) {
+                continue;
+            }
+
             if let Some(tick) = kind.tick_handler.as_ref() {
-                let mut frame = Frame {
-                    entity: Some(id),
-                    locals: HashMap::new(),
-                };
+                let _ = self.execute_handler(id, "tick", tick, HashMap::new())?;
+            }
+        }
 
-                let _ = self.execute_statement_body(tick, &mut frame)?;
+        self.execute_collisions()?;
+        self.deliver_messages()?;
+
+        // Run `on_destroy` handlers while the entities still exist.
+        // These may destroy further entities, so keep going until no new ones are pending. Each
+        // entity's handler runs at most once, so this always terminates
+        let mut destroyed_entities = HashSet::new();
+        loop {
+            let newly_destroyed = self.entities_pending_destroy.iter()
+                .filter(|id| !destroyed_entities.contains(*id) && self.entities.contains_key(*id))
+                .copied()
+                .collect::<Vec<_>>();
+            if newly_destroyed.is_empty() {
+                break;
+            }
+
+            for id in newly_destroyed {
+                destroyed_entities.insert(id);
+
+                let kind = self.entities[&id].kind.clone();
+                if let Some(destroy) = kind.destroy_handler.as_ref() {
+                    let _ = self.execute_handler(id, "on_destroy", destroy, HashMap::new())?;
+                }
             }
         }
 
-        for destroyed_entity in &self.entities_pending_destroy {
+        for destroyed_entity in &destroyed_entities {
             let kind = self.entities[destroyed_entity].kind.name.clone();
-            self.entities.remove(&destroyed_entity);
+            self.entities.remove(destroyed_entity);
             self.entities_by_kinds.get_mut(&kind).unwrap().remove(destroyed_entity);
         }
 
+        // This isn't cleared at the start of the tick, so that entities destroyed from the
+        // top-level constructor or `draw` are destroyed by the next tick
+        self.entities_pending_destroy.clear();
+
+        self.advance_melodies();
+
         let sounds = self.pending_sounds.clone();
         self.pending_sounds.clear();
         Ok(sounds)
     }
 
+    /// Whether an entity's `tick`, `on_press` and `on_release` handlers shouldn't run, because
+    /// it's being destroyed or is asleep.
+    fn skips_tick(&self, id: &EntityId) -> bool {
+        self.entities_pending_destroy.contains(id) || self.entities.get(id).is_some_and(|entity| entity.asleep)
+    }
+
+    /// Runs `on_message` handlers for the messages sent so far. Messages sent by these handlers are
+    /// delivered on the next tick instead, so that entities which reply to each other's messages
+    /// can't keep the tick going forever.
+    fn deliver_messages(&mut self) -> InterpreterResult {
+        for (id, name, arguments) in std::mem::take(&mut self.pending_messages) {
+            // Messages to entities which were destroyed after the message was sent are dropped
+            if self.entities_pending_destroy.contains(&id) {
+                continue;
+            }
+            let Some(entity) = self.entities.get(&id) else {
+                continue;
+            };
+
+            let kind = entity.kind.clone();
+            if let Some((name_parameter, arguments_parameter, body)) = kind.message_handler.as_ref() {
+                let locals = HashMap::from([
+                    (name_parameter.clone(), Object::String(name.clone())),
+                    (arguments_parameter.clone(), arguments),
+                ]);
+                let _ = self.execute_handler(id, &format!("on_message {name}"), body, locals)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the tones of playing melodies which are due to start on this tick, and stops
+    /// melodies which have finished.
+    fn advance_melodies(&mut self) {
+        let tick = self.tick_count;
+        let ticks_per_second = self.time_config.ticks_per_second;
+
+        self.playing_melodies.retain_mut(|playback| {
+            // Melodies started outside of a tick begin on the next one
+            let started_tick = *playback.started_tick.get_or_insert(tick);
+            let elapsed = (tick - started_tick) as f64 / ticks_per_second;
+
+            // Allow for some floating-point error, so that a tone which is due exactly on this
+            // tick isn't pushed back to the next one
+            let mut tones_played = 0;
+            while playback.next_tone_start <= elapsed + 1e-9 {
+                // A melody made of very short tones could be due to play a huge number of them at
+                // once. Skip the rest, rather than falling ever further behind
+                if tones_played == MAX_TONES_PER_TICK {
+                    playback.next_tone_start = elapsed + 1e-9;
+                    break;
+                }
+                tones_played += 1;
+
+                let tone = playback.tones[playback.next_tone];
+                self.pending_sounds.push(tone);
+                playback.next_tone_start += tone.duration;
+                playback.next_tone += 1;
+
+                if playback.next_tone == playback.tones.len() {
+                    if !playback.looping {
+                        return false;
+                    }
+                    playback.next_tone = 0;
+                }
+            }
+
+            true
+        });
+    }
+
+    /// Finds pairs of overlapping entities, and runs their `on_collision` handlers.
+    /// 
+    /// Only pairs where at least one entity has a handler are checked. Each pair is only checked
+    /// once per tick, and entities which have been destroyed this tick are skipped.
+    fn execute_collisions(&mut self) -> InterpreterResult {
+        let mut ids = self.entities.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+
+        for (i, a) in ids.iter().enumerate() {
+            for b in &ids[i + 1..] {
+                if self.entities_pending_destroy.contains(a) || self.entities_pending_destroy.contains(b) {
+                    continue;
+                }
+
+                let (entity_a, entity_b) = (&self.entities[a], &self.entities[b]);
+                if entity_a.kind.collision_handler.is_none() && entity_b.kind.collision_handler.is_none() {
+                    continue;
+                }
+                if entity_a.overlaps(entity_b) != Some(true) {
+                    continue;
+                }
+
+                for (this, other) in [(*a, *b), (*b, *a)] {
+                    let kind = self.entities[&this].kind.clone();
+                    if let Some((parameter, body)) = kind.collision_handler.as_ref() {
+                        let locals = HashMap::from([(parameter.clone(), Object::Entity(other))]);
+                        let _ = self.execute_handler(this, "on_collision", body, locals)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn execute_draw(&mut self) -> InterpreterResult<Vec<DrawOperation>> {
+        self.steps = 0;
         let mut draw_ops = vec![];
 
         let ids_and_kinds = self.entities.iter()
@@ -105,28 +511,111 @@ impl Interpreter {
             .collect::<Vec<_>>();
 
         for (id, kind) in ids_and_kinds {
+            // Entities which have been destroyed, but not yet removed, aren't drawn. Nor are ones
+            // spawned during the last tick, which haven't ticked yet
+            if self.entities_pending_destroy.contains(&id) || self.spawned_this_tick.contains(&id) {
+                continue;
+            }
+
             if let Some(draw) = kind.draw_handler.as_ref() {
-                let mut frame = Frame {
-                    entity: Some(id),
-                    locals: HashMap::new(),
+                self.draw_ignores_camera = Some(false);
+                let result = self.execute_handler(id, "draw", draw, HashMap::new());
+                let ignores_camera = self.draw_ignores_camera.take() == Some(true);
+
+                // `draw` can return one sprite, or an array of `[sprite, dx, dy]` parts which are
+                // offset from the entity's position
+                let parts = match result? {
+                    ControlFlow::Continue(_) | ControlFlow::Break(Object::Null) => continue,
+                    ControlFlow::Break(Object::Sprite(sprite)) => vec![(sprite, 0.0, 0.0)],
+                    ControlFlow::Break(Object::Array(items)) => items.borrow().iter()
+                        .map(|item| {
+                            if let Object::Array(part) = item
+                                && let [Object::Sprite(sprite), Object::Number(dx), Object::Number(dy)] = part.borrow().as_slice()
+                            {
+                                return Ok((sprite.clone(), *dx, *dy));
+                            }
+                            Err(RuntimeError::type_mismatch("array of a sprite and two numbers", item, format!("each part returned from `draw` must be an array of a sprite and two numbers, like `[sprite, dx, dy]`, not {}", item.describe(self))))
+                        })
+                        .collect::<InterpreterResult<Vec<_>>>()?,
+
+                    ControlFlow::Break(other) => return Err(RuntimeError::type_mismatch("sprite or array of parts", &other, "if `draw` returns something, it must be a sprite, or an array of `[sprite, dx, dy]` parts")),
                 };
 
-                match self.execute_statement_body(draw, &mut frame)? {
-                    ControlFlow::Continue(_) | ControlFlow::Break(Object::Null) => {},
-                    ControlFlow::Break(Object::Sprite(sprite)) => {
-                        let (x, y) = self.entities[&id].draw_position_ivars()?;
-                        draw_ops.push(DrawOperation { x, y, sprite })
-                    },
+                let entity = self.entities.get_mut(&id).unwrap();
+                let (mut x, mut y) = entity.draw_position_ivars()?;
+                let z = entity.draw_layer_ivar()?;
 
-                    _ => return Err(RuntimeError::new("if `draw` returns something, it must be a sprite")),
+                if !ignores_camera {
+                    x -= self.camera.0;
+                    y -= self.camera.1;
+                }
+                if let Some((_, dx, dy)) = parts.iter().find(|(_, dx, dy)| !(x + dx).is_finite() || !(y + dy).is_finite()) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("part of entity `{}` at offset ({dx}, {dy}) is too far away to be drawn", kind.name)));
+                }
+
+                // For collisions, the drawn area stretches from the entity's position to the
+                // furthest edge of any part. With no parts, nothing was drawn at all
+                entity.last_drawn_size = (!parts.is_empty()).then(|| {
+                    let width = parts.iter().map(|(sprite, dx, _)| (dx + sprite.width as f64).max(0.0) as usize).max().unwrap();
+                    let height = parts.iter().map(|(sprite, _, dy)| (dy + sprite.height as f64).max(0.0) as usize).max().unwrap();
+                    (width, height)
+                });
+
+                for (sprite, dx, dy) in parts {
+                    draw_ops.push(DrawOperation { x: x + dx, y: y + dy, z, sprite });
                 }
             }
         }
 
+        // Lower layers are drawn first, so that higher ones appear on top. This is a stable sort,
+        // so entities on the same layer stay in spawn order
+        draw_ops.sort_by(|a, b| a.z.total_cmp(&b.z));
+
         self.forbid_sound()?;
         Ok(draw_ops)
     }
 
+    /// Runs one of an entity's handlers.
+    fn execute_handler(&mut self, id: EntityId, name: &str, body: &[Statement], locals: HashMap<String, Object>) -> InterpreterResult<ControlFlow<Object>> {
+        let mut frame = Frame {
+            entity: Some(id),
+            locals,
+        };
+        self.execute_named_body(format!("{}.{name}", self.entities[&id].kind.name), body, &mut frame)
+    }
+
+    /// Runs the body of a handler or function, adding its `name` to the call stack while it runs.
+    pub(crate) fn execute_named_body(&mut self, name: String, body: &[Statement], frame: &mut Frame) -> InterpreterResult<ControlFlow<Object>> {
+        self.call_stack.push(name);
+        let result = self.execute_statement_body(body, frame);
+        self.call_stack.pop();
+        result
+    }
+
+    /// Sets how many statements and expressions may be evaluated by each call to `execute_init`,
+    /// `execute_tick` or `execute_draw`, so that an infinite loop gives an error instead of
+    /// hanging the game. `None` removes the limit, which is the default.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    /// Sets whether arithmetic which results in infinity or NaN, such as `10 ^ 400`, raises an
+    /// error. These checks are on by default, because otherwise the bad value silently spreads
+    /// into positions and stops entities from being drawn. Dividing by zero is always an error.
+    pub fn set_finite_number_checks(&mut self, enabled: bool) {
+        self.check_finite_numbers = enabled;
+    }
+
+    /// Counts one statement or expression towards the step limit.
+    fn take_step(&mut self) -> InterpreterResult {
+        self.steps += 1;
+        match self.step_limit {
+            Some(limit) if self.steps > limit =>
+                Err(RuntimeError::new(RuntimeErrorKind::StepLimitExceeded, format!("exceeded the limit of {limit} steps, is there an infinite loop?"))),
+            _ => Ok(()),
+        }
+    }
+
     pub(crate) fn execute_statement_body(&mut self, body: &[Statement], frame: &mut Frame) -> InterpreterResult<ControlFlow<Object>> {
         for stmt in body {
             match self.interpret_statement(stmt, frame)? {
@@ -138,6 +627,62 @@ impl Interpreter {
         Ok(ControlFlow::Continue(()))
     }
 
+    /// The built-in singleton with the given name, if there is one
+    pub(crate) fn singleton(name: &str) -> Option<Object> {
+        Some(match name {
+            "Input" => Object::InputSingleton,
+            "Display" => Object::DisplaySingleton,
+            "Math" => Object::MathSingleton,
+            "Global" => Object::GlobalSingleton,
+            "Host" => Object::HostSingleton,
+            "Sprite" => Object::SpriteSingleton,
+            "Time" => Object::TimeSingleton,
+            "Text" => Object::TextSingleton,
+            "Storage" => Object::StorageSingleton,
+            "Camera" => Object::CameraSingleton,
+            "Debug" => Object::DebugSingleton,
+            "Game" => Object::GameSingleton,
+            "Color" => Object::ColorSingleton,
+            _ => return None,
+        })
+    }
+
+    /// Sets the priority of an entity kind's `tick` handler. Every `tick` which is merged into the
+    /// handler must agree on this, since they all run together.
+    fn merge_tick_priority(target: &mut EntityKind, priority: f64) -> InterpreterResult {
+        match target.tick_priority {
+            Some(existing) if existing != priority =>
+                Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("tick handler of `{}` is given both priority {existing} and priority {priority}", target.name))),
+            _ => {
+                target.tick_priority = Some(priority);
+                Ok(())
+            }
+        }
+    }
+
+    /// Describes what a name refers to if it's a singleton, entity kind or const. Identifiers are
+    /// looked up in that order before locals, so a local with one of these names could never be
+    /// read back.
+    fn describe_global_name(&self, name: &str) -> Option<&'static str> {
+        if Self::singleton(name).is_some() {
+            Some("a built-in singleton")
+        } else if self.entity_kinds.contains_key(name) || self.pending_entity_declarations.contains_key(name) {
+            Some("an entity declaration")
+        } else if self.constants.contains_key(name) {
+            Some("a const")
+        } else {
+            None
+        }
+    }
+
+    /// Errors if a parameter or loop variable has a name which would be hidden by a global
+    fn check_local_name(&self, name: &str, what: impl FnOnce() -> String) -> InterpreterResult {
+        match self.describe_global_name(name) {
+            Some(description) => Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("{} has the same name as {description}", what()))),
+            None => Ok(()),
+        }
+    }
+
     pub fn entities(&self) -> impl Iterator<Item = &Entity> {
         self.entities.values()
     }
@@ -146,24 +691,40 @@ impl Interpreter {
         match decl {
             Declaration::EntityDeclaration { name, body } => {
                 if target.is_some() {
-                    return Err(RuntimeError::new("cannot nest entity definitions"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "cannot nest entity definitions"));
                 }
                 if self.entity_kinds.contains_key(name) {
-                    return Err(RuntimeError::new(format!("duplicate entity declaration `{name}`")));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("duplicate entity declaration `{name}`")));
+                }
+                if Self::singleton(name).is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("entity declaration `{name}` has the same name as a built-in singleton")));
+                }
+                if self.constants.contains_key(name) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("entity declaration `{name}` has the same name as a const")));
                 }
+                self.pending_entity_declarations.remove(name);
 
                 let mut new_entity_kind = EntityKind {
                     name: name.to_owned(),
                     functions: HashMap::new(),
                     constructor: None,
                     tick_handler: None,
+                    tick_priority: None,
                     draw_handler: None,
+                    destroy_handler: None,
+                    collision_handler: None,
+                    message_handler: None,
+                    press_handlers: HashMap::new(),
+                    release_handlers: HashMap::new(),
                     ivars: vec![],
+                    uses: vec![],
                 };
 
-                for subdecl in body {
-                    self.interpret_declaration(subdecl, Some(&mut new_entity_kind))?;
-                }
+                self.entities_being_declared.push(name.clone());
+                let result = body.iter()
+                    .try_for_each(|subdecl| self.interpret_declaration(subdecl, Some(&mut new_entity_kind)));
+                self.entities_being_declared.pop();
+                result?;
 
                 self.entity_kinds.insert(name.to_owned(), Rc::new(new_entity_kind));
                 Ok(())
@@ -180,19 +741,21 @@ impl Interpreter {
     
                     Ok(())
                 } else {
-                    if !self.top_level_constructor.is_empty() {
-                        return Err(RuntimeError::new("top-level constructor is already declared"));
-                    }
-                    self.top_level_constructor = body.clone();
+                    // Like entity constructors, later top-level constructors are appended, so that
+                    // each file can do its own setup
+                    self.top_level_constructor.extend(body.clone());
                     Ok(())
                 }
             }
             
-            Declaration::TickDeclaration { body } => {
+            Declaration::TickDeclaration { priority, body } => {
                 let Some(target) = target else {
-                    return Err(RuntimeError::new("tick declarations cannot appear outside of an entity"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "tick declarations cannot appear outside of an entity"));
                 };
-                
+                if let Some(priority) = priority {
+                    Self::merge_tick_priority(target, *priority)?;
+                }
+
                 if let Some(tick) = target.tick_handler.as_mut() {
                     tick.extend(body.clone());
                 } else {
@@ -202,92 +765,326 @@ impl Interpreter {
                 Ok(())
             }
 
+            Declaration::DestroyDeclaration { body } => {
+                let Some(target) = target else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_destroy declarations cannot appear outside of an entity"));
+                };
+                
+                if let Some(destroy) = target.destroy_handler.as_mut() {
+                    destroy.extend(body.clone());
+                } else {
+                    target.destroy_handler = Some(body.clone());
+                }
+
+                Ok(())
+            }
+
+            Declaration::PressDeclaration { key, body } | Declaration::ReleaseDeclaration { key, body } => {
+                let Some(target) = target else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_press and on_release declarations cannot appear outside of an entity"));
+                };
+                if InputReport::default().key(key).is_none() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("unknown key `{key}`, expected one of: {}", InputReport::KEYS.join(", "))));
+                }
+
+                let handlers = match decl {
+                    Declaration::PressDeclaration { .. } => &mut target.press_handlers,
+                    _ => &mut target.release_handlers,
+                };
+                handlers.entry(key.clone()).or_default().extend(body.clone());
+                Ok(())
+            }
+
+            Declaration::CollisionDeclaration { parameter, body } => {
+                let Some(target) = target else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_collision declarations cannot appear outside of an entity"));
+                };
+                if target.collision_handler.is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_collision handler is already declared"));
+                }
+                self.check_local_name(parameter, || format!("parameter `{parameter}` of `on_collision`"))?;
+
+                target.collision_handler = Some((parameter.clone(), body.clone()));
+                Ok(())
+            }
+
+            Declaration::MessageDeclaration { name_parameter, arguments_parameter, body } => {
+                let Some(target) = target else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_message declarations cannot appear outside of an entity"));
+                };
+                if target.message_handler.is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "on_message handler is already declared"));
+                }
+                for parameter in [name_parameter, arguments_parameter] {
+                    self.check_local_name(parameter, || format!("parameter `{parameter}` of `on_message`"))?;
+                }
+
+                target.message_handler = Some((name_parameter.clone(), arguments_parameter.clone(), body.clone()));
+                Ok(())
+            }
+
             Declaration::DrawDeclaration { body } => {
                 let Some(target) = target else {
-                    return Err(RuntimeError::new("draw declarations cannot appear outside of an entity"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "draw declarations cannot appear outside of an entity"));
                 };
                 if target.draw_handler.is_some() {
-                    return Err(RuntimeError::new(format!("draw handler is already declared")));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("draw handler is already declared")));
                 }
 
                 target.draw_handler = Some(body.clone());
                 Ok(())
             }
 
-            Declaration::InstanceVarDeclaration { names } => {
+            Declaration::InstanceVarDeclaration { ivars } => {
                 let Some(target) = target else {
-                    return Err(RuntimeError::new("instance variable declarations cannot appear outside of an entity"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "instance variable declarations cannot appear outside of an entity"));
                 };
 
-                for name in names {
-                    if target.ivars.contains(name) {
-                        return Err(RuntimeError::new(format!("instance variable `{name}` is already declared")));
+                for (name, default) in ivars {
+                    match target.ivars.iter().find(|ivar| &ivar.name == name) {
+                        Some(InstanceVar { used_from: None, .. }) =>
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("instance variable `{name}` is already declared"))),
+                        Some(InstanceVar { used_from: Some(other), .. }) =>
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("instance variable `{name}` declared by both `{}` and used entity `{other}`", target.name))),
+                        None => {}
                     }
 
-                    target.ivars.push(name.to_owned());
+                    target.ivars.push(InstanceVar {
+                        name: name.to_owned(),
+                        default: default.clone(),
+                        used_from: None,
+                    });
                 }
                 Ok(())
             }
 
             Declaration::FunctionDeclaration { name, parameters, body } => {
                 let Some(target) = target else {
-                    return Err(RuntimeError::new("function declarations cannot appear outside of an entity"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "function declarations cannot appear outside of an entity"));
                 };
-                if target.functions.contains_key(name) {
-                    return Err(RuntimeError::new(format!("function `{name}` is already declared")));
+                // Functions declared directly in the entity override any copied in by `use`
+                if target.functions.get(name).is_some_and(|f| f.used_from.is_none()) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("function `{name}` is already declared")));
+                }
+                for parameter in parameters {
+                    self.check_local_name(parameter, || format!("parameter `{parameter}` of function `{name}`"))?;
                 }
 
                 let decl = FunctionDeclaration {
                     name: name.to_owned(),
                     parameters: parameters.clone(),
                     body: body.clone(),
+                    used_from: None,
                 };
                 target.functions.insert(name.to_owned(), decl);
                 Ok(())
             }
 
-            Declaration::UseDeclaration { name } => {
+            Declaration::UseDeclaration { name, filter } => {
                 let Some(target) = target else {
-                    return Err(RuntimeError::new("use declarations cannot appear outside of an entity"));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "use declarations cannot appear outside of an entity"));
                 };
+                // Copying an entity's definitions twice would run its handlers twice, which is
+                // never what was intended
+                if target.uses.contains(name) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("entity declaration `{}` uses `{name}` more than once", target.name)));
+                }
+                target.uses.push(name.clone());
+
+                if let Some(start) = self.entities_being_declared.iter().position(|n| n == name) {
+                    let chain = self.entities_being_declared[start..].iter()
+                        .chain([name])
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" -> ");
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("circular `use`: {chain}")));
+                }
+
+                // The used entity might be declared later on, in which case interpret it now
+                if let Some(body) = self.pending_entity_declarations.get(name).cloned() {
+                    self.interpret_declaration(&Declaration::EntityDeclaration { name: name.clone(), body }, None)?;
+                }
+
                 let Some(source_entity_kind) = self.entity_kinds.get(name) else {
-                    return Err(RuntimeError::new(format!("no entity declaration named `{name}`")));
+                    return Err(RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("no entity declaration named `{name}`")));
                 };
 
                 // Copy the contents of that entity declaration into this one
-                let EntityKind { name: _, functions, constructor, tick_handler, draw_handler, ivars } = &**source_entity_kind;
+                let EntityKind {
+                    name: _, functions, constructor, tick_handler, tick_priority, draw_handler, destroy_handler,
+                    collision_handler, message_handler, press_handlers, release_handlers, ivars, uses: _,
+                } = &**source_entity_kind;
+
+                // Every name in the filter must be something which could be copied
+                for filter_name in filter.names() {
+                    let exists = match filter_name.as_str() {
+                        "constructor" => constructor.is_some(),
+                        "tick" => tick_handler.is_some(),
+                        "draw" => draw_handler.is_some(),
+                        "on_destroy" => destroy_handler.is_some(),
+                        "on_collision" => collision_handler.is_some(),
+                        "on_message" => message_handler.is_some(),
+                        "on_press" => !press_handlers.is_empty(),
+                        "on_release" => !release_handlers.is_empty(),
+                        _ => functions.contains_key(filter_name),
+                    };
+                    if !exists {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("entity declaration `{name}` has no function or handler named `{filter_name}` to use")));
+                    }
+                }
+
+                let constructor = constructor.as_ref().filter(|_| filter.includes("constructor"));
+                let tick_handler = tick_handler.as_ref().filter(|_| filter.includes("tick"));
+                let tick_priority = tick_priority.filter(|_| tick_handler.is_some());
+                let draw_handler = draw_handler.as_ref().filter(|_| filter.includes("draw"));
+                let destroy_handler = destroy_handler.as_ref().filter(|_| filter.includes("on_destroy"));
+                let collision_handler = collision_handler.as_ref().filter(|_| filter.includes("on_collision"));
+                let message_handler = message_handler.as_ref().filter(|_| filter.includes("on_message"));
+
+                for (function_name, function) in functions {
+                    if !filter.includes(function_name) {
+                        continue;
+                    }
+
+                    match target.functions.get(function_name) {
+                        // Declared directly in the target, which overrides this one
+                        Some(FunctionDeclaration { used_from: None, .. }) => continue,
+
+                        Some(FunctionDeclaration { used_from: Some(other), .. }) =>
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("function `{function_name}` is brought in by both `use {other}` and `use {name}`"))),
+
+                        None => {
+                            target.functions.insert(function_name.clone(), FunctionDeclaration {
+                                used_from: Some(name.clone()),
+                                ..function.clone()
+                            });
+                        }
+                    }
+                }
 
-                target.functions.extend(functions.clone());
-                target.ivars.extend(ivars.clone());
+                // Unlike functions, instance variables can't be overridden, since the two
+                // declarations might give them different initial values
+                for ivar in ivars {
+                    match target.ivars.iter().find(|existing| existing.name == ivar.name) {
+                        Some(InstanceVar { used_from: None, .. }) =>
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("instance variable `{}` declared by both `{}` and used entity `{name}`", ivar.name, target.name))),
+                        Some(InstanceVar { used_from: Some(other), .. }) =>
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("instance variable `{}` is brought in by both `use {other}` and `use {name}`", ivar.name))),
+                        None => target.ivars.push(InstanceVar { used_from: Some(name.clone()), ..ivar.clone() }),
+                    }
+                }
 
-                if let Some(source_constructor) = constructor.as_ref() {
+                if let Some(source_constructor) = constructor {
                     if let Some(target_constructor) = target.constructor.as_mut() {
                         target_constructor.extend_from_slice(&source_constructor);
                     } else {
                         target.constructor = Some(source_constructor.clone());
                     }
                 }
-                if let Some(source_tick) = tick_handler.as_ref() {
+                if let Some(priority) = tick_priority {
+                    Self::merge_tick_priority(target, priority)?;
+                }
+                if let Some(source_tick) = tick_handler {
                     if let Some(target_tick) = target.tick_handler.as_mut() {
                         target_tick.extend_from_slice(&source_tick);
                     } else {
                         target.tick_handler = Some(source_tick.clone());
                     }
                 }
+                if let Some(source_destroy) = destroy_handler {
+                    if let Some(target_destroy) = target.destroy_handler.as_mut() {
+                        target_destroy.extend_from_slice(source_destroy);
+                    } else {
+                        target.destroy_handler = Some(source_destroy.clone());
+                    }
+                }
 
                 // Extending the `draw` handler doesn't make much sense, because it is designed to return something, so only one will ever run. Don't do that
                 if target.draw_handler.is_some() && draw_handler.is_some() {
-                    return Err(RuntimeError::new(format!("both used entity and target entity define `draw`, but that is not possible to merge")));
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("both used entity and target entity define `draw`, but that is not possible to merge")));
+                }
+                if let Some(source_draw) = draw_handler {
+                    target.draw_handler = Some(source_draw.clone());
+                }
+
+                if filter.includes("on_press") {
+                    for (key, body) in press_handlers {
+                        target.press_handlers.entry(key.clone()).or_default().extend_from_slice(body);
+                    }
+                }
+                if filter.includes("on_release") {
+                    for (key, body) in release_handlers {
+                        target.release_handlers.entry(key.clone()).or_default().extend_from_slice(body);
+                    }
+                }
+
+                // Same for `on_collision`, since handlers could name their parameter differently
+                if target.collision_handler.is_some() && collision_handler.is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "both used entity and target entity define `on_collision`, but that is not possible to merge"));
+                }
+                if let Some(source_collision) = collision_handler {
+                    target.collision_handler = Some(source_collision.clone());
+                }
+                if target.message_handler.is_some() && message_handler.is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "both used entity and target entity define `on_message`, but that is not possible to merge"));
+                }
+                if let Some(source_message) = message_handler {
+                    target.message_handler = Some(source_message.clone());
                 }
 
                 Ok(())
             }
+
+            Declaration::ConstDeclaration { name, value } => {
+                if target.is_some() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, "const declarations cannot appear inside an entity"));
+                }
+                if self.constants.contains_key(name) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("duplicate const declaration `{name}`")));
+                }
+                if let Some(description) = self.describe_global_name(name) {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("const `{name}` has the same name as {description}")));
+                }
+                // Functions and handlers declared before this const couldn't have had their
+                // parameters checked against it, so do that now instead
+                for kind in self.entity_kinds.values() {
+                    if let Some(function) = kind.functions.values().find(|f| f.parameters.contains(name)) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("parameter `{name}` of function `{}` has the same name as a const", function.name)));
+                    }
+                    if kind.collision_handler.as_ref().is_some_and(|(parameter, _)| parameter == name) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("parameter `{name}` of `on_collision` has the same name as a const")));
+                    }
+                    if kind.message_handler.as_ref().is_some_and(|(name_parameter, arguments_parameter, _)| name_parameter == name || arguments_parameter == name) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidDeclaration, format!("parameter `{name}` of `on_message` has the same name as a const")));
+                    }
+                }
+
+                let mut frame = Frame {
+                    entity: None,
+                    locals: HashMap::new(),
+                };
+                let value = self.interpret_expression(value, &mut frame)?.read()?;
+                self.constants.insert(name.to_owned(), value);
+
+                // Copied all together, so that a const which refers to another const's array still
+                // shares it after a reset
+                self.initial_constants = ArrayCopier::default().copy_map(&self.constants);
+                Ok(())
+            }
         }
     }
 
     /// If this is a `return`, returns [`ControlFlow::Break`] and the returned object
     pub fn interpret_statement(&mut self, stmt: &Statement, frame: &mut Frame) -> InterpreterResult<ControlFlow<Object>> {
+        // A `Located` statement only wraps another, so doesn't count as a step of its own
+        if !matches!(stmt, Statement::Located { .. }) {
+            self.take_step()?;
+        }
+
         match stmt {
+            Statement::Located { span, statement } => self.interpret_statement(statement, frame)
+                .map_err(|err| err.traced(span, &self.call_stack)),
+
             Statement::Expression(expr) => {
                 // We should generally read from this value - even though we aren't using it - to
                 // bring out any errors for the value.
@@ -301,7 +1098,7 @@ impl Interpreter {
             Statement::IfConditional { condition, true_body, false_body } => {
                 let condition = self.interpret_expression(condition, frame)?.read()?;
                 let Object::Boolean(condition) = condition else {
-                    return Err(RuntimeError::new("if-condition must be a boolean"));
+                    return Err(RuntimeError::type_mismatch("boolean", &condition, "if-condition must be a boolean"));
                 };
 
                 if condition {
@@ -312,34 +1109,108 @@ impl Interpreter {
                     Ok(ControlFlow::Continue(()))
                 }
             }
-            Statement::EachLoop { variable, source, body } => {
+            Statement::Match { scrutinee, arms, else_body } => {
+                let scrutinee = self.interpret_expression(scrutinee, frame)?.read()?;
+
+                for (value, body) in arms {
+                    let value = self.interpret_expression(value, frame)?.read()?;
+                    if value == scrutinee {
+                        return self.execute_statement_body(body, frame);
+                    }
+                }
+
+                if let Some(else_body) = else_body {
+                    self.execute_statement_body(else_body, frame)
+                } else {
+                    Ok(ControlFlow::Continue(()))
+                }
+            }
+            Statement::EachLoop { variable, index_variable, source, body } => {
+                for name in std::iter::once(variable).chain(index_variable) {
+                    self.check_local_name(name, || format!("loop variable `{name}`"))?;
+                }
                 let source = self.interpret_expression(source, frame)?.read()?;
                 
-                let items = match source {
-                    Object::Array(items) => items,
-                    Object::Number(max) => (0..(max.round() as i64))
-                        .map(|n| Object::Number(n as f64))
-                        .collect(),
-                    _ => return Err(RuntimeError::new("loop source must be an array or integer")),
+                // Numbers and ranges are iterated lazily, so that a huge loop runs into the step
+                // limit rather than allocating every item up-front
+                let items: Box<dyn Iterator<Item = Object>> = match source {
+                    // Changing the array inside the loop doesn't change which items are looped over
+                    Object::Array(items) => Box::new(items.borrow().clone().into_iter()),
+                    Object::Number(max) => Box::new((0..(max.round() as i64))
+                        .map(|n| Object::Number(n as f64))),
+                    Object::Range { start, end, step } => Box::new(
+                        std::iter::successors(Some(start), move |n| n.checked_add(step))
+                            .take_while(move |n| (step > 0 && *n < end) || (step < 0 && *n > end))
+                            .map(|n| Object::Number(n as f64))
+                    ),
+                    other => return Err(RuntimeError::type_mismatch("array, integer, or range", &other, "loop source must be an array, integer, or range")),
                 };
 
-                for item in items {
-                    frame.locals.insert(variable.clone(), item);
-                    match self.execute_statement_body(body, frame)? {
-                        ControlFlow::Continue(_) => {},
-                        ControlFlow::Break(retval) => {
-                            return Ok(ControlFlow::Break(retval));
-                        },
+                // The loop's variables only exist within it, so put back any locals they replaced
+                // once it's finished. This also means nested loops can reuse the same names
+                let replaced_locals = std::iter::once(variable).chain(index_variable)
+                    .map(|name| (name.clone(), frame.locals.get(name).cloned()))
+                    .collect::<Vec<_>>();
+
+                let result = 'each: {
+                    for (index, item) in items.enumerate() {
+                        frame.locals.insert(variable.clone(), item);
+                        if let Some(index_variable) = index_variable {
+                            frame.locals.insert(index_variable.clone(), Object::Number(index as f64));
+                        }
+                        match self.execute_statement_body(body, frame) {
+                            Ok(ControlFlow::Continue(_)) => {},
+                            result => break 'each result,
+                        }
                     }
+
+                    Ok(ControlFlow::Continue(()))
+                };
+
+                for (name, value) in replaced_locals {
+                    match value {
+                        Some(value) => frame.locals.insert(name, value),
+                        None => frame.locals.remove(&name),
+                    };
                 }
+                result
+            }
+            Statement::RepeatUntil { body, condition } => {
+                // A condition which never becomes true is caught by the step limit
+                loop {
+                    if let ControlFlow::Break(retval) = self.execute_statement_body(body, frame)? {
+                        return Ok(ControlFlow::Break(retval));
+                    }
 
-                Ok(ControlFlow::Continue(()))
+                    let condition = self.interpret_expression(condition, frame)?.read()?;
+                    let Object::Boolean(condition) = condition else {
+                        return Err(RuntimeError::type_mismatch("boolean", &condition, "until-condition must be a boolean"));
+                    };
+                    if condition {
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                }
             }
             Statement::Assignment { target, value } => {
                 let value = self.interpret_expression(value, frame)?.read()?;
                 self.interpret_expression(target, frame)?.write(value)?;
                 Ok(ControlFlow::Continue(()))
             }
+            Statement::DestructuringAssignment { targets, value } => {
+                let value = self.interpret_expression(value, frame)?.read()?;
+                let Object::Array(items) = &value else {
+                    return Err(RuntimeError::type_mismatch("array", &value, format!("only arrays can be destructured, not {}", value.describe(self))));
+                };
+                let items = items.borrow().clone();
+                if items.len() != targets.len() {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("cannot destructure array of length {} into {} targets", items.len(), targets.len())));
+                }
+
+                for (target, item) in targets.iter().zip(items) {
+                    self.interpret_expression(target, frame)?.write(item)?;
+                }
+                Ok(ControlFlow::Continue(()))
+            }
             Statement::Return(expr) => {
                 if let Some(expr) = expr {
                     let retval = self.interpret_expression(expr, frame)?.read()?;
@@ -352,39 +1223,49 @@ impl Interpreter {
     }
 
     pub fn interpret_expression<'a>(&'a mut self, expr: &'a Expression, frame: &'a mut Frame) -> InterpreterResult<Value<'a>> {
+        self.take_step()?;
+
         match expr {
             Expression::ThisLiteral => {
                 if let Some(entity) = frame.entity {
                     Ok(Value::ReadOnly(Object::Entity(entity)))
                 } else {
-                    Err(RuntimeError::new("`this` is not valid here"))
+                    Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "`this` is not valid here"))
                 }
             },
 
             Expression::NullLiteral => Ok(Value::ReadOnly(Object::Null)),
             Expression::NumberLiteral(n) => Ok(Value::ReadOnly(Object::Number(*n))),
             Expression::BooleanLiteral(b) => Ok(Value::ReadOnly(Object::Boolean(*b))),
+            Expression::StringLiteral(s) => Ok(Value::ReadOnly(Object::String(s.clone()))),
 
             Expression::ArrayLiteral(items) => {
                 let items = items.iter()
                     .map(|e| self.interpret_expression(e, frame).map(|v| v.read()).flatten())
                     .collect::<Result<Vec<_>, _>>()?;
 
-                Ok(Value::ReadOnly(Object::Array(items)))
+                Ok(Value::ReadOnly(Object::array(items)))
             }
 
             Expression::Identifier(id) => {
-                // Special identifiers!
-                match id.as_ref() {
-                    "Input" => return Ok(Value::ReadOnly(Object::InputSingleton)),
-                    "Display" => return Ok(Value::ReadOnly(Object::DisplaySingleton)),
-                    "Math" => return Ok(Value::ReadOnly(Object::MathSingleton)),
-                    _ => {}, // Carry on
+                // Special identifiers, then entity kinds. Neither can be assigned to, and locals
+                // are never allowed to have these names, since they would always be hidden
+                let global = Self::singleton(id)
+                    .map(|obj| (obj, "a built-in singleton"))
+                    .or_else(|| self.entity_kinds.get(id).map(|kind| (Object::EntityKind(kind.clone()), "an entity declaration")));
+                if let Some((obj, description)) = global {
+                    return Ok(Value::ReadWrite {
+                        value: obj,
+                        write: Box::new(move |_| Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, format!("cannot assign to `{id}`, which is {description}")))),
+                    })
                 }
 
-                // Look for entity kinds
-                if let Some(kind) = self.entity_kinds.get(id) {
-                    return Ok(Value::ReadOnly(Object::EntityKind(kind.clone())))
+                // Then constants, which can be read but never reassigned
+                if let Some(obj) = self.constants.get(id) {
+                    return Ok(Value::ReadWrite {
+                        value: obj.clone(),
+                        write: Box::new(move |_| Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, format!("cannot reassign const `{id}`")))),
+                    })
                 }
 
                 // Finally, locals
@@ -402,47 +1283,71 @@ impl Interpreter {
                             frame.locals.insert(id.to_owned(), o);
                             Ok(())
                         }),
-                        error_on_read: RuntimeError::new(format!("undefined identifier `{id}`"))
+                        error_on_read: RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("undefined identifier `{id}`"))
                     })
                 }
             },
             Expression::InstanceVarIdentifier(id) => {
                 let Some(entity_id) = frame.entity else {
-                    return Err(RuntimeError::new(format!("cannot get instance variable `{id}` in non-entity context")))
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, format!("cannot get instance variable `{id}` in non-entity context")))
                 };
 
-                if let Some(obj) = self.entities[&entity_id].ivars.get(id) {
-                    Ok(Value::ReadWrite {
-                        value: obj.clone(),
-                        write: Box::new(move |o| {
-                            let entity = &mut self.entities.get_mut(&entity_id).unwrap();
-                            entity.ivars.insert(id.to_owned(), o);
-                            Ok(())
-                        }),
-                    })
-                } else {
-                    Err(RuntimeError::new(format!("undeclared instance variable `{id}`")))
-                }    
+                self.instance_var_value(entity_id, id)
+            }
+            Expression::ForeignInstanceVarIdentifier { target, name } => {
+                let target = self.interpret_expression(target, frame)?.read()?;
+                let Object::Entity(entity_id) = target else {
+                    return Err(RuntimeError::type_mismatch("entity", &target, format!("cannot get instance variable `{name}` of non-entity object: {}", target.describe(self))));
+                };
+
+                self.instance_var_value(entity_id, name)
             }
 
             Expression::SpriteLiteral(sprite) => Ok(Value::ReadOnly(Object::Sprite(sprite.clone()))),
-            Expression::SoundLiteral(tone) => Ok(Value::ReadOnly(Object::Sound(tone.clone()))),
+            Expression::SoundLiteral(tone) => {
+                check_tone_durations(std::slice::from_ref(tone))?;
+                Ok(Value::ReadOnly(Object::Sound(tone.clone())))
+            },
+            Expression::MelodyLiteral(tones) => {
+                check_tone_durations(tones)?;
+                Ok(Value::ReadOnly(Object::Melody(tones.clone())))
+            },
 
-            Expression::FunctionCall { target, name, arguments } => {
-                let target = self.interpret_expression(&target, frame)?.read()?;
+            Expression::FunctionCall { target: target_expr, name, arguments } => {
+                let target = self.interpret_expression(&target_expr, frame)?.read()?;
                 let arguments = arguments.iter()
                         .map(|arg| self.interpret_expression(arg, frame).map(|v| v.read()).flatten())
                         .collect::<Result<Vec<_>, _>>()?;
-                
+
                 Ok(Value::ReadOnly(target.call_function(self, name, arguments)?))
             }
 
+            Expression::BareFunctionCall { name, arguments } => {
+                let arguments = arguments.iter()
+                        .map(|arg| self.interpret_expression(arg, frame).and_then(|v| v.read()))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                // Prefer functions on the current entity, then host functions
+                let entity = frame.entity.and_then(|id| self.entities.get(&id).map(|e| (id, e.kind.clone())));
+                if let Some((entity_id, kind)) = &entity && kind.functions.contains_key(name) {
+                    return Ok(Value::ReadOnly(Object::Entity(*entity_id).call_function(self, name, arguments)?));
+                }
+                if self.host_functions.contains_key(name) {
+                    return Ok(Value::ReadOnly(Object::HostSingleton.call_function(self, name, arguments)?));
+                }
+
+                match entity {
+                    Some((_, kind)) => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("no function `{name}` on entity `{}`", kind.name))),
+                    None => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("no function `{name}`"))),
+                }
+            }
+
             Expression::BinaryOperation { left, right, operator } => {
                 fn to_boolean(obj: Object) -> InterpreterResult<bool> {
                     if let Object::Boolean(b) = obj {
                         Ok(b)
                     } else {
-                        Err(RuntimeError::new(format!("both sides of logical operator must be booleans")))
+                        Err(RuntimeError::type_mismatch("boolean", &obj, "both sides of logical operator must be booleans"))
                     }
                 }
 
@@ -468,48 +1373,143 @@ impl Interpreter {
                     }
                 }
 
+                // `??` also short-circuits, only evaluating the right side if the left side is null
+                if let BinaryOperator::NullCoalesce = operator {
+                    let left = self.interpret_expression(left, frame)?.read()?;
+                    if left != Object::Null {
+                        return Ok(Value::ReadOnly(left));
+                    }
+                    let right = self.interpret_expression(right, frame)?.read()?;
+                    return Ok(Value::ReadOnly(right));
+                }
+
                 let left = self.interpret_expression(&left, frame)?.read()?;
                 let right = self.interpret_expression(&right, frame)?.read()?;
 
                 fn numeric(left: Object, right: Object, f: impl FnOnce(f64, f64) -> Object) -> InterpreterResult<Object> {
-                    let (Object::Number(left), Object::Number(right)) = (left, right) else {
-                        return Err(RuntimeError::new(format!("both sides of binary operator must be numbers")));
+                    let (Object::Number(l), Object::Number(r)) = (&left, &right) else {
+                        return Err(expected_numbers(&[&left, &right], "both sides of binary operator must be numbers"));
+                    };
+                    Ok(f(*l, *r))
+                }
+
+                // Kept for the error message if the result isn't finite
+                let operands = match (&left, &right) {
+                    (Object::Number(l), Object::Number(r)) => Some((*l, *r)),
+                    _ => None,
+                };
+
+                let result = match operator {
+                    BinaryOperator::Add => numeric(left, right, |l, r| Object::Number(l + r))?,
+                    BinaryOperator::Subtract => numeric(left, right, |l, r| Object::Number(l - r))?,
+                    BinaryOperator::Multiply => numeric(left, right, |l, r| Object::Number(l * r))?,
+                    BinaryOperator::Divide => {
+                        let (Object::Number(l), Object::Number(r)) = (&left, &right) else {
+                            return Err(expected_numbers(&[&left, &right], "both sides of binary operator must be numbers"));
+                        };
+                        if *r == 0.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("cannot divide {l} by zero")));
+                        }
+                        Object::Number(l / r)
+                    },
+                    BinaryOperator::Power => {
+                        let (Object::Number(base), Object::Number(exponent)) = (&left, &right) else {
+                            return Err(expected_numbers(&[&left, &right], "both sides of `^` must be numbers"));
+                        };
+                        Object::Number(power(*base, *exponent)?)
+                    },
+
+                    BinaryOperator::Equals => Object::Boolean(left == right),
+                    BinaryOperator::NotEquals => Object::Boolean(left != right),
+                    BinaryOperator::LessThan => numeric(left, right, |l, r| Object::Boolean(l < r))?,
+                    BinaryOperator::GreaterThan => numeric(left, right, |l, r| Object::Boolean(l > r))?,
+                    BinaryOperator::LessThanOrEquals => numeric(left, right, |l, r| Object::Boolean(l <= r))?,
+                    BinaryOperator::GreaterThanOrEquals => numeric(left, right, |l, r| Object::Boolean(l >= r))?,
+                    BinaryOperator::In => {
+                        let Object::Array(items) = right else {
+                            return Err(RuntimeError::type_mismatch("array", &right, "right side of `in` must be an array"));
+                        };
+                        Object::Boolean(items.borrow().contains(&left))
+                    },
+
+                    // Handled earlier
+                    BinaryOperator::And | BinaryOperator::Or | BinaryOperator::NullCoalesce => unreachable!(),
+                };
+
+                if self.check_finite_numbers
+                    && let Object::Number(n) = result
+                    && !n.is_finite()
+                    && let Some((l, r)) = operands
+                {
+                    let symbol = match operator {
+                        BinaryOperator::Add => "+",
+                        BinaryOperator::Subtract => "-",
+                        BinaryOperator::Multiply => "*",
+                        BinaryOperator::Divide => "/",
+                        BinaryOperator::Power => "^",
+                        _ => unreachable!("only arithmetic operators give numbers"),
                     };
-                    Ok(f(left, right))
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("`{l} {symbol} {r}` gave {n}, which is not a finite number")));
                 }
 
-                Ok(Value::ReadOnly(
-                    match operator {
-                        BinaryOperator::Add => numeric(left, right, |l, r| Object::Number(l + r))?,
-                        BinaryOperator::Subtract => numeric(left, right, |l, r| Object::Number(l - r))?,
-                        BinaryOperator::Multiply => numeric(left, right, |l, r| Object::Number(l * r))?,
-                        BinaryOperator::Divide => numeric(left, right, |l, r| Object::Number(l / r))?,
+                Ok(Value::ReadOnly(result))
+            }
 
-                        BinaryOperator::Equals => Object::Boolean(left == right),
-                        BinaryOperator::NotEquals => Object::Boolean(left != right),
-                        BinaryOperator::LessThan => numeric(left, right, |l, r| Object::Boolean(l < r))?,
-                        BinaryOperator::GreaterThan => numeric(left, right, |l, r| Object::Boolean(l > r))?,
-                        BinaryOperator::LessThanOrEquals => numeric(left, right, |l, r| Object::Boolean(l <= r))?,
-                        BinaryOperator::GreaterThanOrEquals => numeric(left, right, |l, r| Object::Boolean(l >= r))?,
+            Expression::Range { start, end, step } => {
+                fn endpoint(obj: Object) -> InterpreterResult<i64> {
+                    let Object::Number(n) = obj else {
+                        return Err(RuntimeError::type_mismatch("number", &obj, "range endpoints and step must be numbers"));
+                    };
 
-                        // Handled earlier
-                        BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+                    // `as` would quietly saturate anything which doesn't fit
+                    let n = n.round();
+                    if !(i64::MIN as f64..i64::MAX as f64).contains(&n) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("range endpoint or step {n} is too large")));
                     }
-                ))
+                    Ok(n as i64)
+                }
+
+                let start = endpoint(self.interpret_expression(start, frame)?.read()?)?;
+                let end = endpoint(self.interpret_expression(end, frame)?.read()?)?;
+                let step = match step {
+                    Some(step) => endpoint(self.interpret_expression(step, frame)?.read()?)?,
+                    None => 1,
+                };
+                if step == 0 {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, "range step cannot be zero"));
+                }
+
+                Ok(Value::ReadOnly(Object::Range { start, end, step }))
             }
 
-            Expression::SpawnEntity { name } => {
+            Expression::SpawnEntity { name, initializers } => {
                 let Some(entity_kind) = self.entity_kinds.get(name).cloned() else {
-                    return Err(RuntimeError::new(format!("no entity declaration named `{name}`")))
+                    return Err(RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("no entity declaration named `{name}`")))
                 };
 
+                // Evaluate `with` values up-front, in the spawning context
+                let mut initial_values = HashMap::new();
+                for (ivar_name, value) in initializers {
+                    if !entity_kind.ivars.iter().any(|ivar| &ivar.name == ivar_name) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("cannot initialise undeclared instance variable `{ivar_name}` on entity `{name}`")));
+                    }
+                    if initial_values.contains_key(ivar_name) {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("instance variable `{ivar_name}` is initialised more than once")));
+                    }
+
+                    let value = self.interpret_expression(value, frame)?.read()?;
+                    initial_values.insert(ivar_name.clone(), value);
+                }
+
                 // Build new entity with dummy ivars
                 let mut new_entity = Entity {
                     kind: entity_kind.clone(),
                     ivars: HashMap::new(),
+                    last_drawn_size: None,
+                    asleep: false,
                 };
                 for ivar in &entity_kind.ivars {
-                    new_entity.ivars.insert(ivar.to_owned(), Object::Null);
+                    new_entity.ivars.insert(ivar.name.to_owned(), Object::Null);
                 }
 
                 let entity_id = EntityId(self.next_entity_id);
@@ -517,14 +1517,31 @@ impl Interpreter {
 
                 self.entities.insert(entity_id, new_entity);
                 self.entities_by_kinds.entry(name.clone()).or_default().insert(entity_id);
+                if self.ticking {
+                    self.spawned_this_tick.insert(entity_id);
+                }
+
+                let mut constructor_frame = Frame {
+                    entity: Some(entity_id),
+                    locals: HashMap::new(),
+                };
+
+                // Evaluate default values in declaration order, so they can refer to earlier ones.
+                // Instance variables given by `with` use that value instead of their default
+                for ivar in &entity_kind.ivars {
+                    let value = if let Some(value) = initial_values.remove(&ivar.name) {
+                        value
+                    } else if let Some(default) = &ivar.default {
+                        self.interpret_expression(default, &mut constructor_frame)?.read()?
+                    } else {
+                        continue;
+                    };
+                    self.entities.get_mut(&entity_id).unwrap().ivars.insert(ivar.name.to_owned(), value);
+                }
 
                 // Execute constructor
                 if let Some(constructor) = entity_kind.constructor.as_ref() {
-                    let mut constructor_frame = Frame {
-                        entity: Some(entity_id),
-                        locals: HashMap::new(),
-                    };
-                    self.execute_statement_body(&constructor, &mut constructor_frame)?;
+                    self.execute_named_body(format!("{}.constructor", entity_kind.name), &constructor, &mut constructor_frame)?;
                 }
 
                 Ok(Value::ReadOnly(Object::Entity(entity_id)))
@@ -533,7 +1550,7 @@ impl Interpreter {
             Expression::DestroyEntity(target) => {
                 let target = self.interpret_expression(target, frame)?.read()?;
                 let Object::Entity(entity_id) = target else {
-                    return Err(RuntimeError::new(format!("used `destroy` on non-entity object: {}", target.describe(self))));
+                    return Err(RuntimeError::type_mismatch("entity", &target, format!("used `destroy` on non-entity object: {}", target.describe(self))));
                 };
 
                 self.entities_pending_destroy.insert(entity_id);
@@ -541,17 +1558,46 @@ impl Interpreter {
                 Ok(Value::ReadOnly(Object::Null))
             }
 
-            Expression::Echo(target) => {
-                let target = self.interpret_expression(target, frame)?.read()?;
-                println!("{}", target.describe(self));
-                Ok(Value::ReadOnly(target))
+            Expression::Echo(items) => {
+                let items = items.iter()
+                    .map(|e| self.interpret_expression(e, frame).and_then(|v| v.read()))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                println!("{}", items.iter().map(|i| i.describe(self)).collect::<Vec<_>>().join(" "));
+
+                // With a single item, `echo` can be used in the middle of an expression to inspect it
+                if let [item] = items.as_slice() {
+                    Ok(Value::ReadOnly(item.clone()))
+                } else {
+                    Ok(Value::ReadOnly(Object::Null))
+                }
             }
         }
     }
 
+    /// Builds a [`Value`] which reads and writes an instance variable of a specific entity.
+    pub(crate) fn instance_var_value<'a>(&'a mut self, entity_id: EntityId, id: &'a str) -> InterpreterResult<Value<'a>> {
+        let Some(entity) = self.entities.get(&entity_id) else {
+            return Err(RuntimeError::new(RuntimeErrorKind::EntityNotFound, format!("cannot access instance variable `{id}` of an entity which no longer exists")));
+        };
+
+        if let Some(obj) = entity.ivars.get(id) {
+            Ok(Value::ReadWrite {
+                value: obj.clone(),
+                write: Box::new(move |o| {
+                    let entity = self.entities.get_mut(&entity_id).unwrap();
+                    entity.ivars.insert(id.to_owned(), o);
+                    Ok(())
+                }),
+            })
+        } else {
+            Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("undeclared instance variable `{id}` on entity `{}`", entity.kind.name)))
+        }
+    }
+
     fn forbid_sound(&self) -> InterpreterResult {
         if !self.pending_sounds.is_empty() {
-            return Err(RuntimeError::new("cannot play sound from anywhere other than `tick` (or a function it calls)"))
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "cannot play sound from anywhere other than `tick` (or a function it calls)"))
         }
 
         Ok(())
@@ -586,7 +1632,7 @@ impl<'w> Value<'w> {
 
     pub fn write(self, value: Object) -> InterpreterResult {
         match self {
-            Value::ReadOnly(_) => Err(RuntimeError::new("expression cannot be target of an assignment")),
+            Value::ReadOnly(_) => Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "expression cannot be target of an assignment")),
             Value::WriteOnly { write, .. } => {
                 write(value)?;
                 Ok(())
@@ -600,32 +1646,99 @@ impl<'w> Value<'w> {
 }
 
 /// Uniquely refers to an entity. Allows entities to be passed around like objects.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct EntityId(usize);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntityId(pub(crate) usize);
 
 /// A specific instance of an entity.
 pub struct Entity {
     pub kind: Rc<EntityKind>,
     pub ivars: HashMap<String, Object>,
+
+    /// The width and height of the sprite returned by this entity's most recent `draw`
+    pub last_drawn_size: Option<(usize, usize)>,
+
+    /// Set by `sleep()`, which stops the entity from ticking until `wake()`
+    pub asleep: bool,
 }
 
 impl Entity {
     pub fn draw_position_ivars(&self) -> InterpreterResult<(f64, f64)> {
+        let kind = &self.kind.name;
         let Some(x) = self.ivars.get("x") else {
-            return Err(RuntimeError::new("instance variable `x` must be declared when drawing a sprite"));
+            return Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("entity `{kind}` must declare `x` to be drawn")));
         };
         let Some(y) = self.ivars.get("y") else {
-            return Err(RuntimeError::new("instance variable `y` must be declared when drawing a sprite"));
+            return Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("entity `{kind}` must declare `y` to be drawn")));
         };
 
         let (Object::Number(x), Object::Number(y)) = (x, y) else {
-            return Err(RuntimeError::new("instance variables `x` and `y` must both be numbers"));
+            return Err(expected_numbers(&[x, y], format!("instance variables `x` and `y` of entity `{kind}` must both be numbers to be drawn")));
+        };
+
+        Ok((self.finite_draw_ivar("x", *x)?, self.finite_draw_ivar("y", *y)?))
+    }
+
+    /// The layer which the entity's sprite is drawn on, from its `@z`. Entities without one, or
+    /// where it is `null`, are on layer 0.
+    pub fn draw_layer_ivar(&self) -> InterpreterResult<f64> {
+        match self.ivars.get("z") {
+            None | Some(Object::Null) => Ok(0.0),
+            Some(Object::Number(z)) => self.finite_draw_ivar("z", *z),
+            Some(z) => Err(RuntimeError::type_mismatch("number", z, format!("instance variable `z` of entity `{}` must be a number", self.kind.name))),
+        }
+    }
+
+    /// Errors if an instance variable used to draw the entity is infinity or NaN, which would
+    /// otherwise make the sprite silently disappear.
+    fn finite_draw_ivar(&self, name: &str, value: f64) -> InterpreterResult<f64> {
+        if !value.is_finite() {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("instance variable `{name}` of entity `{}` is {value}, so it can't be drawn", self.kind.name)));
+        }
+        Ok(value)
+    }
+
+    /// The entity's `@x` and `@y`, or `None` if either is undeclared or isn't a number.
+    pub fn position(&self) -> Option<(f64, f64)> {
+        match (self.ivars.get("x"), self.ivars.get("y")) {
+            (Some(Object::Number(x)), Some(Object::Number(y))) => Some((*x, *y)),
+            _ => None,
+        }
+    }
+
+    /// The area which this entity occupies for collision detection, as `(x, y, width, height)`.
+    /// 
+    /// The size comes from `@width` and `@height` if they are declared, or otherwise the size of
+    /// the last-drawn sprite. Returns `None` if the entity has no position or size.
+    pub fn collision_box(&self) -> Option<(f64, f64, f64, f64)> {
+        let (x, y) = self.draw_position_ivars().ok()?;
+        let (width, height) = match (self.ivars.get("width"), self.ivars.get("height")) {
+            (Some(Object::Number(width)), Some(Object::Number(height))) => (*width, *height),
+            _ => {
+                let (width, height) = self.last_drawn_size?;
+                (width as f64, height as f64)
+            }
         };
 
-        Ok((*x, *y))
+        Some((x, y, width, height))
+    }
+
+    /// Whether the collision boxes of two entities overlap. Boxes which only touch along an edge
+    /// don't overlap.
+    /// 
+    /// Returns `None` if either entity doesn't have a collision box.
+    pub fn overlaps(&self, other: &Entity) -> Option<bool> {
+        let (ax, ay, aw, ah) = self.collision_box()?;
+        let (bx, by, bw, bh) = other.collision_box()?;
+        Some(ax < bx + bw && bx < ax + aw && ay < by + bh && by < ay + ah)
     }
 }
 
+/// An `on_collision` handler, as the name of its parameter and its body.
+pub type CollisionHandler = (String, Vec<Statement>);
+
+/// An `on_message` handler, as the names of its two parameters and its body.
+pub type MessageHandler = (String, String, Vec<Statement>);
+
 /// An entity definition which can be instantiated.
 #[derive(Debug, Clone)]
 pub struct EntityKind {
@@ -633,8 +1746,24 @@ pub struct EntityKind {
     pub functions: HashMap<String, FunctionDeclaration>,
     pub constructor: Option<Vec<Statement>>,
     pub tick_handler: Option<Vec<Statement>>,
+
+    /// The priority given with `tick priority N`. Kinds with lower priorities tick first, and
+    /// kinds without one have priority 0
+    pub tick_priority: Option<f64>,
+
     pub draw_handler: Option<Vec<Statement>>,
-    pub ivars: Vec<String>,
+    pub destroy_handler: Option<Vec<Statement>>,
+    pub collision_handler: Option<CollisionHandler>,
+    pub message_handler: Option<MessageHandler>,
+
+    /// `on_press` and `on_release` handlers, keyed by the name of the key they handle
+    pub press_handlers: HashMap<String, Vec<Statement>>,
+    pub release_handlers: HashMap<String, Vec<Statement>>,
+
+    pub ivars: Vec<InstanceVar>,
+
+    /// Names of the entities which this one copies definitions from with `use`
+    pub uses: Vec<String>,
 }
 
 impl PartialEq for EntityKind {
@@ -644,17 +1773,66 @@ impl PartialEq for EntityKind {
     }
 }
 
+/// An instance variable declared by an entity definition.
+#[derive(Debug, Clone)]
+pub struct InstanceVar {
+    pub name: String,
+
+    /// Evaluated when the entity is spawned, before the constructor runs
+    pub default: Option<Expression>,
+
+    /// If this instance variable was copied into an entity by `use`, the name of the entity it
+    /// came from
+    pub used_from: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct FunctionDeclaration {
     pub name: String,
     pub parameters: Vec<String>,
     pub body: Vec<Statement>,
+
+    /// If this function was copied into an entity by `use`, the name of the entity it came from
+    pub used_from: Option<String>,
+}
+
+/// Progress through a melody which is being played.
+#[derive(Debug, Clone)]
+pub(crate) struct MelodyPlayback {
+    pub tones: Vec<Tone>,
+
+    /// Whether to start again from the first tone after the last one
+    pub looping: bool,
+
+    /// The tick which the first tone was played on, or `None` if that hasn't happened yet
+    pub started_tick: Option<u64>,
+
+    /// Index into `tones` of the next tone to play, and when it should start, in seconds after
+    /// `started_tick`
+    pub next_tone: usize,
+    pub next_tone_start: f64,
+}
+
+impl MelodyPlayback {
+    pub fn new(tones: Vec<Tone>, looping: bool) -> Self {
+        Self { tones, looping, started_tick: None, next_tone: 0, next_tone_start: 0.0 }
+    }
+}
+
+/// A message logged through the `Debug` singleton.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugMessage {
+    Log(String),
+    Warning(String),
 }
 
 pub struct DrawOperation {
     pub sprite: Sprite,
     pub x: f64,
     pub y: f64,
+
+    /// The layer this was drawn on. Operations are returned from lowest to highest layer
+    pub z: f64,
 }
 
 /// State of external game inputs.
@@ -669,13 +1847,66 @@ pub struct InputReport {
 
     pub x: bool,
     pub z: bool,
+
+    /// Position of the mouse pointer, in display pixels rather than window pixels
+    pub mouse_x: f64,
+    pub mouse_y: f64,
+
+    pub mouse_left: bool,
+    pub mouse_right: bool,
+}
+
+impl InputReport {
+    /// The names of all supported keys, as used by `on_press` and `on_release`.
+    pub const KEYS: [&str; 6] = ["up", "down", "left", "right", "x", "z"];
+
+    /// Whether the key with the given name is held. Returns `None` if there is no such key.
+    pub fn key(&self, name: &str) -> Option<bool> {
+        match name {
+            "up" => Some(self.up),
+            "down" => Some(self.down),
+            "left" => Some(self.left),
+            "right" => Some(self.right),
+            "x" => Some(self.x),
+            "z" => Some(self.z),
+            _ => None,
+        }
+    }
 }
 
 /// State of the display which this interpreter is rendering to. 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct DisplayConfig {
     pub width: usize,
     pub height: usize,
+
+    /// How many colours the host's palette has, which [`crate::Pixel::Set`] indexes into. At most 16
+    pub palette_size: usize,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self { width: 0, height: 0, palette_size: PALETTE_NAMES.len() }
+    }
+}
+
+/// Names of the colours in the standard palette, by index, which `Color` uses.
+pub const PALETTE_NAMES: [&str; 16] = [
+    "black", "dark_blue", "dark_purple", "dark_green", "brown", "dark_grey", "light_grey", "white",
+    "red", "orange", "yellow", "green", "blue", "lavender", "pink", "peach",
+];
+
+/// How quickly the host is running the game.
+#[derive(Debug, Clone)]
+pub struct TimeConfig {
+    /// How many times `execute_tick` is called each second
+    pub ticks_per_second: f64,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        Self { ticks_per_second: 30.0 }
+    }
 }
 
 pub struct Frame {
@@ -686,18 +1917,142 @@ pub struct Frame {
     pub entity: Option<EntityId>,
 }
 
+/// An error for when all of `values` should have been numbers, which reports the first one that
+/// isn't.
+pub(crate) fn expected_numbers(values: &[&Object], msg: impl Into<String>) -> RuntimeError {
+    let found = values.iter().find(|value| !matches!(value, Object::Number(_))).unwrap_or(&values[0]);
+    RuntimeError::type_mismatch("number", found, msg)
+}
+
+/// Checks that every tone in a sound or melody lasts for a finite length of time which isn't
+/// negative, so that melodies always move forward as they're played.
+pub(crate) fn check_tone_durations(tones: &[Tone]) -> InterpreterResult {
+    if let Some(tone) = tones.iter().find(|tone| !tone.duration.is_finite() || tone.duration < 0.0) {
+        return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("tone duration must be a finite number which isn't negative, but is {}", tone.duration)));
+    }
+    Ok(())
+}
+
+/// Raises `base` to the power of `exponent`, shared by the `^` operator and `Math.pow`.
+pub(crate) fn power(base: f64, exponent: f64) -> InterpreterResult<f64> {
+    if base < 0.0 && exponent.fract() != 0.0 {
+        return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("cannot raise negative number {base} to fractional power {exponent}")));
+    }
+    Ok(base.powf(exponent))
+}
+
 #[derive(Debug, Clone)]
-pub struct RuntimeError(String);
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    message: String,
+
+    /// Where the error happened. The first entry is the statement which failed, and each one after
+    /// it is the statement which called the function of the entry before
+    pub trace: Vec<TraceEntry>,
+
+    /// How many handlers and functions were running when the last entry was added to `trace`
+    traced_depth: Option<usize>,
+}
+
+/// What sort of problem caused a [`RuntimeError`], so that it can be handled without inspecting
+/// the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// A local, const or entity declaration was used which doesn't exist
+    UndefinedIdentifier,
+
+    /// An instance variable was used which the entity doesn't declare
+    UndeclaredIvar,
+
+    /// A value wasn't of the type which was needed
+    TypeMismatch {
+        /// A description of what was needed, like `number`
+        expected: String,
+
+        /// The [`Object::type_name`] of the value which was found instead
+        found: String,
+    },
+
+    /// A function was called with the wrong number of arguments. For functions which take a
+    /// variable number of arguments, `expected` is the minimum
+    ArityMismatch {
+        expected: usize,
+        found: usize,
+    },
+
+    /// A function was called which doesn't exist
+    NoSuchFunction,
+
+    /// An entity was used after it was destroyed
+    EntityNotFound,
+
+    /// A value was of the right type, but not allowed, like a negative square root
+    InvalidValue,
+
+    /// Something was used where it isn't allowed, like `this` outside of an entity
+    InvalidContext,
+
+    /// A declaration conflicts with another one, or appears where it isn't allowed
+    InvalidDeclaration,
+
+    /// A `Debug.assert` failed
+    AssertionFailed,
+
+    /// Too much work was done at once, probably because of an infinite loop. See
+    /// [`Interpreter::set_step_limit`]
+    StepLimitExceeded,
+}
+
+/// A statement within a [`RuntimeError`]'s trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The handler or function which the statement is in, like `Enemy.tick`, if it's known
+    pub function: Option<String>,
+
+    pub span: Span,
+}
 
 impl RuntimeError {
-    pub fn new(msg: impl Into<String>) -> Self {
-        Self(msg.into())
+    pub fn new(kind: RuntimeErrorKind, msg: impl Into<String>) -> Self {
+        Self { kind, message: msg.into(), trace: vec![], traced_depth: None }
+    }
+
+    /// An error for a value which isn't of the `expected` type.
+    pub fn type_mismatch(expected: impl Into<String>, found: &Object, msg: impl Into<String>) -> Self {
+        Self::new(RuntimeErrorKind::TypeMismatch { expected: expected.into(), found: found.type_name().to_owned() }, msg)
+    }
+
+    /// The description of the error, without any location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Adds the statement at `span` to the trace as the error passes through it, unless a
+    /// statement at the same depth of `call_stack` has already been added.
+    fn traced(mut self, span: &Span, call_stack: &[String]) -> Self {
+        if self.traced_depth != Some(call_stack.len()) {
+            self.trace.push(TraceEntry { function: call_stack.last().cloned(), span: span.clone() });
+            self.traced_depth = Some(call_stack.len());
+        }
+        self
     }
 }
 
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "runtime error: {}", self.0)
+        match self.trace.first() {
+            Some(TraceEntry { function: Some(function), span }) => write!(f, "runtime error in {function} ({span}): {}", self.message)?,
+            Some(TraceEntry { function: None, span }) => write!(f, "runtime error at {span}: {}", self.message)?,
+            None => write!(f, "runtime error: {}", self.message)?,
+        }
+
+        for TraceEntry { function, span } in self.trace.iter().skip(1) {
+            match function {
+                Some(function) => write!(f, "\n    called from {function} ({span})")?,
+                None => write!(f, "\n    called from {span}")?,
+            }
+        }
+        Ok(())
     }
 }
 impl Error for RuntimeError {}
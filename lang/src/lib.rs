@@ -11,3 +11,15 @@ pub use parser::*;
 
 mod object;
 pub use object::*;
+
+mod save;
+pub use save::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod font;
+pub use font::*;
+
+#[cfg(test)]
+mod test;
@@ -1,32 +1,171 @@
-use std::{ops::ControlFlow, rc::Rc};
+use std::{cell::RefCell, ops::ControlFlow, rc::Rc};
 
-use crate::{EntityId, EntityKind, Frame, FunctionDeclaration, Interpreter, InterpreterResult, RuntimeError, Sprite, Tone};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 
+use crate::{DebugMessage, EntityId, EntityKind, Frame, FunctionDeclaration, InputReport, Interpreter, InterpreterResult, MelodyPlayback, PALETTE_NAMES, Pixel, RuntimeError, RuntimeErrorKind, Sprite, Tone, expected_numbers, power, render_text};
+
+
+/// How many characters of each instance variable's value are included when describing an entity.
+const MAX_IVAR_DESCRIPTION_LENGTH: usize = 40;
+
+/// The most pixels a sprite made by `scale` can have, so that a big factor gives an error instead
+/// of using up all of the memory.
+const MAX_SCALED_SPRITE_PIXELS: usize = 1 << 24;
+
+/// Identifies an array by where it's stored, for spotting arrays which contain themselves.
+type ArrayPointer = *const RefCell<Vec<Object>>;
 
 /// Some generic object which can be passed around the interpreter.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Object {
     Null,
     Number(f64),
     Boolean(bool),
+    String(String),
     Entity(EntityId),
     EntityKind(Rc<EntityKind>),
     Sprite(Sprite),
     Sound(Tone),
-    Array(Vec<Object>),
+    Melody(Vec<Tone>),
+
+    /// Arrays are shared rather than copied, so every variable holding the same array sees
+    /// changes made through any of them
+    Array(Rc<RefCell<Vec<Object>>>),
+
+    Range {
+        start: i64,
+        end: i64,
+        step: i64,
+    },
 
     InputSingleton,
     DisplaySingleton,
     MathSingleton,
+    GlobalSingleton,
+    HostSingleton,
+    SpriteSingleton,
+    TimeSingleton,
+    TextSingleton,
+    StorageSingleton,
+    CameraSingleton,
+    DebugSingleton,
+    GameSingleton,
+    ColorSingleton,
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.equals_within(other, &mut vec![])
+    }
 }
 
 impl Object {
+    /// Compares two objects, where `comparing` holds the pairs of arrays which are already being
+    /// compared further up. Coming across one of those pairs again means the arrays contain
+    /// themselves, and they're treated as equal, since nothing so far has found them to differ.
+    fn equals_within(&self, other: &Self, comparing: &mut Vec<(ArrayPointer, ArrayPointer)>) -> bool {
+        match (self, other) {
+            // Arrays are compared by their items, but an array is always equal to itself
+            (Object::Array(a), Object::Array(b)) => {
+                let pair = (Rc::as_ptr(a), Rc::as_ptr(b));
+                if Rc::ptr_eq(a, b) || comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.equals_within(b, comparing))
+            },
+
+            (Object::Null, Object::Null) => true,
+            (Object::Number(a), Object::Number(b)) => a == b,
+            (Object::Boolean(a), Object::Boolean(b)) => a == b,
+            (Object::String(a), Object::String(b)) => a == b,
+            (Object::Entity(a), Object::Entity(b)) => a == b,
+            (Object::EntityKind(a), Object::EntityKind(b)) => a == b,
+            (Object::Sprite(a), Object::Sprite(b)) => a == b,
+            (Object::Sound(a), Object::Sound(b)) => a == b,
+            (Object::Melody(a), Object::Melody(b)) => a == b,
+            (Object::Range { start: s1, end: e1, step: st1 }, Object::Range { start: s2, end: e2, step: st2 }) =>
+                (s1, e1, st1) == (s2, e2, st2),
+
+            (Object::InputSingleton, Object::InputSingleton)
+                | (Object::DisplaySingleton, Object::DisplaySingleton)
+                | (Object::MathSingleton, Object::MathSingleton)
+                | (Object::GlobalSingleton, Object::GlobalSingleton)
+                | (Object::HostSingleton, Object::HostSingleton)
+                | (Object::SpriteSingleton, Object::SpriteSingleton)
+                | (Object::TimeSingleton, Object::TimeSingleton)
+                | (Object::TextSingleton, Object::TextSingleton)
+                | (Object::StorageSingleton, Object::StorageSingleton)
+                | (Object::CameraSingleton, Object::CameraSingleton)
+                | (Object::DebugSingleton, Object::DebugSingleton)
+                | (Object::GameSingleton, Object::GameSingleton)
+                | (Object::ColorSingleton, Object::ColorSingleton) => true,
+
+            _ => false,
+        }
+    }
+
+    /// Creates a new array, which isn't shared with anything else yet.
+    pub fn array(items: Vec<Object>) -> Object {
+        Object::Array(Rc::new(RefCell::new(items)))
+    }
+
     pub fn call_function(&self, interpreter: &mut Interpreter, name: &str, arguments: Vec<Object>) -> InterpreterResult<Object> {
+        // Type predicates can be called on anything, except that an entity's own functions take
+        // precedence over them
+        if let Some(result) = self.type_predicate(name) {
+            let shadowed = match self {
+                Object::Entity(entity_id) => interpreter.entities.get(entity_id)
+                    .is_some_and(|entity| entity.kind.functions.contains_key(name)),
+                _ => false,
+            };
+
+            if !shadowed {
+                let [] = arguments.as_slice() else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                return Ok(Object::Boolean(result));
+            }
+        }
+
         match self {
             Object::Entity(entity_id) => {
-                let entity_kind = interpreter.entities[&entity_id].kind.clone();
+                // References to entities can outlive them, so check that this one still exists.
+                // `alive()` is the only function which can be called on one that doesn't, apart
+                // from `send`, whose message is dropped
+                let Some(entity) = interpreter.entities.get(entity_id) else {
+                    if name == "alive" {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        return Ok(Object::Boolean(false));
+                    }
+                    if name == "send" {
+                        Self::message_arguments(name, &arguments)?;
+                        return Ok(Object::Null);
+                    }
+                    return Err(RuntimeError::new(RuntimeErrorKind::EntityNotFound, format!("cannot call function `{}` on an entity which no longer exists", name)));
+                };
+
+                let entity_kind = entity.kind.clone();
                 let Some(FunctionDeclaration { parameters, body, .. }) = entity_kind.functions.get(name) else {
-                    return Err(RuntimeError::new(format!("entity declaration `{}` has no function named `{}`", entity_kind.name, name)));
+                    // If there's no function, fall back to an implicit getter for the instance
+                    // variable with the same name
+                    if let Some(value) = interpreter.entities[entity_id].ivars.get(name) {
+                        if !arguments.is_empty() {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        }
+                        return Ok(value.clone());
+                    }
+
+                    // Failing that, try the built-in functions which every entity has
+                    if let Some(result) = Self::call_builtin_entity_function(interpreter, *entity_id, &entity_kind, name, &arguments)? {
+                        return Ok(result);
+                    }
+
+                    return Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("entity declaration `{}` has no function or instance variable named `{}`", entity_kind.name, name)));
                 };
 
                 if parameters.len() != arguments.len() {
@@ -38,7 +177,7 @@ impl Object {
                     locals: parameters.iter().cloned().zip(arguments).collect(),
                 };
 
-                let retval = match interpreter.execute_statement_body(&body, &mut frame)? {
+                let retval = match interpreter.execute_named_body(format!("{}.{}", entity_kind.name, name), &body, &mut frame)? {
                     ControlFlow::Break(obj) => obj,
                     ControlFlow::Continue(_) => Object::Null,
                 };
@@ -46,64 +185,497 @@ impl Object {
             },
 
             Object::EntityKind(kind) => {
-                // All `EntityKind` functions take no parameters
-                if arguments.len() != 0 {
+                // All `EntityKind` functions take no parameters, except `send_all`
+                if name != "send_all" && arguments.len() != 0 {
                     Self::incorrect_arity(name, 0, arguments.len())?;
                 }
 
                 match name {
+                    "name" => Ok(Object::String(kind.name.clone())),
+
                     "all" => {
                         let Some(entities_of_kind) = interpreter.entities_by_kinds.get(&kind.name) else {
-                            return Ok(Object::Array(vec![]))
+                            return Ok(Object::array(vec![]))
                         };
-                        Ok(Object::Array(
+                        Ok(Object::array(
                             entities_of_kind.iter()
                                 .map(|id| Object::Entity(*id))
                                 .collect()
                         ))
                     },
 
-                    _ => Err(RuntimeError::new(format!("`{}` has no function named `{}`", self.describe(interpreter), name))),
+                    // `destroy_all()` destroys every instance, in the same way as `destroy`, and
+                    // returns how many weren't already being destroyed
+                    "destroy_all" => {
+                        let Some(entities_of_kind) = interpreter.entities_by_kinds.get(&kind.name) else {
+                            return Ok(Object::Number(0.0))
+                        };
+
+                        let mut count = 0;
+                        for id in entities_of_kind {
+                            if interpreter.entities_pending_destroy.insert(*id) {
+                                count += 1;
+                            }
+                        }
+                        Ok(Object::Number(count as f64))
+                    },
+
+                    // `sleep_all()` and `wake_all()` work like calling `sleep()` or `wake()` on every
+                    // instance, and return how many weren't already in that state
+                    "sleep_all" | "wake_all" => {
+                        let asleep = name == "sleep_all";
+                        let mut count = 0;
+                        for id in interpreter.entities_by_kinds.get(&kind.name).into_iter().flatten() {
+                            let entity = interpreter.entities.get_mut(id).unwrap();
+                            if entity.asleep != asleep {
+                                entity.asleep = asleep;
+                                count += 1;
+                            }
+                        }
+                        Ok(Object::Number(count as f64))
+                    },
+
+                    // `send_all(name, arguments)` sends a message to every instance which isn't
+                    // being destroyed, and returns how many there were
+                    "send_all" => {
+                        let (message, message_arguments) = Self::message_arguments(name, &arguments)?;
+                        Self::check_receives_messages(kind, &message)?;
+
+                        let recipients = interpreter.entities_by_kinds.get(&kind.name).into_iter().flatten()
+                            .filter(|id| !interpreter.entities_pending_destroy.contains(*id))
+                            .copied()
+                            .collect::<Vec<_>>();
+                        for id in &recipients {
+                            interpreter.pending_messages.push((*id, message.clone(), message_arguments.clone()));
+                        }
+                        Ok(Object::Number(recipients.len() as f64))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`{}` has no function named `{}`", self.describe(interpreter), name))),
                 }
             },
 
             Object::Sprite(sprite) => {
-                // All `Sprite` functions take no parameters
-                if arguments.len() != 0 {
-                    Self::incorrect_arity(name, 0, arguments.len())?;
+                match name {
+                    // `scale(n)` makes each pixel into an `n` by `n` block
+                    "scale" => {
+                        let [factor] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(factor) = factor else {
+                            return Err(RuntimeError::type_mismatch("number", factor, "argument to `scale` must be a number"));
+                        };
+                        let factor = factor.round();
+                        if factor < 1.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("sprite scale factor must be a positive integer, not {}", factor)));
+                        }
+                        let size = sprite.width.checked_mul(factor as usize)
+                            .zip(sprite.height.checked_mul(factor as usize))
+                            .and_then(|(width, height)| width.checked_mul(height));
+                        if !size.is_some_and(|size| size <= MAX_SCALED_SPRITE_PIXELS) {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("{}x{} sprite is too large to scale by {}", sprite.width, sprite.height, factor)));
+                        }
+                        Ok(Object::Sprite(sprite.scale(factor as usize)))
+                    },
+
+                    // `get_pixel(x, y)` returns the colour index of a pixel, or `null` if it's
+                    // transparent
+                    "get_pixel" => {
+                        let [x, y] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let (x, y) = Self::pixel_position(sprite, x, y)?;
+                        Ok(match sprite.pixel(x, y) {
+                            Pixel::Clear => Object::Null,
+                            Pixel::Set(colour) => Object::Number(colour as f64),
+                        })
+                    },
+
+                    // `set_pixel(x, y, value)` returns a new sprite with one pixel changed. The value
+                    // can be a colour index, `true` for black, or `null` or `false` for transparent
+                    "set_pixel" => {
+                        let [x, y, value] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 3, arguments.len())?;
+                        };
+                        let (x, y) = Self::pixel_position(sprite, x, y)?;
+                        let pixel = Self::pixel_value(interpreter, value)?;
+
+                        let mut sprite = sprite.clone();
+                        sprite.set_pixel(x, y, pixel);
+                        Ok(Object::Sprite(sprite))
+                    },
+
+                    // `overlay(other, dx, dy)` draws `other` on top of this sprite, offset by `dx` and
+                    // `dy`. The result is clipped to the size of this sprite
+                    "overlay" => {
+                        let [other, dx, dy] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 3, arguments.len())?;
+                        };
+                        let (Object::Sprite(other), Object::Number(dx), Object::Number(dy)) = (other, dx, dy) else {
+                            let message = "arguments to `overlay` must be a sprite and two numbers";
+                            return Err(match other {
+                                Object::Sprite(_) => expected_numbers(&[dx, dy], message),
+                                _ => RuntimeError::type_mismatch("sprite", other, message),
+                            });
+                        };
+                        Ok(Object::Sprite(sprite.overlay(other, dx.round() as i64, dy.round() as i64)))
+                    },
+
+                    // `pixels_overlap(other, dx, dy)` is a pixel-perfect collision check, with `other`
+                    // offset by `dx` and `dy`
+                    "pixels_overlap" => {
+                        let [other, dx, dy] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 3, arguments.len())?;
+                        };
+                        let (Object::Sprite(other), Object::Number(dx), Object::Number(dy)) = (other, dx, dy) else {
+                            let message = "arguments to `pixels_overlap` must be a sprite and two numbers";
+                            return Err(match other {
+                                Object::Sprite(_) => expected_numbers(&[dx, dy], message),
+                                _ => RuntimeError::type_mismatch("sprite", other, message),
+                            });
+                        };
+                        Ok(Object::Boolean(sprite.pixels_overlap(other, dx.round() as i64, dy.round() as i64)))
+                    },
+
+                    // `crop(x, y, width, height)` returns part of this sprite
+                    "crop" => {
+                        let [x, y, width, height] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 4, arguments.len())?;
+                        };
+                        let (Object::Number(x), Object::Number(y), Object::Number(width), Object::Number(height)) = (x, y, width, height) else {
+                            return Err(expected_numbers(&[x, y, width, height], "arguments to `crop` must be numbers"));
+                        };
+                        let [x, y, width, height] = [x, y, width, height].map(|n| n.round());
+                        if x < 0.0 || y < 0.0 || width < 1.0 || height < 1.0 || x + width > sprite.width as f64 || y + height > sprite.height as f64 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!(
+                                "cannot crop {}x{} area at ({}, {}) from {}x{} sprite",
+                                width, height, x, y, sprite.width, sprite.height,
+                            )));
+                        }
+                        Ok(Object::Sprite(sprite.crop(x as usize, y as usize, width as usize, height as usize)))
+                    },
+
+                    _ => {
+                        // All other `Sprite` functions take no parameters
+                        if arguments.len() != 0 {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        }
+
+                        match name {
+                            "width" => Ok(Object::Number(sprite.width as f64)),
+                            "height" => Ok(Object::Number(sprite.height as f64)),
+
+                            // `rows()` returns an array for each row, of the same values as `get_pixel`
+                            "rows" => Ok(Object::array(
+                                (0..sprite.height)
+                                    .map(|y| Object::array(
+                                        (0..sprite.width)
+                                            .map(|x| match sprite.pixel(x, y) {
+                                                Pixel::Clear => Object::Null,
+                                                Pixel::Set(colour) => Object::Number(colour as f64),
+                                            })
+                                            .collect()
+                                    ))
+                                    .collect()
+                            )),
+
+                            // Transformations return a new sprite, leaving this one unchanged
+                            "flip_x" => Ok(Object::Sprite(sprite.flip_x())),
+                            "flip_y" => Ok(Object::Sprite(sprite.flip_y())),
+                            "rotate90" => Ok(Object::Sprite(sprite.rotate90())),
+                            "rotate180" => Ok(Object::Sprite(sprite.rotate180())),
+                            "rotate270" => Ok(Object::Sprite(sprite.rotate270())),
+
+                            _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("sprite has no function named `{}`", name))),
+                        }
+                    },
                 }
+            }
 
+            Object::Sound(sound) => {
                 match name {
-                    "width" => Ok(Object::Number(sprite.width as f64)),
-                    "height" => Ok(Object::Number(sprite.height as f64)),
+                    "play" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        interpreter.pending_sounds.push(sound.clone());
+                        Ok(Object::Null)
+                    }
+
+                    // Builders return a new sound, leaving this one unchanged
+                    "with_duration" => {
+                        let [duration] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(duration) = duration else {
+                            return Err(RuntimeError::type_mismatch("number", duration, "argument to `with_duration` must be a number"));
+                        };
+                        if !(*duration > 0.0 && duration.is_finite()) {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("sound duration must be positive and finite, not {}", duration)));
+                        }
+                        Ok(Object::Sound(Tone { duration: *duration, ..*sound }))
+                    }
+
+                    // `with_volume(volume)` changes the volume, which is limited to between 0 and 1
+                    "with_volume" => {
+                        let [volume] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(volume) = volume else {
+                            return Err(RuntimeError::type_mismatch("number", volume, "argument to `with_volume` must be a number"));
+                        };
+                        Ok(Object::Sound(Tone { volume: volume.clamp(0.0, 1.0), ..*sound }))
+                    }
+
+                    // `with_note_offset(semitones)` moves the pitch up by a number of semitones, or
+                    // down if it's negative
+                    "with_note_offset" => {
+                        let [semitones] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(semitones) = semitones else {
+                            return Err(RuntimeError::type_mismatch("number", semitones, "argument to `with_note_offset` must be a number"));
+                        };
+                        let semitones = semitones.round() as i32;
+                        Ok(Object::Sound(Tone {
+                            note: sound.note.transpose(semitones),
+                            end_note: sound.end_note.map(|note| note.transpose(semitones)),
+                            ..*sound
+                        }))
+                    }
 
-                    _ => Err(RuntimeError::new(format!("sprite has no function named `{}`", name))),
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("sound has no function named `{}`", name))),
                 }
             }
 
-            Object::Sound(sound) => {
-                // All `Sound` functions take no parameters
-                if arguments.len() != 0 {
+            Object::Melody(tones) => {
+                if !arguments.is_empty() {
                     Self::incorrect_arity(name, 0, arguments.len())?;
                 }
 
                 match name {
                     "play" => {
-                        interpreter.pending_sounds.push(sound.clone());
+                        interpreter.playing_melodies.push(MelodyPlayback::new(tones.clone(), false));
                         Ok(Object::Null)
-                    }
+                    },
+
+                    // `loop()` plays the melody over and over, until it is stopped
+                    "loop" => {
+                        // This would never get past the first tick
+                        if tones.iter().map(|tone| tone.duration).sum::<f64>() <= 0.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, "cannot loop a melody which has no duration"));
+                        }
+                        interpreter.playing_melodies.push(MelodyPlayback::new(tones.clone(), true));
+                        Ok(Object::Null)
+                    },
+
+                    // `stop()` stops every playback of this melody. Any tone which has already
+                    // started will still finish
+                    "stop" => {
+                        interpreter.playing_melodies.retain(|playback| playback.tones != *tones);
+                        Ok(Object::Null)
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("melody has no function named `{}`", name))),
+                }
+            }
+
+            Object::Array(array) => {
+                if Self::ARRAY_MUTATING_FUNCTIONS.contains(&name) {
+                    return Self::call_mutating_array_function(array, name, arguments);
+                }
+
+                let items = array.borrow();
+                match name {
+                    "length" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(items.len() as f64))
+                    },
+
+                    "is_empty" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Boolean(items.is_empty()))
+                    },
+
+                    // `contains(item)` is the same as `item in array`
+                    "contains" => {
+                        let [item] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        Ok(Object::Boolean(items.contains(item)))
+                    },
+
+                    // `index_of(item)` returns the index of the first item equal to `item`, or `null`
+                    // if there isn't one
+                    "index_of" => {
+                        let [item] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        Ok(items.iter().position(|i| i == item).map(|i| Object::Number(i as f64)).unwrap_or(Object::Null))
+                    },
+
+                    // Reordering functions return a new array, rather than changing this one
+                    "sort" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        let mut numbers = Self::array_numbers(name, &items, interpreter)?;
+                        numbers.sort_by(f64::total_cmp);
+                        Ok(Object::array(numbers.into_iter().map(Object::Number).collect()))
+                    },
+
+                    // `sort_by(ivar)` sorts entities by one of their instance variables, which must be
+                    // a number
+                    "sort_by" => {
+                        let [ivar] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::String(ivar) = ivar else {
+                            return Err(RuntimeError::type_mismatch("string", ivar, "argument to `sort_by` must be a string"));
+                        };
+                        let mut keyed = items.iter()
+                            .map(|item| {
+                                let Object::Entity(entity_id) = item else {
+                                    return Err(RuntimeError::type_mismatch("entity", item, format!("`sort_by` can only sort entities, not {}", item.describe(interpreter))));
+                                };
+                                let Some(entity) = interpreter.entities.get(entity_id) else {
+                                    return Err(RuntimeError::new(RuntimeErrorKind::EntityNotFound, "`sort_by` cannot sort an entity which no longer exists"));
+                                };
+                                match entity.ivars.get(ivar) {
+                                    Some(Object::Number(key)) => Ok((*key, item.clone())),
+                                    Some(key) => Err(RuntimeError::type_mismatch("number", key, format!("instance variable `{}` on entity `{}` must be a number to sort by it", ivar, entity.kind.name))),
+                                    None => Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("undeclared instance variable `{}` on entity `{}`", ivar, entity.kind.name))),
+                                }
+                            })
+                            .collect::<InterpreterResult<Vec<_>>>()?;
+                        keyed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+                        Ok(Object::array(keyed.into_iter().map(|(_, item)| item).collect()))
+                    },
+
+                    "reverse" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::array(items.iter().rev().cloned().collect()))
+                    },
+
+                    // `shuffle()` uses the same random numbers as `Math`, so it is deterministic after
+                    // `Math.seed`
+                    "shuffle" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+
+                        // Fisher-Yates: swap each item with a random item at or before it
+                        let mut items = items.clone();
+                        for i in (1..items.len()).rev() {
+                            let j = interpreter.rng.random_range(0..=i);
+                            items.swap(i, j);
+                        }
+                        Ok(Object::array(items))
+                    },
+
+                    // `choice()` returns a random item, or `null` if the array is empty
+                    "choice" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        if items.is_empty() {
+                            return Ok(Object::Null);
+                        }
+                        Ok(items[interpreter.rng.random_range(0..items.len())].clone())
+                    },
+
+                    "concat" => {
+                        let [other] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Array(other) = other else {
+                            return Err(RuntimeError::type_mismatch("array", other, "argument to `concat` must be an array"));
+                        };
+                        Ok(Object::array(items.iter().chain(other.borrow().iter()).cloned().collect()))
+                    },
+
+                    // `slice(start, end)` returns the items from `start` up to but not including `end`.
+                    // Indexes outside of the array are clamped to its bounds
+                    "slice" => {
+                        let [start, end] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let (Object::Number(start), Object::Number(end)) = (start, end) else {
+                            return Err(expected_numbers(&[start, end], "arguments to `slice` must be numbers"));
+                        };
+                        let start = start.clamp(0.0, items.len() as f64) as usize;
+                        let end = end.clamp(0.0, items.len() as f64) as usize;
+                        Ok(Object::array(items.get(start..end).unwrap_or_default().to_vec()))
+                    },
 
-                    _ => Err(RuntimeError::new(format!("sound has no function named `{}`", name))),
+                    // `join(separator)` describes each item, with `separator` between them
+                    "join" => {
+                        let [separator] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::String(separator) = separator else {
+                            return Err(RuntimeError::type_mismatch("string", separator, "argument to `join` must be a string"));
+                        };
+                        Ok(Object::String(items.iter().map(|i| i.describe(interpreter)).collect::<Vec<_>>().join(separator)))
+                    },
+
+                    "sum" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(Self::array_numbers(name, &items, interpreter)?.into_iter().sum()))
+                    },
+
+                    // `min`, `max` and `average` return `null` for an empty array, since there's no
+                    // sensible number to give
+                    "min" | "max" | "average" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        let numbers = Self::array_numbers(name, &items, interpreter)?;
+                        if numbers.is_empty() {
+                            return Ok(Object::Null);
+                        }
+
+                        let result = match name {
+                            "min" => numbers.into_iter().fold(f64::INFINITY, f64::min),
+                            "max" => numbers.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                            _ => numbers.iter().sum::<f64>() / numbers.len() as f64,
+                        };
+                        Ok(Object::Number(result))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("array has no function named `{}`", name))),
                 }
             }
 
             Object::InputSingleton => {
-                // All `Input` functions take no parameters
-                if arguments.len() != 0 {
+                // All `Input` functions take no parameters, except `pressed`
+                if name != "pressed" && arguments.len() != 0 {
                     Self::incorrect_arity(name, 0, arguments.len())?;
                 }
 
                 match name {
+                    // `pressed(name)` checks whether a key is held, by its name
+                    "pressed" => {
+                        let [key] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::String(key) = key else {
+                            return Err(RuntimeError::type_mismatch("string", key, "argument to `Input.pressed` must be a string"));
+                        };
+                        let Some(held) = interpreter.input_report.key(key) else {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("unknown key `{}` - valid keys are {}", key, InputReport::KEYS.join(", "))));
+                        };
+                        Ok(Object::Boolean(held))
+                    },
+
                     "up_pressed" => Ok(Object::Boolean(interpreter.input_report.up)),
                     "down_pressed" => Ok(Object::Boolean(interpreter.input_report.down)),
                     "left_pressed" => Ok(Object::Boolean(interpreter.input_report.left)),
@@ -111,21 +683,95 @@ impl Object {
                     "x_pressed" => Ok(Object::Boolean(interpreter.input_report.x)),
                     "z_pressed" => Ok(Object::Boolean(interpreter.input_report.z)),
 
-                    _ => Err(RuntimeError::new(format!("`Input` has no function named `{}`", name))),
+                    // Directions are -1, 0 or 1, and are 0 if opposing keys are both held
+                    "direction_x" => {
+                        let input = &interpreter.input_report;
+                        Ok(Object::Number(input.right as i32 as f64 - input.left as i32 as f64))
+                    },
+                    "direction_y" => {
+                        let input = &interpreter.input_report;
+                        Ok(Object::Number(input.down as i32 as f64 - input.up as i32 as f64))
+                    },
+                    "any_direction_pressed" => {
+                        let input = &interpreter.input_report;
+                        Ok(Object::Boolean(input.up || input.down || input.left || input.right))
+                    },
+
+                    "mouse_x" => Ok(Object::Number(interpreter.input_report.mouse_x)),
+                    "mouse_y" => Ok(Object::Number(interpreter.input_report.mouse_y)),
+                    "mouse_pressed" => Ok(Object::Boolean(interpreter.input_report.mouse_left)),
+                    "mouse_right_pressed" => Ok(Object::Boolean(interpreter.input_report.mouse_right)),
+
+                    "any_pressed" => {
+                        let input = &interpreter.input_report;
+                        Ok(Object::Boolean(InputReport::KEYS.iter().any(|key| input.key(key) == Some(true))))
+                    },
+                    "any_just_pressed" =>
+                        Ok(Object::Boolean(InputReport::KEYS.iter().any(|key| interpreter.key_just_pressed(key)))),
+
+                    // `*_just_pressed()` is only true on the first tick that a key is held
+                    _ if let Some(key) = name.strip_suffix("_just_pressed") && InputReport::KEYS.contains(&key) =>
+                        Ok(Object::Boolean(interpreter.key_just_pressed(key))),
+
+                    // `*_released()` is only true on the first tick that a key is no longer held
+                    _ if let Some(key) = name.strip_suffix("_released") && InputReport::KEYS.contains(&key) =>
+                        Ok(Object::Boolean(interpreter.key_just_released(key))),
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Input` has no function named `{}`", name))),
                 }
             }
 
             Object::DisplaySingleton => {
-                // All `Display` functions take no parameters
-                if arguments.len() != 0 {
-                    Self::incorrect_arity(name, 0, arguments.len())?;
-                }
-
+                let (width, height) = (interpreter.display_config.width as f64, interpreter.display_config.height as f64);
                 match name {
-                    "width" => Ok(Object::Number(interpreter.display_config.width as f64)),
-                    "height" => Ok(Object::Number(interpreter.display_config.height as f64)),
+                    "width" | "height" | "center_x" | "center_y" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(match name {
+                            "width" => width,
+                            "height" => height,
+                            "center_x" => width / 2.0,
+                            _ => height / 2.0,
+                        }))
+                    },
+
+                    // `wrap_x(x)` and `wrap_y(y)` wrap a position around to the other side of the
+                    // display if it's gone off one edge
+                    "wrap_x" | "wrap_y" => {
+                        let [value] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(value) = value else {
+                            return Err(RuntimeError::type_mismatch("number", value, format!("argument to `Display.{}` must be a number", name)));
+                        };
+                        Ok(Object::Number(Self::wrap(*value, if name == "wrap_x" { width } else { height })?))
+                    },
+
+                    // `in_bounds(x, y)` checks whether a point is on the display
+                    "in_bounds" => {
+                        let [x, y] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let (Object::Number(x), Object::Number(y)) = (x, y) else {
+                            return Err(expected_numbers(&[x, y], "arguments to `Display.in_bounds` must be numbers"));
+                        };
+                        Ok(Object::Boolean((0.0..width).contains(x) && (0.0..height).contains(y)))
+                    },
+
+                    // `in_bounds_rect(x, y, w, h)` checks whether a rectangle is entirely on the
+                    // display
+                    "in_bounds_rect" => {
+                        let [x, y, w, h] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 4, arguments.len())?;
+                        };
+                        let (Object::Number(x), Object::Number(y), Object::Number(w), Object::Number(h)) = (x, y, w, h) else {
+                            return Err(expected_numbers(&[x, y, w, h], "arguments to `Display.in_bounds_rect` must be numbers"));
+                        };
+                        Ok(Object::Boolean(*x >= 0.0 && *y >= 0.0 && x + w <= width && y + h <= height))
+                    },
 
-                    _ => Err(RuntimeError::new(format!("`Display` has no function named `{}`", name))),
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Display` has no function named `{}`", name))),
                 }
             }
 
@@ -134,74 +780,976 @@ impl Object {
                     // `random_int(start, end)` returns a random integer between `start` and `end`
                     // (inclusive on both sides)
                     "random_int" => {
-                        let [start, end] = arguments.as_slice() else {
-                            Self::incorrect_arity(name, 2, arguments.len())?;
-                        };
-                        let (Object::Number(start), Object::Number(end)) = (start, end) else {
-                            return Err(RuntimeError::new("arguments to `Math.random_int` must be numbers"));
-                        };
+                        let [start, end] = Self::math_arguments(name, &arguments)?;
+                        let (start, end) = (start.round() as i64, end.round() as i64);
+                        if start > end {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("start {} of `Math.random_int` is greater than end {}", start, end)));
+                        }
 
-                        let value = rand::random_range((start.round() as i64)..=(end.round() as i64)) as f64;
+                        let value = interpreter.rng.random_range(start..=end) as f64;
                         Ok(Object::Number(value))
                     },
 
-                    "round" => {
-                        let [value] = arguments.as_slice() else {
-                            Self::incorrect_arity(name, 1, arguments.len())?;
-                        };
-                        let Object::Number(value) = value else {
-                            return Err(RuntimeError::new("arguments to `Math.round` must be a number"));
-                        };
+                    // `random()` returns a random number which is at least 0, but less than 1
+                    "random" => {
+                        let [] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(interpreter.rng.random()))
+                    },
+
+                    // `random_range(start, end)` returns a random number which is at least `start`,
+                    // but less than `end`
+                    "random_range" => {
+                        let [start, end] = Self::math_arguments(name, &arguments)?;
+                        if !(end - start).is_finite() {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("`Math.random_range` needs a finite range, not {} to {}", start, end)));
+                        }
+                        if start > end {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("start {} of `Math.random_range` is greater than end {}", start, end)));
+                        }
+                        if start == end {
+                            return Ok(Object::Number(start));
+                        }
+
+                        Ok(Object::Number(interpreter.rng.random_range(start..end)))
+                    },
 
+                    // `seed(n)` makes all future random numbers deterministic, always producing the
+                    // same sequence for the same seed
+                    "seed" => {
+                        let [seed] = Self::math_arguments(name, &arguments)?;
+                        interpreter.rng = StdRng::seed_from_u64(seed.round() as i64 as u64);
+                        Ok(Object::Null)
+                    },
+
+                    "round" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
                         Ok(Object::Number(value.round()))
                     },
+                    "floor" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(value.floor()))
+                    },
+                    "ceil" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(value.ceil()))
+                    },
 
-                    _ => Err(RuntimeError::new(format!("`Math` has no function named `{}`", name))),
-                }
-            }
+                    // `trunc(x)` drops the fractional part, rounding towards zero
+                    "trunc" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(value.trunc()))
+                    },
 
-            _ => Err(RuntimeError::new(format!("cannot call function `{name}` on an object that doesn't have functions"))),
-        }
-    }
+                    // `sign(x)` is -1, 0 or 1. Unlike `f64::signum`, zero has a sign of 0
+                    "sign" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
+                        let sign = if value == 0.0 { 0.0 } else { value.signum() };
+                        Ok(Object::Number(sign))
+                    },
 
-    fn incorrect_arity(name: &str, expected: usize, actual: usize) -> Result<!, RuntimeError> {
-        Err(RuntimeError::new(format!("function declaration for `{}` has {} parameters, but {} arguments were provided", name, expected, actual)))
-    }
+                    // `min` and `max` take any number of arguments, as long as there are at least two
+                    "min" => {
+                        let numbers = Self::math_variadic_arguments(name, 2, &arguments)?;
+                        Ok(Object::Number(numbers.into_iter().fold(f64::INFINITY, f64::min)))
+                    },
+                    "max" => {
+                        let numbers = Self::math_variadic_arguments(name, 2, &arguments)?;
+                        Ok(Object::Number(numbers.into_iter().fold(f64::NEG_INFINITY, f64::max)))
+                    },
 
-    pub fn describe(&self, interpreter: &Interpreter) -> String {
-        match self {
-            Object::Null => "null".to_owned(),
-            Object::Number(n) => n.to_string(),
-            Object::Boolean(b) => b.to_string(),
-            Object::Entity(entity_id) => {
-                if let Some(entity) = interpreter.entities.get(&entity_id) {
-                    let ivars = entity.ivars.iter()
-                        .map(|(k, v)| format!("{}={}", k, v.describe(interpreter)))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    format!("Entity {} ({})", entity.kind.name, ivars)
-                } else {
-                    "destroyed entity".to_owned()
-                }
-            },
-            Object::EntityKind(kind) => {
-                format!("Entity Declaration {}", kind.name)
-            },
-            Object::Sprite(sprite) =>
-                format!("sprite ({}x{})", sprite.width, sprite.height),
+                    "clamp" => {
+                        let [value, low, high] = Self::math_arguments(name, &arguments)?;
+                        if low.is_nan() || high.is_nan() {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("bounds {} and {} of `Math.clamp` must be numbers", low, high)));
+                        }
+                        if low > high {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("lower bound {} of `Math.clamp` is greater than upper bound {}", low, high)));
+                        }
+                        Ok(Object::Number(value.clamp(low, high)))
+                    },
+
+                    // `lerp(a, b, t)` is the value `t` of the way from `a` to `b` - `t` isn't limited
+                    // to between 0 and 1
+                    "lerp" => {
+                        let [a, b, t] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(a + (b - a) * t))
+                    },
+
+                    // `wrap(value, max)` wraps `value` into the range 0 (inclusive) to `max`
+                    // (exclusive), so `wrap(-1, 10)` is 9
+                    "wrap" => {
+                        let [value, max] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(Self::wrap(value, max)?))
+                    },
+
+                    "pow" => {
+                        let [base, exponent] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(power(base, exponent)?))
+                    },
+
+                    "sqrt" => {
+                        let [value] = Self::math_arguments(name, &arguments)?;
+                        if value < 0.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("cannot take square root of negative number {}", value)));
+                        }
+                        Ok(Object::Number(value.sqrt()))
+                    },
+
+                    // `distance(x1, y1, x2, y2)` is the straight-line distance between two points
+                    "distance" => {
+                        let [x1, y1, x2, y2] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number((x2 - x1).hypot(y2 - y1)))
+                    },
+
+                    // Trigonometry works in radians
+                    "sin" => {
+                        let [x] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(x.sin()))
+                    },
+                    "cos" => {
+                        let [x] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(x.cos()))
+                    },
+                    "tan" => {
+                        let [x] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(x.tan()))
+                    },
+
+                    // `atan2(y, x)` is the angle from the positive X axis to the point (x, y), between
+                    // -pi and pi
+                    "atan2" => {
+                        let [y, x] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(y.atan2(x)))
+                    },
+
+                    "pi" => {
+                        let [] = Self::math_arguments(name, &arguments)?;
+                        Ok(Object::Number(std::f64::consts::PI))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Math` has no function named `{}`", name))),
+                }
+            }
+
+            Object::GlobalSingleton => {
+                match name {
+                    // `get(name)` reads a global, or returns `null` if it has never been set
+                    "get" => {
+                        let [key] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::String(key) = key else {
+                            return Err(RuntimeError::type_mismatch("string", key, "argument to `Global.get` must be a string"));
+                        };
+
+                        Ok(interpreter.globals.get(key).cloned().unwrap_or(Object::Null))
+                    },
+
+                    "set" => {
+                        let [key, value] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let Object::String(key) = key else {
+                            return Err(RuntimeError::type_mismatch("string", key, "first argument to `Global.set` must be a string"));
+                        };
+
+                        interpreter.globals.insert(key.clone(), value.clone());
+                        Ok(Object::Null)
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Global` has no function named `{}`", name))),
+                }
+            }
+
+            Object::ColorSingleton => {
+                let palette_size = interpreter.display_config.palette_size;
+                match name {
+                    "count" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(palette_size as f64))
+                    },
+
+                    // `index(n)` checks that `n` is in the palette, for using colours by number
+                    "index" => {
+                        let [index] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        match index {
+                            Object::Number(n) if n.fract() == 0.0 && (0.0..palette_size as f64).contains(n) => Ok(index.clone()),
+                            _ => {
+                                let message = format!("colour index must be a number from 0 to {}, not {}", palette_size.saturating_sub(1), index.describe(interpreter));
+                                match index {
+                                    Object::Number(_) => Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, message)),
+                                    _ => Err(RuntimeError::type_mismatch("number", index, message)),
+                                }
+                            }
+                        }
+                    },
+
+                    // Named colours, like `red()`, return their index in the palette
+                    _ if let Some(index) = PALETTE_NAMES.iter().position(|n| *n == name) => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        if index >= palette_size {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("colour `{}` isn't available, because the palette only has {} colours", name, palette_size)));
+                        }
+                        Ok(Object::Number(index as f64))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Color` has no function named `{}`", name))),
+                }
+            }
+
+            Object::DebugSingleton => {
+                match name {
+                    "assert" => {
+                        let [condition, message] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let Object::Boolean(condition) = condition else {
+                            return Err(RuntimeError::type_mismatch("boolean", condition, "first argument to `Debug.assert` must be a boolean"));
+                        };
+
+                        if !condition {
+                            return Err(RuntimeError::new(RuntimeErrorKind::AssertionFailed, format!("assertion failed: {}", message.describe(interpreter))));
+                        }
+                        Ok(Object::Null)
+                    },
+
+                    "warn" => {
+                        let [message] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let message = DebugMessage::Warning(message.describe(interpreter));
+                        interpreter.debug_messages.push(message);
+                        Ok(Object::Null)
+                    },
+
+                    // `log` works like `echo`, except that the host decides whether to display it
+                    "log" => {
+                        let message = arguments.iter().map(|a| a.describe(interpreter)).collect::<Vec<_>>().join(" ");
+                        interpreter.debug_messages.push(DebugMessage::Log(message));
+
+                        if let [item] = arguments.as_slice() {
+                            Ok(item.clone())
+                        } else {
+                            Ok(Object::Null)
+                        }
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Debug` has no function named `{}`", name))),
+                }
+            }
+
+            Object::GameSingleton => {
+                match name {
+                    // The game can't be reset from inside one of its own handlers, so this only
+                    // asks the host to do it once the current tick is over
+                    "restart" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        interpreter.restart_requested = true;
+                        Ok(Object::Null)
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Game` has no function named `{}`", name))),
+                }
+            }
+
+            Object::CameraSingleton => {
+                match name {
+                    "x" | "y" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(if name == "x" { interpreter.camera.0 } else { interpreter.camera.1 }))
+                    },
+
+                    "set" => {
+                        let [x, y] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let (Object::Number(x), Object::Number(y)) = (x, y) else {
+                            return Err(expected_numbers(&[x, y], "arguments to `Camera.set` must be numbers"));
+                        };
+                        interpreter.camera = (*x, *y);
+                        Ok(Object::Null)
+                    },
+
+                    // `ignore()` makes the entity being drawn stay in the same place on the
+                    // display wherever the camera is, which is useful for HUDs
+                    "ignore" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        let Some(ignores_camera) = interpreter.draw_ignores_camera.as_mut() else {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "`Camera.ignore` can only be called from `draw`"));
+                        };
+                        *ignores_camera = true;
+                        Ok(Object::Null)
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Camera` has no function named `{}`", name))),
+                }
+            }
+
+            Object::StorageSingleton => {
+                match name {
+                    // `get(name)` reads a stored value, or returns `null` if it has never been set
+                    "get" => {
+                        let [key] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::String(key) = key else {
+                            return Err(RuntimeError::type_mismatch("string", key, "argument to `Storage.get` must be a string"));
+                        };
+
+                        Ok(interpreter.storage.get(key).cloned().unwrap_or(Object::Null))
+                    },
+
+                    "set" => {
+                        let [key, value] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let Object::String(key) = key else {
+                            return Err(RuntimeError::type_mismatch("string", key, "first argument to `Storage.set` must be a string"));
+                        };
+
+                        // Only store simple values, so that the host can easily persist them
+                        let (Object::Number(_) | Object::Boolean(_) | Object::String(_)) = value else {
+                            return Err(RuntimeError::type_mismatch("number, boolean, or string", value, format!("`Storage` can only store numbers, booleans and strings, not {}", value.describe(interpreter))));
+                        };
+
+                        interpreter.storage.insert(key.clone(), value.clone());
+                        interpreter.storage_writes.insert(key.clone());
+                        Ok(Object::Null)
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Storage` has no function named `{}`", name))),
+                }
+            }
+
+            Object::SpriteSingleton => {
+                match name {
+                    // `blank(width, height)` creates a sprite where every pixel is transparent
+                    "blank" => {
+                        let [width, height] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 2, arguments.len())?;
+                        };
+                        let (Object::Number(width), Object::Number(height)) = (width, height) else {
+                            return Err(expected_numbers(&[width, height], "arguments to `Sprite.blank` must be numbers"));
+                        };
+                        let (width, height) = (width.round(), height.round());
+                        if width < 1.0 || height < 1.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("sprite must be at least 1x1, not {}x{}", width, height)));
+                        }
+
+                        Ok(Object::Sprite(Sprite::from_fn(width as usize, height as usize, |_, _| Pixel::Clear)))
+                    },
+
+                    // `from_rows(rows)` creates a sprite from an array of rows, each of which is an
+                    // array of pixel values accepted by `set_pixel`
+                    "from_rows" => {
+                        let [rows] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Array(rows) = rows else {
+                            return Err(RuntimeError::type_mismatch("array", rows, "argument to `Sprite.from_rows` must be an array of arrays"));
+                        };
+
+                        let mut pixel_rows = vec![];
+                        for row in rows.borrow().iter() {
+                            let Object::Array(row) = row else {
+                                return Err(RuntimeError::type_mismatch("array", row, format!("rows of `Sprite.from_rows` must be arrays, not {}", row.describe(interpreter))));
+                            };
+                            pixel_rows.push(row.borrow().iter().map(|value| Self::pixel_value(interpreter, value)).collect::<InterpreterResult<Vec<_>>>()?);
+                        }
+
+                        let width = pixel_rows.first().map(|row| row.len()).unwrap_or(0);
+                        if width == 0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, "sprite must be at least 1x1, so `Sprite.from_rows` needs at least one non-empty row"));
+                        }
+                        if let Some((index, row)) = pixel_rows.iter().enumerate().find(|(_, row)| row.len() != width) {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!(
+                                "rows of `Sprite.from_rows` must all be the same length, but row 0 has {} pixels and row {} has {}",
+                                width, index, row.len(),
+                            )));
+                        }
+
+                        Ok(Object::Sprite(Sprite::from_fn(width, pixel_rows.len(), |x, y| pixel_rows[y][x])))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Sprite` has no function named `{}`", name))),
+                }
+            }
+
+            Object::TimeSingleton => {
+                let ticks = interpreter.tick_count;
+                match name {
+                    // `ticks()` is 1 during the first tick, 2 during the second, and so on
+                    "ticks" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(ticks as f64))
+                    },
+
+                    "seconds" => {
+                        let [] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 0, arguments.len())?;
+                        };
+                        Ok(Object::Number(ticks as f64 / interpreter.time_config.ticks_per_second))
+                    },
+
+                    // `every(n)` is true on every `n`th tick
+                    "every" => {
+                        let [n] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(n) = n else {
+                            return Err(RuntimeError::type_mismatch("number", n, "argument to `Time.every` must be a number"));
+                        };
+                        if n.fract() != 0.0 || *n < 1.0 {
+                            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("argument to `Time.every` must be a positive integer, not {}", n)));
+                        }
+                        Ok(Object::Boolean(ticks > 0 && ticks.is_multiple_of(*n as u64)))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Time` has no function named `{}`", name))),
+                }
+            }
+
+            Object::TextSingleton => {
+                match name {
+                    "sprite" => {
+                        let [value] = arguments.as_slice() else {
+                            Self::incorrect_arity(name, 1, arguments.len())?;
+                        };
+                        let Object::Number(_) = value else {
+                            return Err(RuntimeError::type_mismatch("number", value, format!("`Text.sprite` can only render numbers, not {}", value.describe(interpreter))));
+                        };
+                        render_text(&value.describe(interpreter))
+                            .map(Object::Sprite)
+                            .map_err(|c| RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("`Text.sprite` has no glyph for `{}`", c)))
+                    },
+
+                    _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Text` has no function named `{}`", name))),
+                }
+            }
+
+            Object::HostSingleton => {
+                let Some(function) = interpreter.host_functions.get(name).cloned() else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("`Host` has no function named `{}`", name)));
+                };
+
+                function(interpreter, arguments)
+            }
+
+            _ => Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("cannot call function `{name}` on an object that doesn't have functions"))),
+        }
+    }
+
+    /// If `name` is one of the type predicate functions, like `is_number`, gets whether this
+    /// object is of that type.
+    fn type_predicate(&self, name: &str) -> Option<bool> {
+        Some(match name {
+            "is_null" => matches!(self, Object::Null),
+            "is_number" => matches!(self, Object::Number(_)),
+            "is_boolean" => matches!(self, Object::Boolean(_)),
+            "is_string" => matches!(self, Object::String(_)),
+            "is_entity" => matches!(self, Object::Entity(_)),
+            "is_array" => matches!(self, Object::Array(_)),
+            "is_sprite" => matches!(self, Object::Sprite(_)),
+            "is_sound" => matches!(self, Object::Sound(_)),
+            "is_melody" => matches!(self, Object::Melody(_)),
+            _ => return None,
+        })
+    }
+
+    /// Calls a function which is built into every entity, or returns `None` if there isn't one called
+    /// `name`.
+    /// 
+    /// Functions and instance variables declared on the entity take precedence over these, so that
+    /// adding a new built-in never changes the behaviour of an existing game.
+    fn call_builtin_entity_function(interpreter: &mut Interpreter, entity_id: EntityId, kind: &Rc<EntityKind>, name: &str, arguments: &[Object]) -> InterpreterResult<Option<Object>> {
+        match name {
+            "kind" => {
+                let [] = arguments else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                Ok(Some(Object::EntityKind(kind.clone())))
+            },
+
+            // `send(name, arguments)` queues a message for this entity's `on_message` handler.
+            // `arguments` is optional, and defaults to an empty array
+            "send" => {
+                let (message, message_arguments) = Self::message_arguments(name, arguments)?;
+                Self::check_receives_messages(kind, &message)?;
+                interpreter.pending_messages.push((entity_id, message, message_arguments));
+                Ok(Some(Object::Null))
+            },
+
+            // `sleep()` stops the entity's `tick`, `on_press` and `on_release` handlers from running
+            // until `wake()` is called. It's still drawn, and can still collide and get messages
+            "sleep" | "wake" => {
+                let [] = arguments else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                interpreter.entities.get_mut(&entity_id).unwrap().asleep = name == "sleep";
+                Ok(Some(Object::Null))
+            },
+
+            "is_asleep" => {
+                let [] = arguments else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                Ok(Some(Object::Boolean(interpreter.entities[&entity_id].asleep)))
+            },
+
+            // `alive()` checks whether the entity still exists. Entities which have been destroyed
+            // are handled before getting here, so this one must
+            "alive" => {
+                let [] = arguments else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                Ok(Some(Object::Boolean(true)))
+            },
+
+            // `is_a(Kind)` checks whether this entity is an instance of `Kind`
+            "is_a" => {
+                let [other_kind] = arguments else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                let Object::EntityKind(other_kind) = other_kind else {
+                    return Err(RuntimeError::type_mismatch("entity declaration", other_kind, format!("argument to `is_a` must be an entity declaration, not {}", other_kind.describe(interpreter))));
+                };
+                Ok(Some(Object::Boolean(kind.name == other_kind.name)))
+            },
+
+            // `get(name)` and `set(name, value)` access an instance variable whose name is only
+            // known at runtime
+            "get" => {
+                let [ivar] = arguments else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                let Object::String(ivar) = ivar else {
+                    return Err(RuntimeError::type_mismatch("string", ivar, "argument to `get` must be a string"));
+                };
+                Ok(Some(interpreter.instance_var_value(entity_id, ivar)?.read()?))
+            },
+
+            "set" => {
+                let [ivar, value] = arguments else {
+                    Self::incorrect_arity(name, 2, arguments.len())?;
+                };
+                let Object::String(ivar) = ivar else {
+                    return Err(RuntimeError::type_mismatch("string", ivar, "first argument to `set` must be a string"));
+                };
+                interpreter.instance_var_value(entity_id, ivar)?.write(value.clone())?;
+                Ok(Some(Object::Null))
+            },
+
+            // `distance_to(other)` is the straight-line distance between the two entities' `@x` and
+            // `@y`
+            "distance_to" => {
+                let [other] = arguments else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                let Object::Entity(other_id) = other else {
+                    return Err(RuntimeError::type_mismatch("entity", other, format!("argument to `distance_to` must be an entity, not {}", other.describe(interpreter))));
+                };
+
+                let (x, y) = Self::entity_position(interpreter, entity_id, name)?;
+                let (other_x, other_y) = Self::entity_position(interpreter, *other_id, name)?;
+                Ok(Some(Object::Number((other_x - x).hypot(other_y - y))))
+            },
+
+            "distance_to_point" => {
+                let [other_x, other_y] = arguments else {
+                    Self::incorrect_arity(name, 2, arguments.len())?;
+                };
+                let (Object::Number(other_x), Object::Number(other_y)) = (other_x, other_y) else {
+                    return Err(expected_numbers(&[other_x, other_y], "arguments to `distance_to_point` must be numbers"));
+                };
+
+                let (x, y) = Self::entity_position(interpreter, entity_id, name)?;
+                Ok(Some(Object::Number((other_x - x).hypot(other_y - y))))
+            },
+
+            // `overlaps(other)` checks whether the two entities are touching, in the same way as
+            // `on_collision`
+            "overlaps" => {
+                let [other] = arguments else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                let Object::Entity(other_id) = other else {
+                    return Err(RuntimeError::type_mismatch("entity", other, format!("argument to `overlaps` must be an entity, not {}", other.describe(interpreter))));
+                };
+                let Some(other) = interpreter.entities.get(other_id) else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::EntityNotFound, "cannot use `overlaps` with an entity which no longer exists"));
+                };
+
+                let this = &interpreter.entities[&entity_id];
+                for entity in [this, other] {
+                    if entity.collision_box().is_none() {
+                        return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!(
+                            "`overlaps` needs entity `{}` to have numeric instance variables `x` and `y`, and either numeric `width` and `height` or a sprite which it has drawn",
+                            entity.kind.name,
+                        )));
+                    }
+                }
+                Ok(this.overlaps(other).map(Object::Boolean))
+            },
+
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets an entity's position for a built-in function `name` which needs it, or explains which
+    /// entity is missing one.
+    fn entity_position(interpreter: &Interpreter, entity_id: EntityId, name: &str) -> InterpreterResult<(f64, f64)> {
+        let Some(entity) = interpreter.entities.get(&entity_id) else {
+            return Err(RuntimeError::new(RuntimeErrorKind::EntityNotFound, format!("cannot use `{}` with an entity which no longer exists", name)));
+        };
+        entity.position().ok_or_else(|| RuntimeError::new(RuntimeErrorKind::InvalidValue, format!(
+            "`{}` needs entity `{}` to have numeric instance variables `x` and `y`", name, entity.kind.name
+        )))
+    }
+
+    /// Converts objects into the position of a pixel within `sprite`.
+    fn pixel_position(sprite: &Sprite, x: &Object, y: &Object) -> InterpreterResult<(usize, usize)> {
+        let (Object::Number(x), Object::Number(y)) = (x, y) else {
+            return Err(expected_numbers(&[x, y], "pixel coordinates must be numbers"));
+        };
+        if x.fract() != 0.0 || y.fract() != 0.0 || *x < 0.0 || *y < 0.0 || *x >= sprite.width as f64 || *y >= sprite.height as f64 {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("pixel ({}, {}) is outside of {}x{} sprite", x, y, sprite.width, sprite.height)));
+        }
+        Ok((*x as usize, *y as usize))
+    }
+
+    /// Array functions which change the array they're called on, which is seen by everything else
+    /// sharing the array.
+    const ARRAY_MUTATING_FUNCTIONS: &[&str] = &["push", "pop", "insert", "remove_at", "remove_value"];
+
+    /// Calls one of the [`Self::ARRAY_MUTATING_FUNCTIONS`] on an array.
+    fn call_mutating_array_function(array: &Rc<RefCell<Vec<Object>>>, name: &str, arguments: Vec<Object>) -> InterpreterResult<Object> {
+        // `remove_value(item)` removes the first item equal to `item`, and returns whether there
+        // was one. This finds the item before borrowing the array mutably, since comparing items
+        // may need to look inside this array too
+        if name == "remove_value" {
+            let [item] = arguments.as_slice() else {
+                Self::incorrect_arity(name, 1, arguments.len())?;
+            };
+            let index = array.borrow().iter().position(|i| i == item);
+            let Some(index) = index else {
+                return Ok(Object::Boolean(false));
+            };
+            array.borrow_mut().remove(index);
+            return Ok(Object::Boolean(true));
+        }
+
+        let mut items = array.borrow_mut();
+        match name {
+            "push" => {
+                let [item] = arguments.as_slice() else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                items.push(item.clone());
+                Ok(Object::Null)
+            },
+
+            // `pop()` removes and returns the last item, or returns `null` if the array is empty
+            "pop" => {
+                let [] = arguments.as_slice() else {
+                    Self::incorrect_arity(name, 0, arguments.len())?;
+                };
+                Ok(items.pop().unwrap_or(Object::Null))
+            },
+
+            // `insert(index, item)` moves the items from `index` onwards along by one, and puts `item`
+            // at `index`. Inserting at the length of the array is the same as `push`
+            "insert" => {
+                let [index, item] = arguments.as_slice() else {
+                    Self::incorrect_arity(name, 2, arguments.len())?;
+                };
+                let index = Self::array_index(index, &items, true)?;
+                items.insert(index, item.clone());
+                Ok(Object::Null)
+            },
+
+            // `remove_at(index)` removes and returns the item at `index`
+            "remove_at" => {
+                let [index] = arguments.as_slice() else {
+                    Self::incorrect_arity(name, 1, arguments.len())?;
+                };
+                let index = Self::array_index(index, &items, false)?;
+                Ok(items.remove(index))
+            },
+
+            _ => unreachable!("`{name}` is not a mutating array function"),
+        }
+    }
+
+    /// Converts an object into an index of `items`. If `allow_end` is set, the index may also be
+    /// one past the last item.
+    fn array_index(index: &Object, items: &[Object], allow_end: bool) -> InterpreterResult<usize> {
+        let Object::Number(index) = *index else {
+            return Err(RuntimeError::type_mismatch("number", index, "array index must be a number"));
+        };
+
+        let limit = if allow_end { items.len() + 1 } else { items.len() };
+        if index.fract() != 0.0 || index < 0.0 || index >= limit as f64 {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("array index {} is out of bounds for array of length {}", index, items.len())));
+        }
+        Ok(index as usize)
+    }
+
+    /// Checks that every item of an array is a number, for an array function `name` which only
+    /// works on numbers.
+    fn array_numbers(name: &str, items: &[Object], interpreter: &Interpreter) -> InterpreterResult<Vec<f64>> {
+        items.iter()
+            .map(|item| match item {
+                Object::Number(n) => Ok(*n),
+                _ => Err(RuntimeError::type_mismatch("number", item, format!("`{}` only works on arrays of numbers, but array contains {}", name, item.describe(interpreter)))),
+            })
+            .collect()
+    }
+
+    /// Checks the arguments of `send` or `send_all`, returning the message's name and its array of
+    /// arguments.
+    fn message_arguments(name: &str, arguments: &[Object]) -> InterpreterResult<(String, Object)> {
+        let (message, message_arguments) = match arguments {
+            [message] => (message, Object::array(vec![])),
+            [message, message_arguments] => (message, message_arguments.clone()),
+            _ => Self::incorrect_arity(name, 2, arguments.len())?,
+        };
+
+        let Object::String(message) = message else {
+            return Err(RuntimeError::type_mismatch("string", message, format!("message name given to `{}` must be a string", name)));
+        };
+        if !matches!(message_arguments, Object::Array(_)) {
+            return Err(RuntimeError::type_mismatch("array", &message_arguments, format!("message arguments given to `{}` must be an array", name)));
+        }
+        Ok((message.clone(), message_arguments))
+    }
+
+    /// Errors if an entity kind has no `on_message` handler, since a message sent to it could never
+    /// be received.
+    fn check_receives_messages(kind: &EntityKind, message: &str) -> InterpreterResult {
+        if kind.message_handler.is_none() {
+            return Err(RuntimeError::new(RuntimeErrorKind::NoSuchFunction, format!("entity declaration `{}` has no `on_message` handler to receive message `{}`", kind.name, message)));
+        }
+        Ok(())
+    }
+
+    fn incorrect_arity(name: &str, expected: usize, actual: usize) -> Result<!, RuntimeError> {
+        Err(RuntimeError::new(
+            RuntimeErrorKind::ArityMismatch { expected, found: actual },
+            format!("function declaration for `{}` has {} parameters, but {} arguments were provided", name, expected, actual),
+        ))
+    }
+
+    /// Checks that a `Math` function was given exactly `N` arguments, all of which are numbers.
+    fn math_arguments<const N: usize>(name: &str, arguments: &[Object]) -> InterpreterResult<[f64; N]> {
+        if arguments.len() != N {
+            Self::incorrect_arity(name, N, arguments.len())?;
+        }
+
+        let mut numbers = [0.0; N];
+        for (number, argument) in numbers.iter_mut().zip(Self::math_variadic_arguments(name, 0, arguments)?) {
+            *number = argument;
+        }
+        Ok(numbers)
+    }
+
+    /// Converts a value into a sprite pixel. It can be a colour index, `true` for black, or `null`
+    /// or `false` for transparent.
+    fn pixel_value(interpreter: &Interpreter, value: &Object) -> InterpreterResult<Pixel> {
+        let palette_size = interpreter.display_config.palette_size;
+        match value {
+            Object::Null | Object::Boolean(false) => Ok(Pixel::Clear),
+            Object::Boolean(true) => Ok(Pixel::Set(0)),
+            Object::Number(n) if n.fract() == 0.0 && (0.0..palette_size as f64).contains(n) => Ok(Pixel::Set(*n as u8)),
+            _ => {
+                let message = format!("pixel value must be a colour index from 0 to {}, a boolean, or null, not {}", palette_size.saturating_sub(1), value.describe(interpreter));
+                match value {
+                    Object::Number(_) => Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, message)),
+                    _ => Err(RuntimeError::type_mismatch("colour index, boolean, or null", value, message)),
+                }
+            }
+        }
+    }
+
+    /// Wraps `value` into the range 0 (inclusive) to `max` (exclusive), wrapping negative values
+    /// around from the top of the range.
+    fn wrap(value: f64, max: f64) -> InterpreterResult<f64> {
+        if max <= 0.0 {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("cannot wrap to a range with a non-positive maximum of {}", max)));
+        }
+        Ok(value.rem_euclid(max))
+    }
+
+    /// Checks that a `Math` function was given at least `minimum` arguments, all of which are
+    /// numbers.
+    fn math_variadic_arguments(name: &str, minimum: usize, arguments: &[Object]) -> InterpreterResult<Vec<f64>> {
+        if arguments.len() < minimum {
+            return Err(RuntimeError::new(
+                RuntimeErrorKind::ArityMismatch { expected: minimum, found: arguments.len() },
+                format!("`Math.{}` needs at least {} arguments, but {} were provided", name, minimum, arguments.len()),
+            ));
+        }
+
+        arguments.iter()
+            .map(|argument| match argument {
+                Object::Number(n) => Ok(*n),
+                _ => Err(RuntimeError::type_mismatch("number", argument, format!("arguments to `Math.{}` must be numbers", name))),
+            })
+            .collect()
+    }
+
+    /// If this is a built-in singleton, the name it's referred to by, like `Math`. This is the
+    /// inverse of [`Interpreter::singleton`].
+    pub(crate) fn singleton_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Object::InputSingleton => "Input",
+            Object::DisplaySingleton => "Display",
+            Object::MathSingleton => "Math",
+            Object::GlobalSingleton => "Global",
+            Object::HostSingleton => "Host",
+            Object::SpriteSingleton => "Sprite",
+            Object::TimeSingleton => "Time",
+            Object::TextSingleton => "Text",
+            Object::StorageSingleton => "Storage",
+            Object::CameraSingleton => "Camera",
+            Object::DebugSingleton => "Debug",
+            Object::GameSingleton => "Game",
+            Object::ColorSingleton => "Color",
+            _ => return None,
+        })
+    }
+
+    /// The name of this object's type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Null => "null",
+            Object::Number(_) => "number",
+            Object::Boolean(_) => "boolean",
+            Object::String(_) => "string",
+            Object::Entity(_) => "entity",
+            Object::EntityKind(_) => "entity declaration",
+            Object::Sprite(_) => "sprite",
+            Object::Sound(_) => "sound",
+            Object::Melody(_) => "melody",
+            Object::Array(_) => "array",
+            Object::Range { .. } => "range",
+
+            Object::InputSingleton
+                | Object::DisplaySingleton
+                | Object::MathSingleton
+                | Object::GlobalSingleton
+                | Object::HostSingleton
+                | Object::SpriteSingleton
+                | Object::TimeSingleton
+                | Object::TextSingleton
+                | Object::StorageSingleton
+                | Object::CameraSingleton
+                | Object::DebugSingleton
+                | Object::GameSingleton
+                | Object::ColorSingleton => "singleton",
+        }
+    }
+
+    pub fn describe(&self, interpreter: &Interpreter) -> String {
+        self.describe_within(interpreter, &mut vec![])
+    }
+
+    /// Describes this object, where `enclosing` holds the arrays which it is being described as
+    /// part of. An array which contains itself is described as `[...]` the second time around.
+    fn describe_within(&self, interpreter: &Interpreter, enclosing: &mut Vec<ArrayPointer>) -> String {
+        match self {
+            Object::Null => "null".to_owned(),
+            Object::Number(n) => n.to_string(),
+            Object::Boolean(b) => b.to_string(),
+            Object::String(s) => s.clone(),
+            Object::Entity(entity_id) => {
+                if let Some(entity) = interpreter.entities.get(entity_id) {
+                    // Show instance variables in the order they're declared, and cut long ones
+                    // short so that one big array doesn't fill the console
+                    let ivars = entity.kind.ivars.iter()
+                        .map(|ivar| {
+                            let mut value = entity.ivars[&ivar.name].describe(interpreter);
+                            if let Some((index, _)) = value.char_indices().nth(MAX_IVAR_DESCRIPTION_LENGTH) {
+                                value = format!("{}...", value[..index].trim_end());
+                            }
+                            format!("{}={}", ivar.name, value)
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("Entity {} #{} ({})", entity.kind.name, entity_id.0, ivars)
+                } else {
+                    format!("destroyed entity #{}", entity_id.0)
+                }
+            },
+            Object::EntityKind(kind) => {
+                format!("Entity Declaration {}", kind.name)
+            },
+            Object::Sprite(sprite) =>
+                format!("sprite ({}x{})", sprite.width, sprite.height),
             Object::Sound(tone) =>
                 format!("sound: {tone:?}"),
+            Object::Melody(tones) =>
+                format!("melody: {tones:?}"),
             Object::Array(items) => {
-                if items.is_empty() {
-                    "[ ]".to_string()
-                } else {
-                    format!("[ {} ]", items.iter().map(|i| i.describe(interpreter)).collect::<Vec<_>>().join(", "))
+                if enclosing.contains(&Rc::as_ptr(items)) {
+                    return "[...]".to_string();
                 }
+                let items_ref = items.borrow();
+                if items_ref.is_empty() {
+                    return "[ ]".to_string();
+                }
+
+                enclosing.push(Rc::as_ptr(items));
+                let description = format!("[ {} ]", items_ref.iter().map(|i| i.describe_within(interpreter, enclosing)).collect::<Vec<_>>().join(", "));
+                enclosing.pop();
+                description
             },
             
-            Object::InputSingleton => "Input".to_owned(),
+            Object::Range { start, end, step: 1 } => format!("{start} to {end}"),
+            Object::Range { start, end, step } => format!("{start} to {end} by {step}"),
+            
+            Object::InputSingleton => {
+                let input = &interpreter.input_report;
+                let held = InputReport::KEYS.iter()
+                    .copied()
+                    .filter(|key| input.key(key) == Some(true))
+                    .chain(input.mouse_left.then_some("mouse_left"))
+                    .chain(input.mouse_right.then_some("mouse_right"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Input (held: [{}], mouse at {}, {})", held, input.mouse_x, input.mouse_y)
+            },
             Object::DisplaySingleton => "Display".to_owned(),
             Object::MathSingleton => "Math".to_owned(),
+            Object::GlobalSingleton => {
+                let globals = interpreter.globals.iter()
+                    .map(|(k, v)| format!("{}={}", k, v.describe(interpreter)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Global ({})", globals)
+            },
+            Object::HostSingleton => "Host".to_owned(),
+            Object::SpriteSingleton => "Sprite".to_owned(),
+            Object::TimeSingleton => "Time".to_owned(),
+            Object::TextSingleton => "Text".to_owned(),
+            Object::StorageSingleton => "Storage".to_owned(),
+            Object::CameraSingleton => "Camera".to_owned(),
+            Object::DebugSingleton => "Debug".to_owned(),
+            Object::GameSingleton => "Game".to_owned(),
+            Object::ColorSingleton => "Color".to_owned(),
         }
     }
 }
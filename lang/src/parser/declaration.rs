@@ -1,44 +1,113 @@
-use nom::{IResult, Parser, branch::alt, bytes::complete::tag, character::complete::char, combinator::map, multi::{many0, separated_list0, separated_list1}};
+use nom::{Parser, branch::alt, bytes::complete::tag, character::complete::char, combinator::{cut, map, opt}, multi::many0};
 
-use crate::{Declaration, Statement, parser::{declaration_body, identifier, instance_var_identifier, statement::statement, statement_body, ws0, ws1}};
+use crate::{Declaration, Statement, UseFilter, parser::{IResult, comma_list0, comma_list1, declaration_body, expression::{expression, number}, identifier, instance_var_identifier, keyword, statement::statement, statement_body, ws0, ws1}};
+
+// Each declaration begins with a keyword. Once that has been matched, `cut` commits to that kind of
+// declaration, so that any error is reported from where it really occurred, rather than as a
+// generic failure at the start of the declaration.
 
 fn instance_var_declaration(input: &str) -> IResult<&str, Declaration> {
     map(
         (
-            tag("var"),
-            ws1,
-            separated_list1((ws0, tag(","), ws0), instance_var_identifier),
-            tag(";"),
+            keyword("var"),
+            cut((
+                ws1,
+                comma_list1((
+                    instance_var_identifier,
+                    opt(map((ws0, char('='), ws0, expression), |(_, _, _, e)| e)),
+                )),
+                ws0,
+                tag(";"),
+            )),
         ),
-        |(_, _, names, _)| Declaration::InstanceVarDeclaration { names },
+        |(_, (_, ivars, _, _))| Declaration::InstanceVarDeclaration { ivars },
     ).parse(input)
 }
 
 fn function_declaration(input: &str) -> IResult<&str, Declaration> {
     map(
         (
-            tag("func"),
-            ws1,
-            identifier,
-            ws0,
-            char('('),
-            separated_list0((ws0, char(','), ws0), identifier),
-            char(')'),
-            ws0,
-            statement_body,
+            keyword("func"),
+            cut((
+                ws1,
+                identifier,
+                ws0,
+                char('('),
+                ws0,
+                comma_list0(identifier),
+                ws0,
+                char(')'),
+                ws0,
+                statement_body,
+            )),
         ),
-        |(_, _, name, _, _, parameters, _, _, body)| Declaration::FunctionDeclaration { name, parameters, body }
+        |(_, (_, name, _, _, _, parameters, _, _, _, body))| Declaration::FunctionDeclaration { name, parameters, body }
+    ).parse(input)
+}
+
+fn const_declaration(input: &str) -> IResult<&str, Declaration> {
+    map(
+        (
+            keyword("const"),
+            cut((
+                ws1,
+                identifier,
+                ws0,
+                char('='),
+                ws0,
+                expression,
+                ws0,
+                char(';'),
+            )),
+        ),
+        |(_, (_, name, _, _, _, value, _, _))| Declaration::ConstDeclaration { name, value },
+    ).parse(input)
+}
+
+fn use_declaration(input: &str) -> IResult<&str, Declaration> {
+    let filter = alt((
+        map((ws1, keyword("only"), ws1, comma_list1(identifier)), |(_, _, _, names)| UseFilter::Only(names)),
+        map((ws1, keyword("except"), ws1, comma_list1(identifier)), |(_, _, _, names)| UseFilter::Except(names)),
+    ));
+
+    map(
+        (
+            keyword("use"),
+            cut((
+                ws1,
+                identifier,
+                opt(filter),
+                ws0,
+                char(';'),
+            )),
+        ),
+        |(_, (_, name, filter, _, _))| Declaration::UseDeclaration { name, filter: filter.unwrap_or(UseFilter::All) },
     ).parse(input)
 }
 
 pub fn declaration(input: &str) -> IResult<&str, Declaration> {
     alt((
-        map((tag("entity"), ws1, identifier, ws0, declaration_body), |(_, _, name, _, body)| Declaration::EntityDeclaration { name, body }),
-        map((tag("constructor"), ws0, statement_body), |(_, _, body)| Declaration::ConstructorDeclaration { body }),
-        map((tag("tick"), ws0, statement_body), |(_, _, body)| Declaration::TickDeclaration { body }),
-        map((tag("draw"), ws0, statement_body), |(_, _, body)| Declaration::DrawDeclaration { body }),
-        map((tag("use"), ws1, identifier, ws0, char(';')), |(_, _, name, _, _)| Declaration::UseDeclaration { name }),
+        map((keyword("entity"), cut((ws1, identifier, ws0, declaration_body))), |(_, (_, name, _, body))| Declaration::EntityDeclaration { name, body }),
+        map((keyword("constructor"), cut((ws0, statement_body))), |(_, (_, body))| Declaration::ConstructorDeclaration { body }),
+        map(
+            (keyword("tick"), cut((opt(map((ws1, keyword("priority"), ws1, number), |(_, _, _, n)| n)), ws0, statement_body))),
+            |(_, (priority, _, body))| Declaration::TickDeclaration { priority, body },
+        ),
+        map((keyword("draw"), cut((ws0, statement_body))), |(_, (_, body))| Declaration::DrawDeclaration { body }),
+        map((keyword("on_destroy"), cut((ws0, statement_body))), |(_, (_, body))| Declaration::DestroyDeclaration { body }),
+        map((keyword("on_press"), cut((ws1, identifier, ws0, statement_body))), |(_, (_, key, _, body))| Declaration::PressDeclaration { key, body }),
+        map((keyword("on_release"), cut((ws1, identifier, ws0, statement_body))), |(_, (_, key, _, body))| Declaration::ReleaseDeclaration { key, body }),
+        map(
+            (keyword("on_collision"), cut((ws0, char('('), ws0, identifier, ws0, char(')'), ws0, statement_body))),
+            |(_, (_, _, _, parameter, _, _, _, body))| Declaration::CollisionDeclaration { parameter, body },
+        ),
+        map(
+            (keyword("on_message"), cut((ws0, char('('), ws0, identifier, ws0, char(','), ws0, identifier, ws0, char(')'), ws0, statement_body))),
+            |(_, (_, _, _, name_parameter, _, _, _, arguments_parameter, _, _, _, body))| Declaration::MessageDeclaration { name_parameter, arguments_parameter, body },
+        ),
+        use_declaration,
         instance_var_declaration,
         function_declaration,
+        const_declaration,
     )).parse(input)
 }
@@ -1,16 +1,20 @@
-use nom::{IResult, Parser, branch::alt, bytes::complete::{tag, take_while1}, character::complete::char, combinator::map, error::make_error, multi::{many0, many1, separated_list0}, number::complete::double};
+use nom::{Parser, branch::alt, bytes::complete::{tag, take_while, take_while1}, character::complete::{char, satisfy}, combinator::{map, opt, peek, rest}, error::{ErrorKind, make_error}, multi::{many0, many1, separated_list0, separated_list1}, number::complete::double};
 
-use crate::{BinaryOperator, Expression, Note, Pixel, Sprite, Tone, parser::{identifier, instance_var_identifier, ws0, ws1}};
+use crate::{BinaryOperator, Expression, Note, NoteName, Pixel, Sprite, Tone, parser::{IResult, SyntaxError, comma_list0, comma_list1, fail_expecting, identifier, instance_var_identifier, keyword, ws0, ws1}};
 
-fn number(input: &str) -> IResult<&str, f64> {
+pub fn number(input: &str) -> IResult<&str, f64> {
     double(input)
 }
 
 fn sprite_expression(input: &str) -> IResult<&str, Expression> {
     fn sprite_pixel(input: &str) -> IResult<&str, Pixel> {
         alt((
-            map(char('#'), |_| Pixel::Set),
+            map(char('#'), |_| Pixel::Set(0)),
             map(char('.'), |_| Pixel::Clear),
+            map(
+                satisfy(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)),
+                |c| Pixel::Set(c.to_digit(16).unwrap() as u8),
+            ),
         )).parse(input)
     }
     
@@ -58,66 +62,146 @@ fn sprite_expression(input: &str) -> IResult<&str, Expression> {
         }
     }
 
+    // `sprite hex 8 { FF 81 }` - each row is a hex number, where the most significant of `width`
+    // bits is the leftmost pixel
+    fn hex_sprite(input: &str) -> IResult<&str, Sprite> {
+        let (input, (_, _, width, _, _, _)) = (keyword("hex"), ws1, number, ws0, tag("{"), ws0).parse(input)?;
+        if width.fract() != 0.0 || !(1.0..=64.0).contains(&width) {
+            return Err(nom::Err::Failure(make_error(input, ErrorKind::Verify)));
+        }
+        let width = width as usize;
+
+        let (input, rows) = separated_list0(ws1, hex_sprite_row(width)).parse(input)?;
+        let (input, _) = (ws0, tag("}")).parse(input)?;
+
+        let pixels = rows.iter()
+            .flat_map(|row| (0..width).rev().map(move |bit| if (row >> bit) & 1 == 1 { Pixel::Set(0) } else { Pixel::Clear }))
+            .collect();
+        Ok((input, Sprite { width, height: rows.len(), pixels }))
+    }
+
+    fn hex_sprite_row<'a>(width: usize) -> impl Parser<&'a str, Output = u64, Error = SyntaxError<&'a str>> {
+        move |input: &'a str| {
+            let (rest, digits) = take_while1(|c: char| c.is_ascii_hexdigit()).parse(input)?;
+
+            // Rows with more bits than the declared width are an error, not silently truncated
+            match u64::from_str_radix(digits, 16) {
+                Ok(row) if width == 64 || row >> width == 0 => Ok((rest, row)),
+                _ => Err(nom::Err::Failure(make_error(input, ErrorKind::Verify))),
+            }
+        }
+    }
+
     map(
-        (tag("sprite"), ws0, tag("{"), ws0, sprite, ws0, tag("}")),
-        |(_, _, _, _, sprite, _, _)| Expression::SpriteLiteral(sprite)
+        (
+            keyword("sprite"),
+            ws0,
+            alt((
+                hex_sprite,
+                map((tag("{"), ws0, sprite, ws0, tag("}")), |(_, _, sprite, _, _)| sprite),
+            )),
+        ),
+        |(_, _, sprite)| Expression::SpriteLiteral(sprite)
     ).parse(input)
 }
 
-fn sound_expression(input: &str) -> IResult<&str, Expression> {
-    fn note(input: &str) -> IResult<&str, Note> {
-        alt((
-            map(char('A'), |_| Note::A),
-            map(char('B'), |_| Note::B),
-            map(char('C'), |_| Note::C),
-            map(char('D'), |_| Note::D),
-            map(char('E'), |_| Note::E),
-            map(char('F'), |_| Note::F),
-            map(char('G'), |_| Note::G),
-        )).parse(input)
-    }
+fn note(input: &str) -> IResult<&str, Note> {
+    let name = alt((
+        map(char('A'), |_| NoteName::A),
+        map(char('B'), |_| NoteName::B),
+        map(char('C'), |_| NoteName::C),
+        map(char('D'), |_| NoteName::D),
+        map(char('E'), |_| NoteName::E),
+        map(char('F'), |_| NoteName::F),
+        map(char('G'), |_| NoteName::G),
+    ));
+    let sharp = map(opt(char('s')), |s| s.is_some());
+    let octave = map(opt(satisfy(|c| c.is_ascii_digit())), |c| {
+        c.map(|c| c.to_digit(10).unwrap() as i32).unwrap_or(Note::DEFAULT_OCTAVE)
+    });
+
+    map((name, sharp, octave), |(name, sharp, octave)| Note::new(name, sharp, octave)).parse(input)
+}
 
-    fn tone(input: &str) -> IResult<&str, (f64, Note)> {
-        map(
-            (number, ws0, char(':'), ws0, note),
-            |(duration, _, _, _, note)| (duration, note)
-        ).parse(input)
-    }
+fn tone(input: &str) -> IResult<&str, Tone> {
+    map(
+        (
+            number, ws0, char(':'), ws0, note,
+            opt(map((ws0, tag("->"), ws0, note), |(_, _, _, end_note)| end_note)),
+            opt(map((ws1, keyword("vol"), ws1, number), |(_, _, _, volume)| volume)),
+        ),
+        |(duration, _, _, _, note, end_note, volume)| Tone {
+            duration,
+            note,
+            end_note,
+            volume: volume.unwrap_or(Tone::DEFAULT_VOLUME).clamp(0.0, 1.0),
+        }
+    ).parse(input)
+}
 
+fn sound_expression(input: &str) -> IResult<&str, Expression> {
     // TODO: currently only allows a single tone
     map(
-        (tag("sound"), ws0, tag("{"), ws0, tone, ws0, tag("}")),
-        |(_, _, _, _, (duration, note), _, _)| Expression::SoundLiteral(Tone { duration, note })
+        (keyword("sound"), ws0, tag("{"), ws0, tone, ws0, tag("}")),
+        |(_, _, _, _, tone, _, _)| Expression::SoundLiteral(tone)
+    ).parse(input)
+}
+
+/// A sequence of tones, like `melody { 0.25: C, 0.25: D, 0.5: E }`, which are played one after
+/// the other.
+fn melody_expression(input: &str) -> IResult<&str, Expression> {
+    map(
+        (keyword("melody"), ws0, tag("{"), ws0, comma_list1(tone), ws0, tag("}")),
+        |(_, _, _, _, tones, _, _)| Expression::MelodyLiteral(tones)
     ).parse(input)
 }
 
 fn echo_expression(input: &str) -> IResult<&str, Expression> {
     map(
-        (tag("echo"), ws1, expression),
-        |(_, _, e)| Expression::Echo(Box::new(e)),
+        (keyword("echo"), ws1, separated_list1((ws0, char(','), ws0), expression)),
+        |(_, _, items)| Expression::Echo(items),
     ).parse(input)
 }
 
 fn spawn_expression(input: &str) -> IResult<&str, Expression> {
     map(
-        (tag("spawn"), ws1, identifier),
-        |(_, _, name)| Expression::SpawnEntity { name },
+        (
+            keyword("spawn"),
+            ws1,
+            identifier,
+            opt(map(
+                (
+                    ws1, keyword("with"), ws0, char('{'), ws0,
+                    comma_list0(map((identifier, ws0, char(':'), ws0, expression), |(name, _, _, _, value)| (name, value))),
+                    ws0, char('}'),
+                ),
+                |(_, _, _, _, _, initializers, _, _)| initializers,
+            )),
+        ),
+        |(_, _, name, initializers)| Expression::SpawnEntity { name, initializers: initializers.unwrap_or_default() },
     ).parse(input)
 }
 
 fn destroy_expression(input: &str) -> IResult<&str, Expression> {
     map(
-        (tag("destroy"), ws1, expression),
+        (keyword("destroy"), ws1, expression),
         |(_, _, expr)| Expression::DestroyEntity(Box::new(expr)),
     ).parse(input)
 }
 
+fn string_expression(input: &str) -> IResult<&str, Expression> {
+    map(
+        (char('"'), take_while(|c| c != '"'), char('"')),
+        |(_, s, _): (_, &str, _)| Expression::StringLiteral(s.to_owned()),
+    ).parse(input)
+}
+
 fn array_expression(input: &str) -> IResult<&str, Expression> {
     map(
         (
             char('['),
             ws0,
-            separated_list0((ws0, char(','), ws0), expression),
+            comma_list0(expression),
             ws0,
             char(']'),
         ),
@@ -127,15 +211,23 @@ fn array_expression(input: &str) -> IResult<&str, Expression> {
 
 fn atom_expression(input: &str) -> IResult<&str, Expression> {
     alt((
-        map(tag("null"), |_| Expression::NullLiteral),
-        map(tag("this"), |_| Expression::ThisLiteral),
-        map(tag("true"), |_| Expression::BooleanLiteral(true)),
-        map(tag("false"), |_| Expression::BooleanLiteral(false)),
+        map(keyword("null"), |_| Expression::NullLiteral),
+        map(keyword("this"), |_| Expression::ThisLiteral),
+        map(keyword("true"), |_| Expression::BooleanLiteral(true)),
+        map(keyword("false"), |_| Expression::BooleanLiteral(false)),
 
         sprite_expression,
         sound_expression,
+        melody_expression,
+        string_expression,
         array_expression,
 
+        // Must come before plain identifiers. Locals can't hold functions, so a name followed by
+        // parentheses is always a call, even if a local with that name exists
+        map(
+            (identifier, ws0, char('('), ws0, comma_list0(expression), ws0, char(')')),
+            |(name, _, _, _, arguments, _, _)| Expression::BareFunctionCall { name, arguments },
+        ),
         map(identifier, |id| Expression::Identifier(id)),
         map(instance_var_identifier, |id| Expression::InstanceVarIdentifier(id)),
         map(number, |n| Expression::NumberLiteral(n)),
@@ -145,31 +237,45 @@ fn atom_expression(input: &str) -> IResult<&str, Expression> {
 }
 
 fn call_expression(input: &str) -> IResult<&str, Expression> {
+    enum Postfix {
+        Call { name: String, arguments: Vec<Expression> },
+        InstanceVar(String),
+    }
+
     let (input, mut expr) = atom_expression(input)?;
 
-    let (input, calls) = many0(
-        map(
-            (
-                ws0,
-                char('.'),
-                ws0,
-                identifier,
-                ws0,
-                char('('),
-                separated_list0(
-                    char(','),
-                    map((ws0, expression, ws0), |(_, e, _)| e),
+    let (input, postfixes) = many0(
+        alt((
+            map(
+                (
+                    ws0,
+                    char('.'),
+                    ws0,
+                    identifier,
+                    ws0,
+                    char('('),
+                    ws0,
+                    comma_list0(expression),
+                    ws0,
+                    char(')'),
                 ),
-                char(')'),
+                |(_, _, _, name, _, _, _, arguments, _, _)| Postfix::Call { name, arguments },
             ),
-            |(_, _, _, name, _, _, arguments, _)| (name, arguments),
-        )
+            // No whitespace permitted, so it's clear that `entity@var` is one unit
+            map(instance_var_identifier, Postfix::InstanceVar),
+        ))
     ).parse(input)?;
-    for (name, arguments) in calls {
-        expr = Expression::FunctionCall {
-            target: Box::new(expr),
-            name,
-            arguments,
+    for postfix in postfixes {
+        expr = match postfix {
+            Postfix::Call { name, arguments } => Expression::FunctionCall {
+                target: Box::new(expr),
+                name,
+                arguments,
+            },
+            Postfix::InstanceVar(name) => Expression::ForeignInstanceVarIdentifier {
+                target: Box::new(expr),
+                name,
+            },
         }
     }
 
@@ -185,14 +291,29 @@ fn prefix_expression(input: &str) -> IResult<&str, Expression> {
     )).parse(input)
 }
 
+fn power_expression(input: &str) -> IResult<&str, Expression> {
+    let (input, base) = prefix_expression(input)?;
+
+    // Right-associative, so recurse for the exponent: `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`
+    let (input, exponent) = opt((ws0, char('^'), ws0, power_expression)).parse(input)?;
+    match exponent {
+        Some((_, _, _, exponent)) => Ok((input, Expression::BinaryOperation {
+            left: Box::new(base),
+            right: Box::new(exponent),
+            operator: BinaryOperator::Power,
+        })),
+        None => Ok((input, base)),
+    }
+}
+
 fn mul_div_expression(input: &str) -> IResult<&str, Expression> {
-    let (input, mut expr) = prefix_expression(input)?;
+    let (input, mut expr) = power_expression(input)?;
 
     let (input, ops) = many0((
         ws0,
         alt((char('*'), char('/'))),
         ws0,
-        prefix_expression,
+        power_expression,
     )).parse(input)?;
     for (_, op, _, right) in ops {
         let operator = match op {
@@ -227,16 +348,49 @@ fn add_sub_expression(input: &str) -> IResult<&str, Expression> {
     Ok((input, expr))
 }
 
+fn range_expression(input: &str) -> IResult<&str, Expression> {
+    let (input, start) = add_sub_expression(input)?;
+
+    let (input, range) = opt((
+        ws0,
+        keyword("to"),
+        ws0,
+        add_sub_expression,
+        opt(map((ws0, keyword("by"), ws0, add_sub_expression), |(_, _, _, step)| step)),
+    )).parse(input)?;
+
+    match range {
+        Some((_, _, _, end, step)) => Ok((input, Expression::Range {
+            start: Box::new(start),
+            end: Box::new(end),
+            step: step.map(Box::new),
+        })),
+        None => Ok((input, start)),
+    }
+}
+
 fn cmp_expression(input: &str) -> IResult<&str, Expression> {
-    let (input, mut expr) = add_sub_expression(input)?;
+    let (input, mut expr) = range_expression(input)?;
 
     let (input, ops) = many0((
         ws0,
-        alt((tag("=="), tag("!="), tag("<="), tag(">="), tag("<"), tag(">"))),
+        peek(rest),
+        // Longer operators must come first, otherwise `<=` would be parsed as `<` followed by `=`
+        alt((tag("=="), tag("!="), tag("<="), tag(">="), tag("<"), tag(">"), keyword("in"))),
         ws0,
-        add_sub_expression,
+        range_expression,
     )).parse(input)?;
-    for (_, op, _, right) in ops {
+
+    // Ordering a comparison's boolean result will always fail at runtime, and is almost certainly
+    // an attempt at `0 < x < 10`, so reject it here with a more helpful message.
+    // Equality on booleans is fine though, like `a < b == c`
+    for (_, op_input, op, _, _) in ops.iter().skip(1) {
+        if matches!(*op, "<" | ">" | "<=" | ">=") {
+            return fail_expecting(op_input, "`&&` between comparisons, which can't be chained - write `a < b && b < c` rather than `a < b < c`");
+        }
+    }
+
+    for (_, _, op, _, right) in ops {
         let operator = match op {
             "==" => BinaryOperator::Equals,
             "!=" => BinaryOperator::NotEquals,
@@ -244,6 +398,7 @@ fn cmp_expression(input: &str) -> IResult<&str, Expression> {
             ">" => BinaryOperator::GreaterThan,
             "<=" => BinaryOperator::LessThanOrEquals,
             ">=" => BinaryOperator::GreaterThanOrEquals,
+            "in" => BinaryOperator::In,
             _ => unreachable!(),
         };
         expr = Expression::BinaryOperation { left: Box::new(expr), right: Box::new(right), operator };
@@ -252,14 +407,25 @@ fn cmp_expression(input: &str) -> IResult<&str, Expression> {
     Ok((input, expr))
 }
 
-fn bool_op_expression(input: &str) -> IResult<&str, Expression> {
+fn null_coalesce_expression(input: &str) -> IResult<&str, Expression> {
     let (input, mut expr) = cmp_expression(input)?;
 
+    let (input, rights) = many0((ws0, tag("??"), ws0, cmp_expression)).parse(input)?;
+    for (_, _, _, right) in rights {
+        expr = Expression::BinaryOperation { left: Box::new(expr), right: Box::new(right), operator: BinaryOperator::NullCoalesce };
+    }
+
+    Ok((input, expr))
+}
+
+fn bool_op_expression(input: &str) -> IResult<&str, Expression> {
+    let (input, mut expr) = null_coalesce_expression(input)?;
+
     let (input, ops) = many0((
         ws0,
         alt((tag("&&"), tag("||"))),
         ws0,
-        cmp_expression,
+        null_coalesce_expression,
     )).parse(input)?;
     for (_, op, _, right) in ops {
         let operator = match op {
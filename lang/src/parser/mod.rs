@@ -1,13 +1,60 @@
-use std::error::Error;
+use std::{error::Error, fmt::Display, rc::Rc};
 
-use nom::{IResult, Parser, branch::alt, bytes::complete::{tag, take_until, take_while, take_while1}, character::complete::{anychar, satisfy}, combinator::{map, recognize}, multi::{many0, many1}};
+use nom::{Parser, branch::alt, bytes::complete::{tag, take_until, take_while, take_while1}, character::complete::{anychar, char, satisfy}, combinator::{map, not, opt, recognize}, sequence::terminated, multi::{many0, many1, separated_list1}};
 
-use crate::{Declaration, Statement};
+use crate::{Declaration, Span, Statement};
 
 mod expression;
 mod statement;
 mod declaration;
 
+/// The error type used by all parsers.
+/// 
+/// Most errors only record where they happened, but parsers which know what should have been
+/// found can describe it in `expected`, which is then used in the [`ParseError`].
+#[derive(Debug, Clone, PartialEq)]
+struct SyntaxError<I> {
+    input: I,
+    expected: Option<&'static str>,
+
+    /// If the error happened within a statement, the input at the start of the innermost one, so
+    /// that [`parse_reporting_all`] can skip over it
+    statement_start: Option<I>,
+}
+
+impl<I> nom::error::ParseError<I> for SyntaxError<I> {
+    fn from_error_kind(input: I, _: nom::error::ErrorKind) -> Self {
+        Self { input, expected: None, statement_start: None }
+    }
+
+    fn append(_: I, _: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type IResult<I, O> = nom::IResult<I, O, SyntaxError<I>>;
+
+/// Fails immediately, with a description of what was expected at `input`.
+fn fail_expecting<'a, O>(input: &'a str, expected: &'static str) -> IResult<&'a str, O> {
+    Err(nom::Err::Failure(SyntaxError { input, expected: Some(expected), statement_start: None }))
+}
+
+/// Runs `parser`, but if it doesn't match, commits to a failure describing what was `expected`.
+fn expect<'a, O>(expected: &'static str, mut parser: impl Parser<&'a str, Output = O, Error = SyntaxError<&'a str>>) -> impl Parser<&'a str, Output = O, Error = SyntaxError<&'a str>> {
+    move |input: &'a str| match parser.parse(input) {
+        Err(nom::Err::Error(_)) => fail_expecting(input, expected),
+        result => result,
+    }
+}
+
+const EXPECTED_SEMICOLON: &str = "`;` after statement";
+
+/// Expects the `;` which ends a statement, reporting a missing one at the end of the statement
+/// rather than wherever the next non-whitespace character happens to be.
+fn semicolon(input: &str) -> IResult<&str, ()> {
+    map(expect(EXPECTED_SEMICOLON, (ws0, char(';'))), |_| ()).parse(input)
+}
+
 fn comment(input: &str) -> IResult<&str, &str> {
     recognize(
         (tag("/*"), take_until("*/"), tag("*/")),
@@ -30,15 +77,15 @@ fn ws0(input: &str) -> IResult<&str, &str> {
     ).parse(input)
 }
 
+fn is_identifier_character(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
 fn identifier(input: &str) -> IResult<&str, String> {
     fn is_first_identifier_character(c: char) -> bool {
         c.is_alphabetic() || c == '_'
     }
 
-    fn is_identifier_character(c: char) -> bool {
-        c.is_alphanumeric() || c == '_'
-    }
-
     let (input, first) = satisfy(is_first_identifier_character)(input)?;
     let (input, rest) = take_while(is_identifier_character)(input)?;
     
@@ -46,12 +93,31 @@ fn identifier(input: &str) -> IResult<&str, String> {
     Ok((input, id))
 }
 
+/// Matches a keyword, but only if it isn't the start of a longer identifier - so `this` will not
+/// match the beginning of `thisPlayer`.
+fn keyword<'a>(word: &'static str) -> impl Parser<&'a str, Output = &'a str, Error = SyntaxError<&'a str>> {
+    terminated(tag(word), not(satisfy(is_identifier_character)))
+}
+
+/// A comma-separated list of at least one `item`, which may have a trailing comma.
+fn comma_list1<'a, O>(item: impl Parser<&'a str, Output = O, Error = SyntaxError<&'a str>>) -> impl Parser<&'a str, Output = Vec<O>, Error = SyntaxError<&'a str>> {
+    terminated(
+        separated_list1((ws0, char(','), ws0), item),
+        opt((ws0, char(','))),
+    )
+}
+
+/// Like `comma_list1`, but also allows an empty list. A lone comma is not a valid empty list.
+fn comma_list0<'a, O>(item: impl Parser<&'a str, Output = O, Error = SyntaxError<&'a str>>) -> impl Parser<&'a str, Output = Vec<O>, Error = SyntaxError<&'a str>> {
+    map(opt(comma_list1(item)), Option::unwrap_or_default)
+}
+
 fn instance_var_identifier(input: &str) -> IResult<&str, String> {
     let (input, _) = tag("@")(input)?;
     identifier(input)
 }
 
-fn braced_body<'a, T>(inner: impl Fn(&str) -> IResult<&str, T>) -> impl Parser<&'a str, Output = Vec<T>, Error = nom::error::Error<&'a str>> {
+fn braced_body<'a, T>(inner: impl Fn(&str) -> IResult<&str, T>) -> impl Parser<&'a str, Output = Vec<T>, Error = SyntaxError<&'a str>> {
     map(
         (
             ws0,
@@ -59,30 +125,243 @@ fn braced_body<'a, T>(inner: impl Fn(&str) -> IResult<&str, T>) -> impl Parser<&
             many0(
                 map((ws0, inner, ws0), |(_, i, _)| i),
             ),
+            // Also skips any whitespace before an item which failed to parse, so that errors are
+            // reported at the item itself
+            ws0,
             tag("}"),
             ws0,
         ),
-        |(_, _, s, _, _)| s,
+        |(_, _, s, _, _, _)| s,
     )
 }
 
 fn statement_body(input: &str) -> IResult<&str, Vec<Statement>> {
-    braced_body(statement::statement).parse(input)
+    braced_body(|input| {
+        // Note where the statement began if it failed, unless a nested statement already has
+        let (remaining, statement) = statement::statement(input).map_err(|err| match err {
+            nom::Err::Failure(e) => nom::Err::Failure(SyntaxError { statement_start: e.statement_start.or(Some(input)), ..e }),
+            err => err,
+        })?;
+
+        // Only the distance to the end of the source is known here, so the line and column are
+        // filled in by `locate_declarations` once parsing has finished
+        let span = Span { file: None, line: 0, column: 0, offset: input.len() };
+        Ok((remaining, Statement::Located { span, statement: Box::new(statement) }))
+    }).parse(input)
 }
 
 fn declaration_body(input: &str) -> IResult<&str, Vec<Declaration>> {
     braced_body(declaration::declaration).parse(input)
 }
 
-pub fn parse(input: &str) -> Result<Vec<Declaration>, Box<dyn Error + '_>> {
+pub fn parse(input: &str) -> Result<Vec<Declaration>, ParseError> {
+    let mut declarations = parse_declarations(input).map_err(|e| ParseError::at(input, e.input, e.expected.unwrap_or("valid syntax")))?;
+    locate_declarations(&mut declarations, input, None);
+    Ok(declarations)
+}
+
+/// The most errors which [`parse_reporting_all`] will report for one source.
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// Like [`parse`], but if there is an error within a statement, skips that statement and carries
+/// on, so that as many errors as possible can be reported at once.
+pub fn parse_reporting_all(input: &str) -> Result<Vec<Declaration>, Vec<ParseError>> {
+    parse_reporting_all_in_file(input, None)
+}
+
+/// Like [`parse_reporting_all`], but the [`Span`]s of the parsed statements also record the name
+/// of the `file` which the source came from.
+pub fn parse_file_reporting_all(input: &str, file: &str) -> Result<Vec<Declaration>, Vec<ParseError>> {
+    parse_reporting_all_in_file(input, Some(file.into()))
+}
+
+fn parse_reporting_all_in_file(input: &str, file: Option<Rc<str>>) -> Result<Vec<Declaration>, Vec<ParseError>> {
+    // Skipped statements are blanked out with spaces, so all positions stay the same
+    let mut source = input.to_owned();
+    let mut errors = vec![];
+
+    while errors.len() < MAX_REPORTED_ERRORS {
+        let err = match parse_declarations(&source) {
+            Ok(mut declarations) if errors.is_empty() => {
+                locate_declarations(&mut declarations, input, file);
+                return Ok(declarations);
+            }
+            Ok(_) => break,
+            Err(err) => err,
+        };
+
+        let offset = source.len() - err.input.len();
+        errors.push(ParseError::at(input, &input[offset..], err.expected.unwrap_or("valid syntax")));
+
+        // Errors outside of a statement can't be skipped, because there's no telling where the
+        // broken part ends
+        let Some(statement_start) = err.statement_start else { break };
+        let start = source.len() - statement_start.len();
+
+        // The statement ends where the `;` should have been if that was the problem, otherwise at
+        // the next `;` - unless the end of the block comes first
+        let end = if err.expected == Some(EXPECTED_SEMICOLON) {
+            offset
+        } else {
+            match source[offset..].find([';', '}']) {
+                Some(i) if source[offset + i..].starts_with(';') => offset + i + 1,
+                Some(i) => offset + i,
+                None => source.len(),
+            }
+        };
+        if start >= end {
+            break;
+        }
+
+        let blanked = source[start..end].chars()
+            .map(|c| if c == '\n' { "\n".to_owned() } else { " ".repeat(c.len_utf8()) })
+            .collect::<String>();
+        source.replace_range(start..end, &blanked);
+    }
+
+    Err(errors)
+}
+
+fn parse_declarations(input: &str) -> Result<Vec<Declaration>, SyntaxError<&str>> {
     let (remaining, declarations) =
         many0(
             map((ws0, declaration::declaration, ws0), |(_, d, _)| d),
-        ).parse(input)?;
+        ).parse(input)
+        .map_err(|err| match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => SyntaxError { input: "", expected: Some("more input"), statement_start: None },
+        })?;
 
     if !remaining.is_empty() {
-        return Err("parse error - not all input consumed".into());
+        // `many0` stops at the first declaration which fails to parse, discarding its error.
+        // Parse it again to find out where within the declaration the problem really is
+        return match declaration::declaration(remaining) {
+            Err(nom::Err::Failure(e)) => Err(e),
+            _ => Err(SyntaxError { input: remaining, expected: Some("declaration"), statement_start: None }),
+        };
     }
 
     Ok(declarations)
 }
+
+/// Fills in the line and column of the [`Span`] of every statement within `declarations`, which
+/// were parsed from `source`.
+fn locate_declarations(declarations: &mut [Declaration], source: &str, file: Option<Rc<str>>) {
+    fn locate_statements(statements: &mut [Statement], source: &str, file: &Option<Rc<str>>) {
+        for statement in statements {
+            match statement {
+                Statement::Located { span, statement } => {
+                    span.offset = source.len() - span.offset;
+                    (span.line, span.column) = line_and_column(source, span.offset);
+                    span.file = file.clone();
+                    locate_statements(std::slice::from_mut(statement.as_mut()), source, file);
+                }
+
+                Statement::IfConditional { true_body, false_body, .. } => {
+                    locate_statements(true_body, source, file);
+                    if let Some(false_body) = false_body {
+                        locate_statements(false_body, source, file);
+                    }
+                }
+                Statement::Match { arms, else_body, .. } => {
+                    for (_, body) in arms {
+                        locate_statements(body, source, file);
+                    }
+                    if let Some(else_body) = else_body {
+                        locate_statements(else_body, source, file);
+                    }
+                }
+                Statement::EachLoop { body, .. } | Statement::RepeatUntil { body, .. } =>
+                    locate_statements(body, source, file),
+
+                Statement::Expression(_) | Statement::Assignment { .. }
+                    | Statement::DestructuringAssignment { .. } | Statement::Return(_) => {}
+            }
+        }
+    }
+
+    for declaration in declarations {
+        match declaration {
+            Declaration::EntityDeclaration { body, .. } => locate_declarations(body, source, file.clone()),
+
+            Declaration::ConstructorDeclaration { body }
+                | Declaration::TickDeclaration { body, .. }
+                | Declaration::DrawDeclaration { body }
+                | Declaration::DestroyDeclaration { body }
+                | Declaration::PressDeclaration { body, .. }
+                | Declaration::ReleaseDeclaration { body, .. }
+                | Declaration::CollisionDeclaration { body, .. }
+                | Declaration::MessageDeclaration { body, .. }
+                | Declaration::FunctionDeclaration { body, .. } => locate_statements(body, source, &file),
+
+            Declaration::InstanceVarDeclaration { .. } | Declaration::UseDeclaration { .. }
+                | Declaration::ConstDeclaration { .. } => {}
+        }
+    }
+}
+
+/// The 1-indexed line and column (in characters) of the byte `offset` into `source`.
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// A parse failure, with the position in the source where it occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-indexed line number
+    pub line: usize,
+
+    /// 1-indexed column number, in characters
+    pub column: usize,
+
+    /// The full source line containing the error
+    pub snippet: String,
+
+    /// A description of what the parser was expecting to find
+    pub expected: String,
+
+    /// An excerpt of the source found at the error position, up to the end of the line
+    pub found: String,
+}
+
+impl ParseError {
+    /// Builds an error for the position in `source` at which `remaining` begins.
+    fn at(source: &str, remaining: &str, expected: impl Into<String>) -> Self {
+        let offset = source.len() - remaining.len();
+        let before = &source[..offset];
+
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = source[offset..].find('\n').map(|i| offset + i).unwrap_or(source.len());
+        let (line, column) = line_and_column(source, offset);
+
+        Self {
+            line,
+            column,
+            snippet: source[line_start..line_end].to_owned(),
+            expected: expected.into(),
+            found: source[offset..line_end].chars().take(40).collect(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep tabs in the caret line, so it still lines up with the snippet
+        let padding = self.snippet.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        write!(f, "parse error at line {}, column {}: expected {}", self.line, self.column, self.expected)?;
+        if self.found.trim().is_empty() {
+            writeln!(f, ", found end of line")?;
+        } else {
+            writeln!(f, ", found `{}`", self.found.trim_end())?;
+        }
+        writeln!(f, "    {}", self.snippet)?;
+        write!(f, "    {}^", padding)
+    }
+}
+impl Error for ParseError {}
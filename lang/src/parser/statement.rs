@@ -1,6 +1,6 @@
-use nom::{IResult, Parser, branch::alt, bytes::complete::tag, character::complete::char, combinator::map};
+use nom::{Parser, branch::alt, bytes::complete::tag, character::complete::char, combinator::{map, not, opt}, multi::many0};
 
-use crate::{Expression, Statement, parser::{expression::expression, identifier, statement_body, ws0, ws1}};
+use crate::{Expression, Statement, parser::{IResult, comma_list1, expect, expression::expression, identifier, instance_var_identifier, keyword, semicolon, statement_body, ws0, ws1}};
 
 fn parenthesised_expression(input: &str) -> IResult<&str, Expression> {
     map(
@@ -18,7 +18,7 @@ fn parenthesised_expression(input: &str) -> IResult<&str, Expression> {
 fn if_statement(input: &str) -> IResult<&str, Statement> {
     map(
         (
-            tag("if"),
+            keyword("if"),
             ws0,
             parenthesised_expression,
             ws0,
@@ -29,33 +29,93 @@ fn if_statement(input: &str) -> IResult<&str, Statement> {
     ).parse(input)
 }
 
+fn match_statement(input: &str) -> IResult<&str, Statement> {
+    fn arm(input: &str) -> IResult<&str, (Expression, Vec<Statement>)> {
+        map(
+            (ws0, not(keyword("else")), expression, statement_body),
+            |(_, _, value, body)| (value, body),
+        ).parse(input)
+    }
+
+    map(
+        (
+            keyword("match"),
+            ws0,
+            parenthesised_expression,
+            ws0,
+            char('{'),
+            many0(arm),
+            ws0,
+            opt(map((keyword("else"), statement_body), |(_, body)| body)),
+            ws0,
+            char('}'),
+        ),
+        |(_, _, scrutinee, _, _, arms, _, else_body, _, _)| Statement::Match { scrutinee, arms, else_body }
+    ).parse(input)
+}
+
 fn each_loop(input: &str) -> IResult<&str, Statement> {
     map(
         (
-            tag("each"),
+            keyword("each"),
             ws1,
             identifier,
+            opt(map((ws0, char(','), ws0, identifier), |(_, _, _, index)| index)),
             ws1,
-            tag("in"),
+            keyword("in"),
             ws0,
             parenthesised_expression,
             ws0,
             statement_body,
         ),
-        |(_, _, variable, _, _, _, source, _, body)| Statement::EachLoop { variable, source, body }
+        |(_, _, variable, index_variable, _, _, _, source, _, body)| Statement::EachLoop { variable, index_variable, source, body }
+    ).parse(input)
+}
+
+fn repeat_until_loop(input: &str) -> IResult<&str, Statement> {
+    map(
+        (
+            keyword("repeat"),
+            ws0,
+            statement_body,
+            ws0,
+            keyword("until"),
+            ws0,
+            parenthesised_expression,
+            semicolon,
+        ),
+        |(_, _, body, _, _, _, condition, _)| Statement::RepeatUntil { body, condition }
+    ).parse(input)
+}
+
+fn destructuring_assignment(input: &str) -> IResult<&str, Statement> {
+    let target = alt((
+        map(identifier, Expression::Identifier),
+        map(instance_var_identifier, Expression::InstanceVarIdentifier),
+    ));
+
+    map(
+        (
+            char('['), ws0, comma_list1(target), ws0, char(']'),
+            ws0, tag("="), ws0, expression, semicolon,
+        ),
+        |(_, _, targets, _, _, _, _, _, value, _)| Statement::DestructuringAssignment { targets, value },
     ).parse(input)
 }
 
 pub fn statement(input: &str) -> IResult<&str, Statement> {
     alt((
         if_statement,
+        match_statement,
         each_loop,
-        map((tag("return"), ws1, expression, ws0, tag(";")), |(_, _, e, _, _)| Statement::Return(Some(e))),
-        map((tag("return"), ws0, tag(";")), |_| Statement::Return(None)),
+        repeat_until_loop,
+        map((keyword("return"), ws1, expression, semicolon), |(_, _, e, _)| Statement::Return(Some(e))),
+        map((keyword("return"), ws0, tag(";")), |_| Statement::Return(None)),
+        destructuring_assignment,
         map(
-            (expression, ws0, tag("="), ws0, expression, ws0, tag(";")),
-            |(target, _, _, _, value, _, _)| Statement::Assignment { target, value },
+            (expression, ws0, tag("="), ws0, expect("expression", expression), semicolon),
+            |(target, _, _, _, value, _)| Statement::Assignment { target, value },
         ),
-        map((expression, ws0, tag(";")), |(e, _, _)| Statement::Expression(e)),
+        map((expression, semicolon), |(e, _)| Statement::Expression(e)),
     )).parse(input)
 }
@@ -0,0 +1,342 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Entity, EntityId, Interpreter, InterpreterResult, Object, RuntimeError, RuntimeErrorKind, Sprite, Tone, check_tone_durations};
+
+/// Everything needed to carry on a game from where it was, created with
+/// [`Interpreter::save_state`]. The host can serialise this however it likes, and later load it
+/// into an interpreter created from the same declarations with [`Interpreter::load_state`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveState {
+    /// Every entity which hadn't been destroyed, in the order they were spawned
+    pub entities: Vec<SavedEntity>,
+
+    /// The ID which the next entity to be spawned would have been given. References to lower IDs
+    /// which aren't in `entities` are to entities which had been destroyed
+    pub next_entity_id: usize,
+
+    pub globals: BTreeMap<String, SavedValue>,
+
+    /// Constants which hold arrays, since those arrays could have been changed since they were
+    /// declared. Other constants are declared again by the interpreter the game is loaded into
+    #[serde(default)]
+    pub constants: BTreeMap<String, SavedValue>,
+
+    /// Contents of the arrays referred to by [`SavedValue::Array`]. Each array is only saved once,
+    /// so arrays which were shared between variables are still shared once they're loaded
+    pub arrays: Vec<Vec<SavedValue>>,
+
+    pub camera: (f64, f64),
+    pub tick_count: u64,
+}
+
+/// An entity within a [`SaveState`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedEntity {
+    /// The entity's ID when it was saved. It may be given a different one when it's loaded, but
+    /// references to it from other values will be changed to match
+    pub id: usize,
+
+    /// The name of the entity's declaration
+    pub kind: String,
+
+    pub ivars: BTreeMap<String, SavedValue>,
+    pub last_drawn_size: Option<(usize, usize)>,
+    pub asleep: bool,
+}
+
+/// An [`Object`] within a [`SaveState`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SavedValue {
+    Null,
+    Number(f64),
+    Boolean(bool),
+    String(String),
+
+    /// A reference to an entity, by its saved ID
+    Entity(usize),
+
+    /// An entity declaration, by name
+    EntityKind(String),
+
+    Sprite(Sprite),
+    Sound(Tone),
+    Melody(Vec<Tone>),
+
+    /// An array, as an index into [`SaveState::arrays`]
+    Array(usize),
+
+    Range {
+        start: i64,
+        end: i64,
+        step: i64,
+    },
+
+    /// A built-in singleton, like `Math`, by name
+    Singleton(String),
+}
+
+impl Interpreter {
+    /// Saves the state of the running game, so that it can be carried on later with
+    /// [`Interpreter::load_state`]. This should be called between ticks.
+    ///
+    /// Sounds and melodies which are still playing aren't saved, and neither is anything kept by
+    /// the host, like `Storage`. Messages which haven't been delivered yet are dropped, and
+    /// entities which have been destroyed but not removed yet are left out, without running their
+    /// `on_destroy`.
+    pub fn save_state(&self) -> SaveState {
+        let mut saver = Saver { arrays: vec![], array_indices: HashMap::new() };
+
+        let entities = self.entities.iter()
+            .filter(|(id, _)| !self.entities_pending_destroy.contains(id))
+            .map(|(id, entity)| SavedEntity {
+                id: id.0,
+                kind: entity.kind.name.clone(),
+                ivars: saver.save_map(&entity.ivars),
+                last_drawn_size: entity.last_drawn_size,
+                asleep: entity.asleep,
+            })
+            .collect();
+        let globals = saver.save_map(&self.globals);
+        let array_constants = self.constants.iter()
+            .filter(|(_, value)| matches!(value, Object::Array(_)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let constants = saver.save_map(&array_constants);
+
+        SaveState {
+            entities,
+            next_entity_id: self.next_entity_id,
+            globals,
+            constants,
+            arrays: saver.arrays,
+            camera: self.camera,
+            tick_count: self.tick_count,
+        }
+    }
+
+    /// Replaces the running game with one saved by [`Interpreter::save_state`], which may have
+    /// come from a different interpreter created from the same declarations.
+    ///
+    /// Entities are given new IDs, in the same order as their saved ones. If the declarations have
+    /// changed since the game was saved, so that an entity's declaration or one of its instance
+    /// variables no longer exists, this gives an error and the running game is left as it was.
+    /// Instance variables which have been declared since are `null`. Saved constants only replace
+    /// ones which are still declared and still hold an array.
+    pub fn load_state(&mut self, state: SaveState) -> InterpreterResult {
+        if self.ticking {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "cannot load a saved game during a tick"));
+        }
+
+        let mut saved_entities = state.entities;
+        saved_entities.sort_by_key(|e| e.id);
+
+        // Work out every entity's new ID first, so that entities can refer to ones after them
+        let mut ids = HashMap::new();
+        for (saved_entity, new_id) in saved_entities.iter().zip(1..) {
+            if saved_entity.id >= state.next_entity_id {
+                return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("saved entity #{} has an ID which hadn't been given out yet", saved_entity.id)));
+            }
+            if ids.insert(saved_entity.id, EntityId(new_id)).is_some() {
+                return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("there is more than one saved entity #{}", saved_entity.id)));
+            }
+        }
+
+        let mut loader = Loader {
+            interpreter: self,
+            next_entity_id: state.next_entity_id,
+            next_new_id: saved_entities.len() + 1,
+            ids,
+            arrays: state.arrays.iter().map(|_| Rc::new(RefCell::new(vec![]))).collect(),
+        };
+
+        // Arrays are filled in afterwards, because they could contain themselves
+        let mut array_contents = vec![];
+        for items in &state.arrays {
+            array_contents.push(items.iter().map(|item| loader.load(item)).collect::<InterpreterResult<Vec<_>>>()?);
+        }
+
+        let mut entities = vec![];
+        for saved_entity in &saved_entities {
+            let Some(kind) = self.entity_kinds.get(&saved_entity.kind).cloned() else {
+                return Err(RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("saved entity #{} was spawned from entity declaration `{}`, which no longer exists", saved_entity.id, saved_entity.kind)));
+            };
+
+            let mut ivars = kind.ivars.iter()
+                .map(|ivar| (ivar.name.clone(), Object::Null))
+                .collect::<HashMap<_, _>>();
+            for (name, value) in &saved_entity.ivars {
+                let Some(ivar) = ivars.get_mut(name) else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::UndeclaredIvar, format!("saved entity #{} has instance variable `{name}`, but entity `{}` no longer declares it", saved_entity.id, kind.name)));
+                };
+                *ivar = loader.load(value)?;
+            }
+
+            let id = loader.ids[&saved_entity.id];
+            entities.push((id, Entity { kind, ivars, last_drawn_size: saved_entity.last_drawn_size, asleep: saved_entity.asleep }));
+        }
+
+        let globals = state.globals.iter()
+            .map(|(name, value)| Ok((name.clone(), loader.load(value)?)))
+            .collect::<InterpreterResult<HashMap<_, _>>>()?;
+        let mut constants = vec![];
+        for (name, value) in &state.constants {
+            let value = loader.load(value)?;
+            if matches!(value, Object::Array(_)) && matches!(self.constants.get(name), Some(Object::Array(_))) {
+                constants.push((name.clone(), value));
+            }
+        }
+
+        for (array, contents) in loader.arrays.iter().zip(array_contents) {
+            *array.borrow_mut() = contents;
+        }
+        let next_entity_id = loader.next_new_id;
+
+        // Nothing can go wrong from here, so the running game can be replaced
+        self.clear_game_state();
+        for (id, entity) in entities {
+            self.entities_by_kinds.entry(entity.kind.name.clone()).or_default().insert(id);
+            self.entities.insert(id, entity);
+        }
+        self.next_entity_id = next_entity_id;
+        self.globals = globals;
+        self.constants.extend(constants);
+        self.camera = state.camera;
+        self.tick_count = state.tick_count;
+
+        Ok(())
+    }
+}
+
+struct Saver {
+    arrays: Vec<Vec<SavedValue>>,
+
+    /// Indices into `arrays` of the arrays which have been saved so far
+    array_indices: HashMap<*const RefCell<Vec<Object>>, usize>,
+}
+
+impl Saver {
+    /// Saves values in order of their names, so that arrays are numbered the same way every time
+    /// the same game is saved.
+    fn save_map(&mut self, objects: &HashMap<String, Object>) -> BTreeMap<String, SavedValue> {
+        let mut names = objects.keys().collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().map(|name| (name.clone(), self.save(&objects[name]))).collect()
+    }
+
+    fn save(&mut self, object: &Object) -> SavedValue {
+        match object {
+            Object::Null => SavedValue::Null,
+            Object::Number(n) => SavedValue::Number(*n),
+            Object::Boolean(b) => SavedValue::Boolean(*b),
+            Object::String(s) => SavedValue::String(s.clone()),
+            Object::Entity(id) => SavedValue::Entity(id.0),
+            Object::EntityKind(kind) => SavedValue::EntityKind(kind.name.clone()),
+            Object::Sprite(sprite) => SavedValue::Sprite(sprite.clone()),
+            Object::Sound(tone) => SavedValue::Sound(*tone),
+            Object::Melody(tones) => SavedValue::Melody(tones.clone()),
+            Object::Range { start, end, step } => SavedValue::Range { start: *start, end: *end, step: *step },
+
+            Object::Array(items) => {
+                if let Some(index) = self.array_indices.get(&Rc::as_ptr(items)) {
+                    return SavedValue::Array(*index);
+                }
+
+                // Claim an index before saving the items, in case the array contains itself
+                let index = self.arrays.len();
+                self.arrays.push(vec![]);
+                self.array_indices.insert(Rc::as_ptr(items), index);
+
+                let saved_items = items.borrow().iter().map(|item| self.save(item)).collect();
+                self.arrays[index] = saved_items;
+                SavedValue::Array(index)
+            },
+
+            Object::InputSingleton
+                | Object::DisplaySingleton
+                | Object::MathSingleton
+                | Object::GlobalSingleton
+                | Object::HostSingleton
+                | Object::SpriteSingleton
+                | Object::TimeSingleton
+                | Object::TextSingleton
+                | Object::StorageSingleton
+                | Object::CameraSingleton
+                | Object::DebugSingleton
+                | Object::GameSingleton
+                | Object::ColorSingleton => SavedValue::Singleton(object.singleton_name().unwrap().to_owned()),
+        }
+    }
+}
+
+struct Loader<'a> {
+    interpreter: &'a Interpreter,
+
+    /// [`SaveState::next_entity_id`], which every saved entity reference must be below
+    next_entity_id: usize,
+
+    /// The next ID to give to an entity which had already been destroyed when the game was saved
+    next_new_id: usize,
+
+    /// New IDs of the saved entities, keyed by their saved IDs
+    ids: HashMap<usize, EntityId>,
+
+    arrays: Vec<Rc<RefCell<Vec<Object>>>>,
+}
+
+impl Loader<'_> {
+    fn load(&mut self, value: &SavedValue) -> InterpreterResult<Object> {
+        Ok(match value {
+            SavedValue::Null => Object::Null,
+            SavedValue::Number(n) => Object::Number(*n),
+            SavedValue::Boolean(b) => Object::Boolean(*b),
+            SavedValue::String(s) => Object::String(s.clone()),
+            SavedValue::Sprite(sprite) => Object::Sprite(sprite.clone()),
+            SavedValue::Sound(tone) => {
+                check_tone_durations(std::slice::from_ref(tone))?;
+                Object::Sound(*tone)
+            },
+            SavedValue::Melody(tones) => {
+                check_tone_durations(tones)?;
+                Object::Melody(tones.clone())
+            },
+            SavedValue::Range { start, end, step } => Object::Range { start: *start, end: *end, step: *step },
+
+            SavedValue::Entity(id) => {
+                if *id >= self.next_entity_id {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("saved game refers to entity #{id}, which hadn't been spawned yet")));
+                }
+
+                // Entities which had been destroyed still need an ID, which will never be used by
+                // an entity that exists
+                let new_id = *self.ids.entry(*id).or_insert_with(|| {
+                    self.next_new_id += 1;
+                    EntityId(self.next_new_id - 1)
+                });
+                Object::Entity(new_id)
+            },
+
+            SavedValue::EntityKind(name) => {
+                let Some(kind) = self.interpreter.entity_kinds.get(name) else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("saved game refers to entity declaration `{name}`, which no longer exists")));
+                };
+                Object::EntityKind(kind.clone())
+            },
+
+            SavedValue::Array(index) => {
+                let Some(array) = self.arrays.get(*index) else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::InvalidValue, format!("saved game refers to array {index}, which wasn't saved")));
+                };
+                Object::Array(array.clone())
+            },
+
+            SavedValue::Singleton(name) => {
+                let Some(singleton) = Interpreter::singleton(name) else {
+                    return Err(RuntimeError::new(RuntimeErrorKind::UndefinedIdentifier, format!("saved game refers to singleton `{name}`, which doesn't exist")));
+                };
+                singleton
+            },
+        })
+    }
+}
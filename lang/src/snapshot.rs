@@ -0,0 +1,189 @@
+use std::{cell::RefCell, collections::{BTreeMap, BTreeSet, HashMap, VecDeque}, rc::Rc};
+
+use rand::rngs::StdRng;
+
+use crate::{Entity, EntityId, InputReport, Interpreter, InterpreterResult, MelodyPlayback, Object, RuntimeError, RuntimeErrorKind};
+
+/// A copy of the running game's state, taken with [`Interpreter::snapshot`], which the same
+/// interpreter can go back to with [`Interpreter::restore`].
+///
+/// Unlike a [`crate::SaveState`], this is only kept in memory, so it's cheap enough to take one
+/// every tick.
+pub struct Snapshot {
+    entities: BTreeMap<EntityId, Entity>,
+    entities_by_kinds: HashMap<String, BTreeSet<EntityId>>,
+    next_entity_id: usize,
+    entities_pending_destroy: BTreeSet<EntityId>,
+    spawned_this_tick: BTreeSet<EntityId>,
+    pending_messages: Vec<(EntityId, String, Object)>,
+    playing_melodies: Vec<MelodyPlayback>,
+    globals: HashMap<String, Object>,
+    constants: HashMap<String, Object>,
+    camera: (f64, f64),
+    input_report: InputReport,
+    previous_input_report: InputReport,
+    tick_count: u64,
+    rng: StdRng,
+}
+
+impl Snapshot {
+    /// The number of ticks which had happened when this snapshot was taken.
+    pub fn tick_count(&self) -> u64 {
+        self.tick_count
+    }
+}
+
+impl Interpreter {
+    /// Takes a snapshot of the running game, which can be gone back to with
+    /// [`Interpreter::restore`]. This should be taken between ticks.
+    ///
+    /// The state of `Math`'s random number generator is included, so once a snapshot is restored,
+    /// the game will play out the same way it did after the snapshot was taken, as long as it's
+    /// given the same input. `Storage` isn't included, since the host has already persisted it.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut copier = ArrayCopier::default();
+        Snapshot {
+            entities: copier.copy_entities(&self.entities),
+            entities_by_kinds: self.entities_by_kinds.clone(),
+            next_entity_id: self.next_entity_id,
+            entities_pending_destroy: self.entities_pending_destroy.clone(),
+            spawned_this_tick: self.spawned_this_tick.clone(),
+            pending_messages: copier.copy_messages(&self.pending_messages),
+            playing_melodies: self.playing_melodies.clone(),
+            globals: copier.copy_map(&self.globals),
+            constants: copier.copy_map(&self.constants),
+            camera: self.camera,
+            input_report: self.input_report.clone(),
+            previous_input_report: self.previous_input_report.clone(),
+            tick_count: self.tick_count,
+            rng: self.rng.clone(),
+        }
+    }
+
+    /// Puts the game back into the state it was in when `snapshot` was taken. The snapshot is left
+    /// as it is, so it can be restored again later.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> InterpreterResult {
+        if self.ticking {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidContext, "cannot restore a snapshot during a tick"));
+        }
+
+        self.clear_game_state();
+
+        let mut copier = ArrayCopier::default();
+        self.entities = copier.copy_entities(&snapshot.entities);
+        self.entities_by_kinds = snapshot.entities_by_kinds.clone();
+        self.next_entity_id = snapshot.next_entity_id;
+        self.entities_pending_destroy = snapshot.entities_pending_destroy.clone();
+        self.spawned_this_tick = snapshot.spawned_this_tick.clone();
+        self.pending_messages = copier.copy_messages(&snapshot.pending_messages);
+        self.playing_melodies = snapshot.playing_melodies.clone();
+        self.globals = copier.copy_map(&snapshot.globals);
+        self.constants = copier.copy_map(&snapshot.constants);
+        self.camera = snapshot.camera;
+        self.input_report = snapshot.input_report.clone();
+        self.previous_input_report = snapshot.previous_input_report.clone();
+        self.tick_count = snapshot.tick_count;
+        self.rng = snapshot.rng.clone();
+        Ok(())
+    }
+}
+
+/// Copies objects so that changes to arrays in the copy don't affect the original, or the other
+/// way around. Arrays which were shared by the original are shared by the copy too.
+#[derive(Default)]
+pub(crate) struct ArrayCopier {
+    /// Copies of the arrays which have been copied so far, keyed by the original
+    copies: HashMap<*const RefCell<Vec<Object>>, Rc<RefCell<Vec<Object>>>>,
+}
+
+impl ArrayCopier {
+    fn copy(&mut self, object: &Object) -> Object {
+        let Object::Array(items) = object else {
+            return object.clone();
+        };
+        if let Some(copy) = self.copies.get(&Rc::as_ptr(items)) {
+            return Object::Array(copy.clone());
+        }
+
+        // Record the copy before copying the items, in case the array contains itself
+        let copy = Rc::new(RefCell::new(vec![]));
+        self.copies.insert(Rc::as_ptr(items), copy.clone());
+        let copied_items = items.borrow().iter().map(|item| self.copy(item)).collect();
+        *copy.borrow_mut() = copied_items;
+        Object::Array(copy)
+    }
+
+    pub(crate) fn copy_map(&mut self, objects: &HashMap<String, Object>) -> HashMap<String, Object> {
+        objects.iter().map(|(name, object)| (name.clone(), self.copy(object))).collect()
+    }
+
+    fn copy_messages(&mut self, messages: &[(EntityId, String, Object)]) -> Vec<(EntityId, String, Object)> {
+        messages.iter().map(|(id, name, arguments)| (*id, name.clone(), self.copy(arguments))).collect()
+    }
+
+    fn copy_entities(&mut self, entities: &BTreeMap<EntityId, Entity>) -> BTreeMap<EntityId, Entity> {
+        entities.iter()
+            .map(|(id, entity)| (*id, Entity {
+                kind: entity.kind.clone(),
+                ivars: self.copy_map(&entity.ivars),
+                last_drawn_size: entity.last_drawn_size,
+                asleep: entity.asleep,
+            }))
+            .collect()
+    }
+}
+
+/// The most recent snapshots of a game, for going back in time. Once it's full, adding another
+/// snapshot forgets the oldest one.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+}
+
+impl SnapshotHistory {
+    /// An empty history which keeps up to `capacity` snapshots.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { snapshots: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Adds a snapshot as the most recent one.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Removes and returns the most recent snapshot. Calling this once per tick and restoring the
+    /// result plays the game backwards.
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        self.snapshots.pop_back()
+    }
+
+    /// The snapshot taken `steps_back` snapshots before the most recent one, so `0` is the most
+    /// recent, or `None` if the history doesn't go back that far.
+    pub fn get(&self, steps_back: usize) -> Option<&Snapshot> {
+        self.snapshots.len().checked_sub(steps_back + 1).and_then(|index| self.snapshots.get(index))
+    }
+
+    /// Forgets the `steps_back` most recent snapshots, such as after restoring an older one, so
+    /// that the history carries on from there.
+    pub fn discard(&mut self, steps_back: usize) {
+        self.snapshots.truncate(self.snapshots.len().saturating_sub(steps_back));
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
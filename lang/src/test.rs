@@ -1,4 +1,29 @@
-use crate::{BinaryOperator, Declaration, Expression, Interpreter, Object, Statement};
+use std::{collections::{BTreeMap, HashMap}, rc::Rc};
+
+use crate::{BinaryOperator, DebugMessage, Declaration, DisplayConfig, EntityId, Expression, InputReport, Interpreter, InterpreterResult, Note, NoteName, Object, Pixel, RuntimeErrorKind, SaveState, SavedValue, SnapshotHistory, Sprite, Statement, TimeConfig, Tone, parse, parse_file_reporting_all, parse_reporting_all};
+
+/// Evaluates a single expression inside an entity constructor, and returns the result
+fn evaluate(expr: &str) -> InterpreterResult<Object> {
+    let source = format!("
+        entity Result {{
+            var @value;
+            constructor {{ @value = {expr}; }}
+        }}
+        constructor {{ spawn Result; }}
+    ");
+    let interpreter = run(&source)?;
+
+    let result = interpreter.entities().next().unwrap();
+    Ok(result.ivars["value"].clone())
+}
+
+/// Loads a program and executes its top-level constructor
+fn run(source: &str) -> InterpreterResult<Interpreter> {
+    let declarations = parse(source).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations)?;
+    interpreter.execute_init()?;
+    Ok(interpreter)
+}
 
 #[test]
 fn test_basic_interpreter() {
@@ -7,7 +32,7 @@ fn test_basic_interpreter() {
     interpreter.interpret_declaration(&Declaration::EntityDeclaration {
         name: "Player".to_owned(),
         body: vec![
-            Declaration::InstanceVarDeclaration { names: vec!["score".to_owned()] },
+            Declaration::InstanceVarDeclaration { ivars: vec![("score".to_owned(), None)] },
             Declaration::ConstructorDeclaration { body: vec![
                 Statement::Assignment {
                     target: Expression::InstanceVarIdentifier("score".to_owned()),
@@ -34,7 +59,7 @@ fn test_basic_interpreter() {
     interpreter.interpret_declaration(&Declaration::ConstructorDeclaration { body: vec![
         Statement::Assignment {
             target: Expression::Identifier("plyr".to_owned()),
-            value: Expression::AddEntity { name: "Player".to_owned() },
+            value: Expression::SpawnEntity { name: "Player".to_owned(), initializers: vec![] },
         },
         Statement::Expression(
             Expression::FunctionCall {
@@ -61,3 +86,3487 @@ fn test_basic_interpreter() {
     assert_eq!(player.ivars.len(), 1);
     assert_eq!(player.ivars["score"], Object::Number(2.0));
 }
+
+#[test]
+fn test_in_operator() {
+    assert_eq!(evaluate("2 in [1, 2, 3]").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("4 in [1, 2, 3]").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("1 + 1 in [2]").unwrap(), Object::Boolean(true));
+    assert!(evaluate("1 in 1").is_err());
+}
+
+#[test]
+fn test_global() {
+    let mut interpreter = run(r#"
+        entity Scorer {
+            tick { Global.set("score", Global.get("score") + 1); }
+        }
+        constructor {
+            Global.set("score", 0);
+            spawn Scorer;
+        }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+
+    assert_eq!(interpreter.globals["score"], Object::Number(2.0));
+    assert_eq!(evaluate(r#"Global.get("missing")"#).unwrap(), Object::Null);
+}
+
+#[test]
+fn test_comparison_operators() {
+    assert_eq!(evaluate("1 == 1").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("1 != 1").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("1 < 2").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("1 > 2").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("2 <= 2").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("1 >= 2").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("1 <= 2 == true").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("3 >= 2 != false").unwrap(), Object::Boolean(true));
+}
+
+#[test]
+fn test_sound_pitch_bend() {
+    let Object::Sound(tone) = evaluate("sound { 0.5: C -> A }").unwrap() else { panic!() };
+    let (c4, a4) = (Note::new(NoteName::C, false, 4), Note::new(NoteName::A, false, 4));
+    assert_eq!(tone, Tone { note: c4, duration: 0.5, end_note: Some(a4), volume: 1.0 });
+    assert_eq!(tone.frequency_at(0.0), c4.frequency());
+    assert_eq!(tone.frequency_at(1.0), a4.frequency());
+    assert!(tone.frequency_at(0.5) > c4.frequency() && tone.frequency_at(0.5) < a4.frequency());
+
+    let Object::Sound(tone) = evaluate("sound { 0.5: C }").unwrap() else { panic!() };
+    assert_eq!(tone.frequency_at(1.0), c4.frequency());
+}
+
+#[test]
+fn test_host_functions() {
+    let declarations = parse(r#"
+        constructor { Global.set("result", Host.double(21)); }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.register_function("double", Box::new(|_, arguments| {
+        let [Object::Number(n)] = arguments.as_slice() else { panic!() };
+        Ok(Object::Number(n * 2.0))
+    }));
+    interpreter.execute_init().unwrap();
+
+    assert_eq!(interpreter.globals["result"], Object::Number(42.0));
+}
+
+#[test]
+fn test_identifiers_starting_with_keywords() {
+    let interpreter = run(r#"
+        constructor {
+            thisPlayer = 1;
+            iframe = 2;
+            eachness = thisPlayer + iframe;
+            truex = eachness;
+            nullable = truex;
+            returned = nullable;
+            Global.set("result", returned);
+        }
+    "#).unwrap();
+
+    assert_eq!(interpreter.globals["result"], Object::Number(3.0));
+}
+
+#[test]
+fn test_parse_error_position() {
+    let err = parse("entity {}").unwrap_err();
+    assert_eq!((err.line, err.column), (1, 8));
+    assert_eq!(err.snippet, "entity {}");
+
+    let err = parse("entity A {}\n\nentity B {\n    var x;\n}").unwrap_err();
+    assert_eq!((err.line, err.column), (4, 9));
+    assert_eq!(err.snippet, "    var x;");
+}
+
+#[test]
+fn test_parse_error_in_middle_declaration() {
+    let err = parse("
+        entity A {
+            tick { @x = 1; }
+        }
+        entity B {
+            tick {
+                @x = 1;
+                @y = ;
+            }
+        }
+        entity C {
+            tick { @x = 1; }
+        }
+    ").unwrap_err();
+    assert_eq!((err.line, err.column), (8, 22));
+    assert_eq!(err.expected, "expression");
+    assert_eq!(err.found, ";");
+}
+
+#[test]
+fn test_implicit_ivar_getters() {
+    let mut interpreter = run(r#"
+        entity Wall {
+            var @x, @y;
+            constructor { @x = 5; @y = 3; }
+        }
+        entity Player {
+            var @x, @y, @blocked;
+            constructor { @x = 5; @y = 3; @blocked = false; }
+            tick {
+                each wall in (Wall.all()) {
+                    if (wall.x() == @x && wall.y() == @y) {
+                        @blocked = true;
+                    }
+                }
+            }
+            func position() { return [@x, @y]; }
+        }
+        constructor {
+            spawn Wall;
+            player = spawn Player;
+            Global.set("player", player);
+        }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+
+    let Object::Entity(player) = interpreter.globals["player"] else { panic!() };
+    assert_eq!(interpreter.entities[&player].ivars["blocked"], Object::Boolean(true));
+
+    let err = evaluate("this.z()").unwrap_err();
+    assert!(err.to_string().contains("no function or instance variable named `z`"));
+}
+
+#[test]
+fn test_foreign_ivar_assignment() {
+    let mut interpreter = run(r#"
+        entity Enemy {
+            var @x;
+            constructor { @x = 10; }
+        }
+        entity Player {
+            tick {
+                each enemy in (Enemy.all()) {
+                    enemy@x = enemy@x - 2;
+                }
+            }
+        }
+        constructor {
+            Global.set("enemy", spawn Enemy);
+            spawn Player;
+        }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+
+    let Object::Entity(enemy) = interpreter.globals["enemy"] else { panic!() };
+    assert_eq!(interpreter.entities[&enemy].ivars["x"], Object::Number(6.0));
+
+    let err = evaluate("this@y").unwrap_err();
+    assert!(err.to_string().contains("undeclared instance variable `y`"));
+
+    // Destroyed entities give an error rather than a panic
+    let mut interpreter = run(r#"
+        entity Enemy {
+            var @x;
+            tick { destroy this; }
+        }
+        entity Player {
+            var @target;
+            constructor { @target = spawn Enemy; }
+            tick { @target@x = 1; }
+        }
+        constructor { spawn Player; }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    let err = interpreter.execute_tick().unwrap_err();
+    assert!(err.to_string().contains("no longer exists"));
+}
+
+#[test]
+fn test_match_statement() {
+    let interpreter = run(r#"
+        entity Machine {
+            func describe(state) {
+                match (state) {
+                    0 { return "idle"; }
+                    1 { return "running"; }
+                    "done" { return "finished"; }
+                    else { return "unknown"; }
+                }
+                return "unreachable";
+            }
+
+            func no_else(state) {
+                match (state) {
+                    0 { return "zero"; }
+                }
+                return "fell through";
+            }
+        }
+        constructor {
+            m = spawn Machine;
+            Global.set("results", [m.describe(0), m.describe(1), m.describe("done"), m.describe(5), m.no_else(1)]);
+        }
+    "#).unwrap();
+
+    assert_eq!(interpreter.globals["results"], Object::array(vec![
+        Object::String("idle".to_owned()),
+        Object::String("running".to_owned()),
+        Object::String("finished".to_owned()),
+        Object::String("unknown".to_owned()),
+        Object::String("fell through".to_owned()),
+    ]));
+}
+
+#[test]
+fn test_echo_multiple() {
+    assert_eq!(evaluate("echo 1 + 1").unwrap(), Object::Number(2.0));
+    assert_eq!(evaluate(r#"echo "x:", 1, "y:", 2"#).unwrap(), Object::Null);
+}
+
+#[test]
+fn test_ivar_defaults() {
+    let interpreter = run(r#"
+        entity Player {
+            var @x = 2, @lives = 3;
+            var @double_lives = @lives * 2, @later;
+            var @lives_before_constructor;
+            constructor {
+                @lives_before_constructor = @lives;
+                @lives = 1;
+            }
+        }
+        constructor { spawn Player; }
+    "#).unwrap();
+
+    let player = interpreter.entities().next().unwrap();
+    assert_eq!(player.ivars["x"], Object::Number(2.0));
+    assert_eq!(player.ivars["lives"], Object::Number(1.0));
+    assert_eq!(player.ivars["double_lives"], Object::Number(6.0));
+    assert_eq!(player.ivars["later"], Object::Null);
+    assert_eq!(player.ivars["lives_before_constructor"], Object::Number(3.0));
+}
+
+#[test]
+fn test_ranges() {
+    // Encodes the iterated values as digits, e.g. [2, 3, 4] becomes 234
+    fn digits(range: &str) -> Object {
+        run(&format!("
+            constructor {{
+                digits = 0;
+                each i in ({range}) {{ digits = digits * 10 + i; }}
+                Global.set(\"digits\", digits);
+            }}
+        ")).unwrap().globals["digits"].clone()
+    }
+
+    assert_eq!(digits("2 to 5"), Object::Number(234.0));
+    assert_eq!(digits("5 to 5"), Object::Number(0.0));
+    assert_eq!(digits("5 to 2"), Object::Number(0.0));
+    assert_eq!(digits("0 to 10 by 4"), Object::Number(48.0));
+    assert_eq!(digits("3 to 0 by -1"), Object::Number(321.0));
+    assert_eq!(digits("0.6 to 3.5"), Object::Number(123.0));
+    assert!(evaluate("0 to 5 by 0").is_err());
+
+    // Steps which would go past the largest integer end the loop instead
+    assert_eq!(digits("1 to 10 by 9e18"), Object::Number(1.0));
+    assert_eq!(digits("0 to 0 - 9e18 by 0 - 9e18"), Object::Number(0.0));
+    for source in ["1 to 10 by 1e19", "0 - 1e19 to 0 by 0 - 1e19", "0 to 1e400"] {
+        let Err(err) = evaluate(source) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+        assert!(err.message().contains("is too large"));
+    }
+}
+
+#[test]
+fn test_power() {
+    let declarations = parse("const X = 2 ^ 3 ^ 2;").unwrap();
+    let [Declaration::ConstDeclaration { value, .. }] = declarations.as_slice() else { panic!() };
+    let Expression::BinaryOperation { left, right, operator: BinaryOperator::Power } = value else { panic!() };
+    assert!(matches!(**left, Expression::NumberLiteral(2.0)));
+    assert!(matches!(**right, Expression::BinaryOperation { operator: BinaryOperator::Power, .. }));
+
+    assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), Object::Number(512.0));
+    assert_eq!(evaluate("2 * 3 ^ 2").unwrap(), Object::Number(18.0));
+    assert_eq!(evaluate("Math.pow(9, 0.5)").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("Math.pow(-2, 3)").unwrap(), Object::Number(-8.0));
+    assert!(evaluate("-8 ^ 0.5").unwrap_err().to_string().contains("fractional power"));
+    assert!(evaluate("Math.pow(-8, 0.5)").is_err());
+}
+
+#[test]
+fn test_rounding() {
+    // Negative numbers are where these differ from each other
+    assert_eq!(evaluate("Math.floor(-2.3)").unwrap(), Object::Number(-3.0));
+    assert_eq!(evaluate("Math.ceil(-2.7)").unwrap(), Object::Number(-2.0));
+    assert_eq!(evaluate("Math.trunc(-2.7)").unwrap(), Object::Number(-2.0));
+    assert_eq!(evaluate("Math.round(-2.7)").unwrap(), Object::Number(-3.0));
+    assert_eq!(evaluate("Math.round(-2.3)").unwrap(), Object::Number(-2.0));
+    assert_eq!(evaluate("Math.floor(2.7)").unwrap(), Object::Number(2.0));
+    assert_eq!(evaluate("Math.ceil(2.3)").unwrap(), Object::Number(3.0));
+
+    assert_eq!(evaluate("Math.sign(-4.5)").unwrap(), Object::Number(-1.0));
+    assert_eq!(evaluate("Math.sign(0)").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("Math.sign(0 - 0)").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("Math.sign(12)").unwrap(), Object::Number(1.0));
+
+    assert!(evaluate("Math.floor(null)").unwrap_err().to_string().contains("arguments to `Math.floor` must be numbers"));
+}
+
+#[test]
+fn test_min_max_clamp_lerp() {
+    assert_eq!(evaluate("Math.min(3, 1)").unwrap(), Object::Number(1.0));
+    assert_eq!(evaluate("Math.max(3, 1)").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("Math.min(4, -2, 7, 0)").unwrap(), Object::Number(-2.0));
+    assert_eq!(evaluate("Math.max(4, -2, 7, 0)").unwrap(), Object::Number(7.0));
+    assert!(evaluate("Math.min(1)").unwrap_err().to_string().contains("at least 2 arguments, but 1 were provided"));
+    assert!(evaluate("Math.max(1, true)").unwrap_err().to_string().contains("arguments to `Math.max` must be numbers"));
+
+    assert_eq!(evaluate("Math.clamp(-5, 0, 10)").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("Math.clamp(5, 0, 10)").unwrap(), Object::Number(5.0));
+    assert_eq!(evaluate("Math.clamp(15, 0, 10)").unwrap(), Object::Number(10.0));
+    assert!(evaluate("Math.clamp(5, 10, 0)").unwrap_err().to_string().contains("greater than upper bound"));
+
+    // NaN can't be made by the language itself, but could be given by the host
+    let mut interpreter = Interpreter::with_declarations(&[]).unwrap();
+    for bounds in [(f64::NAN, 10.0), (0.0, f64::NAN)] {
+        let arguments = vec![Object::Number(5.0), Object::Number(bounds.0), Object::Number(bounds.1)];
+        let Err(err) = Object::MathSingleton.call_function(&mut interpreter, "clamp", arguments) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+        assert!(err.message().contains("must be numbers"));
+    }
+
+    assert_eq!(evaluate("Math.lerp(10, 20, 0)").unwrap(), Object::Number(10.0));
+    assert_eq!(evaluate("Math.lerp(10, 20, 0.5)").unwrap(), Object::Number(15.0));
+    assert_eq!(evaluate("Math.lerp(10, 20, 1)").unwrap(), Object::Number(20.0));
+}
+
+#[test]
+fn test_sqrt_distance() {
+    assert_eq!(evaluate("Math.sqrt(16)").unwrap(), Object::Number(4.0));
+    assert_eq!(evaluate("Math.sqrt(0)").unwrap(), Object::Number(0.0));
+    assert!(evaluate("Math.sqrt(0 - 4)").unwrap_err().to_string().contains("cannot take square root of negative number -4"));
+
+    assert_eq!(evaluate("Math.distance(1, 2, 4, 6)").unwrap(), Object::Number(5.0));
+    assert_eq!(evaluate("Math.distance(4, 6, 1, 2)").unwrap(), Object::Number(5.0));
+    assert!(evaluate("Math.distance(1, 2, 3)").unwrap_err().to_string().contains("4 parameters, but 3 arguments"));
+}
+
+#[test]
+fn test_seeded_random() {
+    let interpreter = run(r#"
+        entity Result {
+            var @first, @second, @other_seed;
+            constructor {
+                Math.seed(1234);
+                @first = this.sequence();
+                Math.seed(1234);
+                @second = this.sequence();
+                Math.seed(99);
+                @other_seed = this.sequence();
+            }
+
+            func sequence() {
+                return [Math.random(), Math.random_int(1, 1000), Math.random_range(5, 10), Math.random()];
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().next().unwrap();
+
+    assert_eq!(result.ivars["first"], result.ivars["second"]);
+    assert_ne!(result.ivars["first"], result.ivars["other_seed"]);
+
+    let Object::Array(first) = &result.ivars["first"] else { panic!() };
+    let first = first.borrow();
+
+    let [Object::Number(float), Object::Number(int), Object::Number(ranged), Object::Number(_)] = first.as_slice() else { panic!() };
+    assert!((0.0..1.0).contains(float));
+    assert!((1.0..=1000.0).contains(int) && int.fract() == 0.0);
+    assert!((5.0..10.0).contains(ranged));
+
+    assert_eq!(evaluate("Math.random_range(3, 3)").unwrap(), Object::Number(3.0));
+    assert!(evaluate("Math.random_range(3, 2)").unwrap_err().to_string().contains("greater than end"));
+    for source in ["Math.random_range(0, 1e400)", "Math.random_range(0 - 1e308, 1e308)"] {
+        let Err(err) = evaluate(source) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+        assert!(err.message().contains("needs a finite range"));
+    }
+    assert!(evaluate("Math.random_int(3, 2)").unwrap_err().to_string().contains("greater than end"));
+}
+
+#[test]
+fn test_trigonometry() {
+    let number = |expr| match evaluate(expr).unwrap() {
+        Object::Number(n) => n,
+        other => panic!("expected number, got {other:?}"),
+    };
+
+    assert!(number("Math.sin(Math.pi())").abs() < 1e-9);
+    assert!((number("Math.cos(Math.pi())") + 1.0).abs() < 1e-9);
+    assert!((number("Math.tan(Math.pi() / 4)") - 1.0).abs() < 1e-9);
+
+    // One point in each quadrant
+    let pi = std::f64::consts::PI;
+    assert!((number("Math.atan2(1, 1)") - pi / 4.0).abs() < 1e-9);
+    assert!((number("Math.atan2(1, -1)") - 3.0 * pi / 4.0).abs() < 1e-9);
+    assert!((number("Math.atan2(-1, -1)") + 3.0 * pi / 4.0).abs() < 1e-9);
+    assert!((number("Math.atan2(-1, 1)") + pi / 4.0).abs() < 1e-9);
+
+    assert!(evaluate("Math.pi(1)").unwrap_err().to_string().contains("0 parameters, but 1 arguments"));
+    assert!(evaluate("Math.atan2(1)").unwrap_err().to_string().contains("2 parameters, but 1 arguments"));
+    assert!(evaluate("Math.sin(\"a\")").unwrap_err().to_string().contains("arguments to `Math.sin` must be numbers"));
+}
+
+#[test]
+fn test_array_length() {
+    assert_eq!(evaluate("[].length()").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("[].is_empty()").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("[1, 2, 3].length()").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("[1, 2, 3].is_empty()").unwrap(), Object::Boolean(false));
+    assert!(evaluate("[1].length(1)").unwrap_err().to_string().contains("0 parameters, but 1 arguments"));
+    assert!(evaluate("[1].size()").unwrap_err().to_string().contains("array has no function named `size`"));
+
+    let interpreter = run(r#"
+        entity Enemy {}
+        entity Counter {
+            var @enemies, @count, @none;
+            constructor {
+                @enemies = Enemy.all();
+                @count = @enemies.length();
+                @none = @enemies.is_empty();
+            }
+        }
+        constructor {
+            spawn Enemy;
+            spawn Enemy;
+            spawn Counter;
+        }
+    "#).unwrap();
+    let counter = interpreter.entities().find(|e| e.ivars.contains_key("count")).unwrap();
+    assert_eq!(counter.ivars["count"], Object::Number(2.0));
+    assert_eq!(counter.ivars["none"], Object::Boolean(false));
+}
+
+#[test]
+fn test_array_mutation() {
+    let mut interpreter = run(r#"
+        entity Trail {
+            var @trail = [], @popped;
+            tick {
+                @trail.push(@trail.length());
+            }
+        }
+        constructor { spawn Trail; }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+    let trail = interpreter.entities().next().unwrap();
+    assert_eq!(trail.ivars["trail"], Object::array(vec![Object::Number(0.0), Object::Number(1.0)]));
+
+    let interpreter = run(r#"
+        entity Result {
+            var @items = [1, 2], @popped, @empty_popped, @removed, @other;
+            constructor {
+                items = [];
+                items.push(3);
+                @empty_popped = [].pop();
+                @popped = @items.pop();
+                @items.insert(0, 5);
+                @items.insert(2, 7);
+                @removed = @items.remove_at(1);
+
+                @other = spawn Result2;
+                @other@list.push(1);
+            }
+        }
+        entity Result2 {
+            var @list = [];
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().find(|e| e.kind.name == "Result").unwrap();
+    assert_eq!(result.ivars["popped"], Object::Number(2.0));
+    assert_eq!(result.ivars["empty_popped"], Object::Null);
+    assert_eq!(result.ivars["removed"], Object::Number(1.0));
+    assert_eq!(result.ivars["items"], Object::array(vec![Object::Number(5.0), Object::Number(7.0)]));
+    let other = interpreter.entities().find(|e| e.kind.name == "Result2").unwrap();
+    assert_eq!(other.ivars["list"], Object::array(vec![Object::Number(1.0)]));
+
+    assert!(evaluate("[1, 2].remove_at(2)").unwrap_err().to_string().contains("array index 2 is out of bounds for array of length 2"));
+    assert!(evaluate("[1, 2].insert(0.5, 1)").unwrap_err().to_string().contains("out of bounds"));
+    // A const always refers to the same array, but that array's items can change
+    let interpreter = run("const XS = []; constructor { XS.push(1); Global.set(\"xs\", XS); }").unwrap();
+    assert_eq!(interpreter.globals["xs"], Object::array(vec![Object::Number(1.0)]));
+    let Err(err) = run("const XS = []; constructor { XS = [1]; }") else { panic!() };
+    assert!(err.to_string().contains("cannot reassign const `XS`"));
+}
+
+#[test]
+fn test_array_search() {
+    assert_eq!(evaluate("[3, 1, 3].contains(3)").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("[3, 1, 3].contains(2)").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("[3, 1, 3].index_of(3)").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("[3, 1, 3].index_of(1)").unwrap(), Object::Number(1.0));
+    assert_eq!(evaluate("[3, 1, 3].index_of(2)").unwrap(), Object::Null);
+
+    let mut interpreter = run(r#"
+        entity Enemy {}
+        entity Targeter {
+            var @targets, @first, @second, @removed, @removed_again, @found_destroyed;
+            constructor {
+                @first = spawn Enemy;
+                @second = spawn Enemy;
+                @targets = [@first, @second, @first];
+                @removed = @targets.remove_value(@first);
+                @removed_again = @targets.remove_value(this);
+                destroy @second;
+            }
+            tick {
+                @found_destroyed = @targets.contains(@second);
+            }
+        }
+        constructor { spawn Targeter; }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+
+    let targeter = interpreter.entities().find(|e| e.kind.name == "Targeter").unwrap();
+    let (first, second) = (targeter.ivars["first"].clone(), targeter.ivars["second"].clone());
+    assert_eq!(targeter.ivars["targets"], Object::array(vec![second, first]));
+    assert_eq!(targeter.ivars["removed"], Object::Boolean(true));
+    assert_eq!(targeter.ivars["removed_again"], Object::Boolean(false));
+
+    // Entities are compared by identity, so a destroyed entity is still found
+    assert_eq!(targeter.ivars["found_destroyed"], Object::Boolean(true));
+}
+
+#[test]
+fn test_array_sorting() {
+    let numbers = |ns: &[f64]| Object::array(ns.iter().map(|n| Object::Number(*n)).collect());
+    assert_eq!(evaluate("[3, 0 - 1, 10, 2.5, 3].sort()").unwrap(), numbers(&[-1.0, 2.5, 3.0, 3.0, 10.0]));
+    assert_eq!(evaluate("[1, 2, 3].reverse()").unwrap(), numbers(&[3.0, 2.0, 1.0]));
+    assert_eq!(evaluate("[].sort()").unwrap(), numbers(&[]));
+    assert!(evaluate("[1, true].sort()").unwrap_err().to_string().contains("`sort` only works on arrays of numbers, but array contains true"));
+
+    let interpreter = run(r#"
+        entity Enemy {
+            var @y;
+        }
+        entity Result {
+            var @original, @sorted, @sorted_ys, @reversed;
+            constructor {
+                @original = [spawn Enemy with { y: 20 }, spawn Enemy with { y: 5 }, spawn Enemy with { y: 12 }];
+                @sorted = @original.sort_by("y");
+                @reversed = @sorted.reverse();
+                @sorted_ys = [];
+                each enemy in (@sorted) {
+                    @sorted_ys.push(enemy@y);
+                }
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().find(|e| e.kind.name == "Result").unwrap();
+    assert_eq!(result.ivars["sorted_ys"], numbers(&[5.0, 12.0, 20.0]));
+    let Object::Array(original) = &result.ivars["original"] else { panic!() };
+    let original = original.borrow();
+    assert_eq!(result.ivars["sorted"], Object::array(vec![original[1].clone(), original[2].clone(), original[0].clone()]));
+    assert_eq!(result.ivars["reversed"], Object::array(vec![original[0].clone(), original[2].clone(), original[1].clone()]));
+
+    assert!(evaluate("[1].sort_by(\"y\")").unwrap_err().to_string().contains("can only sort entities"));
+}
+
+#[test]
+fn test_array_concat_slice_join() {
+    let numbers = |ns: &[f64]| Object::array(ns.iter().map(|n| Object::Number(*n)).collect());
+    assert_eq!(evaluate("[1, 2].concat([3])").unwrap(), numbers(&[1.0, 2.0, 3.0]));
+    assert_eq!(evaluate("[].concat([3])").unwrap(), numbers(&[3.0]));
+    assert_eq!(evaluate("[1].concat([])").unwrap(), numbers(&[1.0]));
+    assert!(evaluate("[1].concat(2)").unwrap_err().to_string().contains("must be an array"));
+
+    assert_eq!(evaluate("[1, 2, 3, 4].slice(1, 3)").unwrap(), numbers(&[2.0, 3.0]));
+    assert_eq!(evaluate("[1, 2, 3, 4].slice(2, 100)").unwrap(), numbers(&[3.0, 4.0]));
+    assert_eq!(evaluate("[1, 2, 3, 4].slice(0 - 5, 1)").unwrap(), numbers(&[1.0]));
+    assert_eq!(evaluate("[1, 2, 3, 4].slice(10, 20)").unwrap(), numbers(&[]));
+    assert_eq!(evaluate("[1, 2, 3, 4].slice(3, 1)").unwrap(), numbers(&[]));
+    assert_eq!(evaluate("[].slice(0, 1)").unwrap(), numbers(&[]));
+
+    assert_eq!(evaluate(r#"[1, "a", true, null].join(", ")"#).unwrap(), Object::String("1, a, true, null".to_owned()));
+    assert_eq!(evaluate(r#"[].join(", ")"#).unwrap(), Object::String("".to_owned()));
+}
+
+#[test]
+fn test_array_aggregates() {
+    assert_eq!(evaluate("[4, 1, 7].sum()").unwrap(), Object::Number(12.0));
+    assert_eq!(evaluate("[4, 1, 7].min()").unwrap(), Object::Number(1.0));
+    assert_eq!(evaluate("[4, 1, 7].max()").unwrap(), Object::Number(7.0));
+    assert_eq!(evaluate("[4, 1, 7].average()").unwrap(), Object::Number(4.0));
+
+    assert_eq!(evaluate("[].sum()").unwrap(), Object::Number(0.0));
+    assert_eq!(evaluate("[].min()").unwrap(), Object::Null);
+    assert_eq!(evaluate("[].max()").unwrap(), Object::Null);
+    assert_eq!(evaluate("[].average()").unwrap(), Object::Null);
+
+    assert!(evaluate(r#"[1, "2"].sum()"#).unwrap_err().to_string().contains("`sum` only works on arrays of numbers"));
+    assert!(evaluate("[null].min()").unwrap_err().to_string().contains("`min` only works on arrays of numbers"));
+}
+
+#[test]
+fn test_array_shuffle_choice() {
+    let source = r#"
+        entity Result {
+            var @shuffled, @choices;
+            constructor {
+                Math.seed(7);
+                @shuffled = [1, 2, 3, 4, 5, 6, 7, 8].shuffle();
+                @choices = [];
+                each _ in (10) {
+                    @choices.push([10, 20, 30].choice());
+                }
+            }
+        }
+        constructor { spawn Result; }
+    "#;
+    let first = run(source).unwrap();
+    let first = first.entities().next().unwrap();
+    let second = run(source).unwrap();
+    let second = second.entities().next().unwrap();
+    assert_eq!(first.ivars["shuffled"], second.ivars["shuffled"]);
+    assert_eq!(first.ivars["choices"], second.ivars["choices"]);
+
+    // Shuffling only reorders the items
+    let Object::Array(shuffled) = &first.ivars["shuffled"] else { panic!() };
+    let shuffled = shuffled.borrow();
+    let mut sorted = shuffled.iter().map(|n| match n { Object::Number(n) => *n, _ => panic!() }).collect::<Vec<_>>();
+    sorted.sort_by(f64::total_cmp);
+    assert_eq!(sorted, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+
+    let Object::Array(choices) = &first.ivars["choices"] else { panic!() };
+    let choices = choices.borrow();
+    assert!(choices.iter().all(|c| [10.0, 20.0, 30.0].map(Object::Number).contains(c)));
+
+    assert_eq!(evaluate("[].choice()").unwrap(), Object::Null);
+    assert_eq!(evaluate("[].shuffle()").unwrap(), Object::array(vec![]));
+}
+
+#[test]
+fn test_destroy_all() {
+    let mut interpreter = run(r#"
+        entity Bullet {
+            on_destroy {
+                Global.set("destroyed", Global.get("destroyed") + 1);
+            }
+        }
+        entity Wave {
+            var @count, @second_count;
+            tick {
+                @count = Bullet.destroy_all();
+                @second_count = Bullet.destroy_all();
+            }
+        }
+        constructor {
+            Global.set("destroyed", 0);
+            each _ in (5) {
+                spawn Bullet;
+            }
+            spawn Wave;
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.entities().filter(|e| e.kind.name == "Bullet").count(), 5);
+
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.entities().filter(|e| e.kind.name == "Bullet").count(), 0);
+    assert_eq!(interpreter.globals["destroyed"], Object::Number(5.0));
+
+    let wave = interpreter.entities().find(|e| e.kind.name == "Wave").unwrap();
+    assert_eq!(wave.ivars["count"], Object::Number(5.0));
+    assert_eq!(wave.ivars["second_count"], Object::Number(0.0));
+
+    interpreter.execute_tick().unwrap();
+    let wave = interpreter.entities().find(|e| e.kind.name == "Wave").unwrap();
+    assert_eq!(wave.ivars["count"], Object::Number(0.0));
+}
+
+#[test]
+fn test_entity_kind_introspection() {
+    let interpreter = run(r#"
+        entity Enemy {}
+        entity Pickup {
+            func kind() {
+                return "custom";
+            }
+        }
+        entity Sorter {
+            var @things, @enemies, @enemy_kind, @pickup_kind;
+            constructor {
+                @things = [spawn Enemy, spawn Pickup, spawn Enemy];
+                @enemies = [];
+                each thing in (@things) {
+                    if (thing.is_a(Enemy)) {
+                        @enemies.push(thing);
+                    }
+                }
+
+                @enemy_kind = (spawn Enemy).kind();
+                @pickup_kind = (spawn Pickup).kind();
+            }
+        }
+        constructor { spawn Sorter; }
+    "#).unwrap();
+    let sorter = interpreter.entities().find(|e| e.kind.name == "Sorter").unwrap();
+    let Object::Array(things) = &sorter.ivars["things"] else { panic!() };
+    let things = things.borrow();
+    assert_eq!(sorter.ivars["enemies"], Object::array(vec![things[0].clone(), things[2].clone()]));
+
+    let Object::EntityKind(enemy_kind) = &sorter.ivars["enemy_kind"] else { panic!() };
+    assert_eq!(enemy_kind.name, "Enemy");
+
+    // A declared function takes precedence over the built-in one
+    assert_eq!(sorter.ivars["pickup_kind"], Object::String("custom".to_owned()));
+
+    // So does an instance variable's implicit getter
+    let interpreter = run(r#"
+        entity Card {
+            var @kind = "spade", @alive = 3;
+        }
+        constructor {
+            card = spawn Card;
+            Global.set("kind", card.kind());
+            Global.set("alive", card.alive());
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["kind"], Object::String("spade".to_owned()));
+    assert_eq!(interpreter.globals["alive"], Object::Number(3.0));
+
+    let Err(err) = run("entity E {} constructor { (spawn E).is_a(1); }") else { panic!() };
+    assert!(err.to_string().contains("argument to `is_a` must be an entity declaration"));
+}
+
+#[test]
+fn test_stale_entity_references() {
+    let mut interpreter = run(r#"
+        entity Enemy {
+            var @hp = 3;
+            func hit() {
+                @hp = @hp - 1;
+            }
+        }
+        entity Targeter {
+            var @target, @target_alive, @ticks = 0;
+            constructor {
+                @target = spawn Enemy;
+            }
+            tick {
+                @target_alive = @target.alive();
+                if (@ticks == 0) {
+                    destroy @target;
+                }
+                if (@ticks == 2) {
+                    @target.hit();
+                }
+                @ticks = @ticks + 1;
+            }
+        }
+        constructor { spawn Targeter; }
+    "#).unwrap();
+    let target_alive = |interpreter: &Interpreter| interpreter.entities()
+        .find(|e| e.kind.name == "Targeter").unwrap()
+        .ivars["target_alive"].clone();
+
+    interpreter.execute_tick().unwrap();
+    assert_eq!(target_alive(&interpreter), Object::Boolean(true));
+    interpreter.execute_tick().unwrap();
+    assert_eq!(target_alive(&interpreter), Object::Boolean(false));
+
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert!(err.to_string().contains("cannot call function `hit` on an entity which no longer exists"));
+}
+
+#[test]
+fn test_entity_distance() {
+    let interpreter = run(r#"
+        entity Thing {
+            var @x, @y;
+        }
+        entity Result {
+            var @distance, @reverse_distance, @point_distance;
+            constructor {
+                a = spawn Thing with { x: 1, y: 2 };
+                b = spawn Thing with { x: 4, y: 6 };
+                @distance = a.distance_to(b);
+                @reverse_distance = b.distance_to(a);
+                @point_distance = a.distance_to_point(13, 7);
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().find(|e| e.kind.name == "Result").unwrap();
+    assert_eq!(result.ivars["distance"], Object::Number(5.0));
+    assert_eq!(result.ivars["reverse_distance"], Object::Number(5.0));
+    assert_eq!(result.ivars["point_distance"], Object::Number(13.0));
+
+    let Err(err) = run(r#"
+        entity Thing { var @x = 0, @y = 0; }
+        entity Marker {}
+        constructor { (spawn Thing).distance_to(spawn Marker); }
+    "#) else { panic!() };
+    assert!(err.to_string().contains("`distance_to` needs entity `Marker` to have numeric instance variables `x` and `y`"));
+}
+
+#[test]
+fn test_entity_overlaps() {
+    let interpreter = run(r#"
+        entity Box {
+            var @x, @y, @width = 4, @height = 4;
+        }
+        entity Result {
+            var @touching, @overlapping, @diagonal;
+            constructor {
+                a = spawn Box with { x: 0, y: 0 };
+                @touching = a.overlaps(spawn Box with { x: 4, y: 0 });
+                @overlapping = a.overlaps(spawn Box with { x: 3, y: 0 });
+                @diagonal = a.overlaps(spawn Box with { x: 3, y: 4 });
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().find(|e| e.kind.name == "Result").unwrap();
+    assert_eq!(result.ivars["touching"], Object::Boolean(false));
+    assert_eq!(result.ivars["overlapping"], Object::Boolean(true));
+    assert_eq!(result.ivars["diagonal"], Object::Boolean(false));
+
+    // Without `@width` and `@height`, the size of the last drawn sprite is used
+    let source = r#"
+        entity Dot {
+            var @x, @y;
+            draw { return sprite { ## }; }
+        }
+        entity Checker {
+            var @result;
+            tick {
+                [a, b] = Dot.all().sort_by("x");
+                @result = a.overlaps(b);
+            }
+        }
+        constructor {
+            spawn Dot with { x: 0, y: 0 };
+            spawn Dot with { x: 1, y: 0 };
+            spawn Checker;
+        }
+    "#;
+    let mut interpreter = run(source).unwrap();
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert!(err.to_string().contains("`overlaps` needs entity `Dot` to have"));
+
+    interpreter.execute_draw().unwrap();
+    interpreter.execute_tick().unwrap();
+    let checker = interpreter.entities().find(|e| e.kind.name == "Checker").unwrap();
+    assert_eq!(checker.ivars["result"], Object::Boolean(true));
+}
+
+#[test]
+fn test_sprite_flip() {
+    let original = "(sprite { #.. ##. })";
+    assert_eq!(evaluate(&format!("{original}.flip_x()")).unwrap(), evaluate("sprite { ..# .## }").unwrap());
+    assert_eq!(evaluate(&format!("{original}.flip_y()")).unwrap(), evaluate("sprite { ##. #.. }").unwrap());
+
+    assert_eq!(evaluate(&format!("{original}.flip_x().flip_x()")).unwrap(), evaluate(original).unwrap());
+    assert_eq!(evaluate(&format!("{original}.flip_y().flip_y()")).unwrap(), evaluate(original).unwrap());
+}
+
+#[test]
+fn test_sprite_rotate() {
+    let Object::Sprite(original) = evaluate("sprite { #.. ##. }").unwrap() else { panic!() };
+
+    let rotated = original.rotate90();
+    assert_eq!((rotated.width, rotated.height), (2, 3));
+    assert_eq!(Object::Sprite(rotated.clone()), evaluate("sprite { ## #. .. }").unwrap());
+    assert_eq!(rotated.pixel(1, 0), original.pixel(0, 0));
+    assert_eq!(rotated.pixel(0, 2), original.pixel(2, 1));
+
+    let rotated = original.rotate180();
+    assert_eq!((rotated.width, rotated.height), (3, 2));
+    assert_eq!(Object::Sprite(rotated.clone()), evaluate("sprite { .## ..# }").unwrap());
+    assert_eq!(rotated.pixel(2, 1), original.pixel(0, 0));
+
+    let rotated = original.rotate270();
+    assert_eq!((rotated.width, rotated.height), (2, 3));
+    assert_eq!(Object::Sprite(rotated.clone()), evaluate("sprite { .. .# ## }").unwrap());
+    assert_eq!(rotated.pixel(0, 2), original.pixel(0, 0));
+
+    assert_eq!(original.rotate90().rotate270(), original);
+    assert_eq!(original.rotate90().rotate90(), original.rotate180());
+    assert_eq!(evaluate("(sprite { #.. ##. }).rotate90().width()").unwrap(), Object::Number(2.0));
+}
+
+#[test]
+fn test_sprite_scale() {
+    let scaled = evaluate("(sprite { #. .# }).scale(3)").unwrap();
+    assert_eq!(scaled, evaluate("sprite {
+        ###...
+        ###...
+        ###...
+        ...###
+        ...###
+        ...###
+    }").unwrap());
+    assert_eq!(evaluate("(sprite { #. .# }).scale(3).width()").unwrap(), Object::Number(6.0));
+    assert_eq!(evaluate("(sprite { #. .# }).scale(3).height()").unwrap(), Object::Number(6.0));
+    assert_eq!(evaluate("(sprite { #. }).scale(1.2)").unwrap(), evaluate("sprite { #. }").unwrap());
+
+    assert!(evaluate("(sprite { # }).scale(0)").unwrap_err().to_string().contains("must be a positive integer, not 0"));
+    assert!(evaluate("(sprite { # }).scale(0 - 2)").unwrap_err().to_string().contains("must be a positive integer, not -2"));
+    assert!(evaluate("(sprite { # }).scale()").unwrap_err().to_string().contains("1 parameters, but 0 arguments"));
+    for factor in ["1e30", "1e9", "5000"] {
+        let Err(err) = evaluate(&format!("(sprite {{ ## }}).scale({factor})")) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+        assert!(err.message().contains("2x1 sprite is too large to scale by"));
+    }
+    assert_eq!(evaluate("(sprite { ## }).scale(2000).width()").unwrap(), Object::Number(4000.0));
+    assert!(evaluate("(sprite { # }).width(1)").unwrap_err().to_string().contains("0 parameters, but 1 arguments"));
+}
+
+#[test]
+fn test_sprite_pixels() {
+    let interpreter = run(r#"
+        entity Result {
+            var @line, @corner, @empty, @coloured;
+            constructor {
+                @line = Sprite.blank(4, 4);
+                each i in (4) {
+                    @line = @line.set_pixel(i, i, true);
+                }
+
+                @corner = @line.get_pixel(3, 3);
+                @empty = @line.get_pixel(3, 0);
+                @coloured = @line.set_pixel(1, 0, 8).set_pixel(0, 0, null).get_pixel(1, 0);
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().next().unwrap();
+    assert_eq!(result.ivars["line"], evaluate("sprite { #... .#.. ..#. ...# }").unwrap());
+    assert_eq!(result.ivars["corner"], Object::Number(0.0));
+    assert_eq!(result.ivars["empty"], Object::Null);
+    assert_eq!(result.ivars["coloured"], Object::Number(8.0));
+
+    assert!(evaluate("Sprite.blank(4, 4).get_pixel(4, 1)").unwrap_err().to_string().contains("pixel (4, 1) is outside of 4x4 sprite"));
+    assert!(evaluate("Sprite.blank(4, 2).set_pixel(0, 0 - 1, true)").unwrap_err().to_string().contains("pixel (0, -1) is outside of 4x2 sprite"));
+    assert!(evaluate("Sprite.blank(4, 2).set_pixel(0, 0, 16)").unwrap_err().to_string().contains("colour index from 0 to 15"));
+    assert!(evaluate("Sprite.blank(0, 2)").unwrap_err().to_string().contains("at least 1x1"));
+}
+
+#[test]
+fn test_sprite_overlay() {
+    let base = "(sprite { 111 1.1 111 })";
+    let hat = "(sprite { 8. 88 })";
+    assert_eq!(evaluate(&format!("{base}.overlay({hat}, 1, 1)")).unwrap(), evaluate("sprite { 111 181 188 }").unwrap());
+
+    // Transparent pixels of the overlay leave the base showing through
+    assert_eq!(evaluate(&format!("{hat}.overlay(sprite {{ .. .# }}, 0, 0)")).unwrap(), evaluate("sprite { 8. 8# }").unwrap());
+
+    // Anything outside of the base is clipped
+    assert_eq!(evaluate(&format!("{base}.overlay({hat}, 0 - 1, 0 - 1)")).unwrap(), evaluate("sprite { 811 1.1 111 }").unwrap());
+    assert_eq!(evaluate(&format!("{base}.overlay({hat}, 2, 2)")).unwrap(), evaluate("sprite { 111 1.1 118 }").unwrap());
+    assert_eq!(evaluate(&format!("{base}.overlay({hat}, 5, 0)")).unwrap(), evaluate(base).unwrap());
+    assert_eq!(evaluate(&format!("{base}.overlay({hat}, 0 - 2, 0)")).unwrap(), evaluate(base).unwrap());
+    for (dx, dy) in [("0 - 1e30", "0"), ("1e30", "0"), ("0", "0 - 1e30"), ("1e400", "1e400")] {
+        assert_eq!(evaluate(&format!("{base}.overlay({hat}, {dx}, {dy})")).unwrap(), evaluate(base).unwrap());
+    }
+
+    assert!(evaluate(&format!("{base}.overlay(1, 0, 0)")).unwrap_err().to_string().contains("must be a sprite and two numbers"));
+}
+
+#[test]
+fn test_sprite_crop() {
+    let interpreter = run(r#"
+        const SHEET = sprite {
+            ########........
+            #......#...##...
+            #......#..#..#..
+            #......#.#....#.
+            #......#.#....#.
+            #......#..#..#..
+            #......#...##...
+            ########........
+        };
+        const SQUARE = SHEET.crop(0, 0, 8, 8);
+        const CIRCLE = SHEET.crop(8, 0, 8, 8);
+
+        entity Result {
+            var @square = SQUARE, @circle = CIRCLE;
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().next().unwrap();
+    assert_eq!(result.ivars["square"], evaluate("sprite {
+        ########
+        #......#
+        #......#
+        #......#
+        #......#
+        #......#
+        #......#
+        ########
+    }").unwrap());
+    assert_eq!(result.ivars["circle"], evaluate("sprite {
+        ........
+        ...##...
+        ..#..#..
+        .#....#.
+        .#....#.
+        ..#..#..
+        ...##...
+        ........
+    }").unwrap());
+
+    assert!(evaluate("Sprite.blank(16, 8).crop(9, 0, 8, 8)").unwrap_err().to_string().contains("cannot crop 8x8 area at (9, 0) from 16x8 sprite"));
+    assert!(evaluate("Sprite.blank(16, 8).crop(0, 0 - 1, 8, 8)").unwrap_err().to_string().contains("cannot crop"));
+    assert!(evaluate("Sprite.blank(16, 8).crop(0, 0, 0, 8)").unwrap_err().to_string().contains("cannot crop"));
+}
+
+#[test]
+fn test_sprite_pixels_overlap() {
+    let ring = "(sprite { ### #.# ### })";
+    let dot = "(sprite { # })";
+
+    // The bounding boxes overlap, but the dot is in the ring's hole
+    assert_eq!(evaluate(&format!("{ring}.pixels_overlap({dot}, 1, 1)")).unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate(&format!("{ring}.pixels_overlap({dot}, 2, 1)")).unwrap(), Object::Boolean(true));
+
+    // Offsets work in both directions, and sprites which don't intersect never overlap
+    assert_eq!(evaluate(&format!("{dot}.pixels_overlap({ring}, 0 - 1, 0 - 1)")).unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate(&format!("{dot}.pixels_overlap({ring}, 0 - 2, 0)")).unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate(&format!("{ring}.pixels_overlap({ring}, 3, 0)")).unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate(&format!("{ring}.pixels_overlap({ring}, 2, 2)")).unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate(&format!("{ring}.pixels_overlap({ring}, 10, 0 - 10)")).unwrap(), Object::Boolean(false));
+    for (dx, dy) in [("1e30", "0"), ("0 - 1e30", "0"), ("0", "1e400")] {
+        assert_eq!(evaluate(&format!("{ring}.pixels_overlap({ring}, {dx}, {dy})")).unwrap(), Object::Boolean(false));
+    }
+}
+
+#[test]
+fn test_sound_builders() {
+    let interpreter = run(r#"
+        entity Result {
+            var @original = sound { 0.5: C -> G }, @short, @higher, @lower;
+            constructor {
+                @short = @original.with_duration(0.1);
+                @higher = @original.with_note_offset(13);
+                @lower = @original.with_note_offset(0 - 1);
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let result = interpreter.entities().next().unwrap();
+    let original = Tone {
+        note: Note::new(NoteName::C, false, 4),
+        duration: 0.5,
+        end_note: Some(Note::new(NoteName::G, false, 4)),
+        volume: 1.0,
+    };
+    assert_eq!(result.ivars["original"], Object::Sound(original));
+    assert_eq!(result.ivars["short"], Object::Sound(Tone { duration: 0.1, ..original }));
+    assert_eq!(result.ivars["higher"], Object::Sound(Tone {
+        note: Note::new(NoteName::C, true, 5),
+        end_note: Some(Note::new(NoteName::G, true, 5)),
+        ..original
+    }));
+    assert_eq!(result.ivars["lower"], Object::Sound(Tone {
+        note: Note::new(NoteName::B, false, 3),
+        end_note: Some(Note::new(NoteName::F, true, 4)),
+        ..original
+    }));
+
+    assert!(evaluate("(sound { 0.5: C }).with_duration(0)").unwrap_err().to_string().contains("sound duration must be positive and finite, not 0"));
+    assert!(evaluate("(sound { 0.5: C }).play(1)").unwrap_err().to_string().contains("0 parameters, but 1 arguments"));
+}
+
+#[test]
+fn test_input_just_pressed() {
+    let mut interpreter = run(r#"
+        entity Ship {
+            var @fired = [];
+            tick {
+                @fired.push(Input.z_just_pressed());
+            }
+        }
+        constructor { spawn Ship; }
+    "#).unwrap();
+
+    for z in [true, true, false, true] {
+        interpreter.update_input_report(InputReport { z, ..Default::default() });
+        interpreter.execute_tick().unwrap();
+    }
+
+    let ship = interpreter.entities().next().unwrap();
+    assert_eq!(ship.ivars["fired"], Object::array([true, false, false, true].map(Object::Boolean).to_vec()));
+
+    assert!(evaluate("Input.w_just_pressed()").unwrap_err().to_string().contains("`Input` has no function named `w_just_pressed`"));
+}
+
+#[test]
+fn test_input_released() {
+    let mut interpreter = run(r#"
+        entity Charger {
+            var @released = [];
+            tick {
+                @released.push(Input.x_released());
+            }
+        }
+        constructor { spawn Charger; }
+    "#).unwrap();
+
+    // Press, hold, release, then stay released
+    for x in [true, true, false, false] {
+        interpreter.update_input_report(InputReport { x, ..Default::default() });
+        interpreter.execute_tick().unwrap();
+    }
+
+    let charger = interpreter.entities().next().unwrap();
+    assert_eq!(charger.ivars["released"], Object::array([false, false, true, false].map(Object::Boolean).to_vec()));
+}
+
+#[test]
+fn test_input_directions() {
+    let mut interpreter = run(r#"
+        entity Ship {
+            var @dx, @dy, @moving;
+            tick {
+                @dx = Input.direction_x();
+                @dy = Input.direction_y();
+                @moving = Input.any_direction_pressed();
+            }
+        }
+        constructor { spawn Ship; }
+    "#).unwrap();
+    let mut directions = |report| {
+        interpreter.update_input_report(report);
+        interpreter.execute_tick().unwrap();
+        let ship = interpreter.entities().next().unwrap();
+        (ship.ivars["dx"].clone(), ship.ivars["dy"].clone(), ship.ivars["moving"].clone())
+    };
+
+    assert_eq!(directions(InputReport::default()), (Object::Number(0.0), Object::Number(0.0), Object::Boolean(false)));
+    assert_eq!(directions(InputReport { left: true, down: true, ..Default::default() }), (Object::Number(-1.0), Object::Number(1.0), Object::Boolean(true)));
+    assert_eq!(directions(InputReport { right: true, up: true, ..Default::default() }), (Object::Number(1.0), Object::Number(-1.0), Object::Boolean(true)));
+
+    // Opposing keys cancel out, but are still being pressed
+    assert_eq!(
+        directions(InputReport { left: true, right: true, up: true, down: true, ..Default::default() }),
+        (Object::Number(0.0), Object::Number(0.0), Object::Boolean(true)),
+    );
+}
+
+#[test]
+fn test_input_any_and_by_name() {
+    let mut interpreter = run(r#"
+        entity Title {
+            var @any = [], @any_just = [], @z_by_name = [];
+            tick {
+                @any.push(Input.any_pressed());
+                @any_just.push(Input.any_just_pressed());
+                @z_by_name.push(Input.pressed("z"));
+            }
+        }
+        constructor { spawn Title; }
+    "#).unwrap();
+
+    for report in [
+        InputReport::default(),
+        InputReport { x: true, ..Default::default() },
+        InputReport { x: true, ..Default::default() },
+        InputReport { x: true, z: true, ..Default::default() },
+    ] {
+        interpreter.update_input_report(report);
+        interpreter.execute_tick().unwrap();
+    }
+
+    let booleans = |bs: [bool; 4]| Object::array(bs.map(Object::Boolean).to_vec());
+    let title = interpreter.entities().next().unwrap();
+    assert_eq!(title.ivars["any"], booleans([false, true, true, true]));
+    assert_eq!(title.ivars["any_just"], booleans([false, true, false, true]));
+    assert_eq!(title.ivars["z_by_name"], booleans([false, false, false, true]));
+
+    assert!(evaluate(r#"Input.pressed("space")"#).unwrap_err().to_string().contains("unknown key `space` - valid keys are up, down, left, right, x, z"));
+    assert!(evaluate("Input.any_pressed(1)").unwrap_err().to_string().contains("0 parameters, but 1 arguments"));
+}
+
+#[test]
+fn test_display_helpers() {
+    let declarations = parse(r#"
+        entity Result {
+            var @center, @inside, @right_edge, @negative, @rect_inside, @rect_overhanging;
+            constructor {
+                @center = [Display.center_x(), Display.center_y()];
+                @inside = Display.in_bounds(63, 47);
+                @right_edge = Display.in_bounds(64, 10);
+                @negative = Display.in_bounds(0 - 0.5, 10);
+                @rect_inside = Display.in_bounds_rect(60, 40, 4, 8);
+                @rect_overhanging = Display.in_bounds_rect(61, 40, 4, 8);
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.update_display_config(DisplayConfig { width: 64, height: 48, ..Default::default() });
+    interpreter.execute_init().unwrap();
+
+    let result = interpreter.entities().next().unwrap();
+    assert_eq!(result.ivars["center"], Object::array(vec![Object::Number(32.0), Object::Number(24.0)]));
+    assert_eq!(result.ivars["inside"], Object::Boolean(true));
+    assert_eq!(result.ivars["right_edge"], Object::Boolean(false));
+    assert_eq!(result.ivars["negative"], Object::Boolean(false));
+    assert_eq!(result.ivars["rect_inside"], Object::Boolean(true));
+    assert_eq!(result.ivars["rect_overhanging"], Object::Boolean(false));
+}
+
+#[test]
+fn test_time() {
+    let mut interpreter = run(r#"
+        entity Clock {
+            var @ticks = [];
+            var @fired = [];
+            var @seconds;
+            tick {
+                @ticks.push(Time.ticks());
+                @fired.push(Time.every(3));
+                @seconds = Time.seconds();
+            }
+        }
+        constructor { spawn Clock; }
+    "#).unwrap();
+    interpreter.update_time_config(TimeConfig { ticks_per_second: 4.0 });
+
+    for _ in 0..9 {
+        interpreter.execute_tick().unwrap();
+    }
+
+    let clock = interpreter.entities().next().unwrap();
+    assert_eq!(clock.ivars["ticks"], Object::array((1..=9).map(|t| Object::Number(t as f64)).collect()));
+    assert_eq!(
+        clock.ivars["fired"],
+        Object::array([false, false, true, false, false, true, false, false, true].map(Object::Boolean).to_vec()),
+    );
+
+    // Nine ticks at four ticks per second
+    assert_eq!(clock.ivars["seconds"], Object::Number(2.25));
+
+    assert_eq!(evaluate("Time.ticks()").unwrap(), Object::Number(0.0));
+    assert!(evaluate("Time.every(0)").unwrap_err().to_string().contains("must be a positive integer"));
+    assert!(evaluate("Time.every(1.5)").unwrap_err().to_string().contains("must be a positive integer"));
+}
+
+#[test]
+fn test_text_sprite() {
+    // Three 3-pixel glyphs, with a column of spacing between each
+    let Object::Sprite(score) = evaluate("Text.sprite(120)").unwrap() else { panic!() };
+    assert_eq!((score.width, score.height), (11, 5));
+    assert_eq!(score.pixel(3, 0), Pixel::Clear);
+    assert_eq!(score.pixel(4, 0), Pixel::Set(0));
+
+    let Object::Sprite(negative) = evaluate("Text.sprite(0 - 7)").unwrap() else { panic!() };
+    assert_eq!(negative.width, 7);
+    assert_eq!(negative.pixel(1, 2), Pixel::Set(0));
+    assert_eq!(negative.pixel(1, 0), Pixel::Clear);
+
+    assert!(evaluate("Text.sprite(true)").unwrap_err().to_string().contains("can only render numbers"));
+}
+
+#[test]
+fn test_storage() {
+    let declarations = parse(r#"
+        entity HighScore {
+            var @loaded;
+            constructor {
+                @loaded = Storage.get("best");
+                Storage.set("best", @loaded + 50);
+                Storage.set("name", "AAA");
+            }
+        }
+        constructor { spawn HighScore; }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+
+    // Pretend the host saved this on a previous run
+    interpreter.load_storage(HashMap::from([("best".to_owned(), Object::Number(100.0))]));
+    interpreter.execute_init().unwrap();
+
+    let high_score = interpreter.entities().next().unwrap();
+    assert_eq!(high_score.ivars["loaded"], Object::Number(100.0));
+
+    let writes = interpreter.take_storage_writes();
+    assert_eq!(writes, HashMap::from([
+        ("best".to_owned(), Object::Number(150.0)),
+        ("name".to_owned(), Object::String("AAA".to_owned())),
+    ]));
+    assert!(interpreter.take_storage_writes().is_empty());
+
+    assert_eq!(evaluate(r#"Storage.get("missing")"#).unwrap(), Object::Null);
+    assert!(evaluate(r#"Storage.set("sprite", sprite { # })"#).unwrap_err().to_string().contains("can only store numbers, booleans and strings"));
+}
+
+#[test]
+fn test_camera() {
+    let mut interpreter = run(r#"
+        entity Tree {
+            var @x = 50;
+            var @y = 20;
+            draw { return sprite { # }; }
+        }
+        entity ScoreDisplay {
+            var @x = 1;
+            var @y = 1;
+            draw {
+                Camera.ignore();
+                return sprite { ## };
+            }
+        }
+        constructor {
+            spawn Tree;
+            spawn ScoreDisplay;
+            Camera.set(30, 5);
+        }
+    "#).unwrap();
+
+    let mut positions = interpreter.execute_draw().unwrap().into_iter()
+        .map(|op| (op.sprite.width, op.x, op.y))
+        .collect::<Vec<_>>();
+    positions.sort_by_key(|(width, _, _)| *width);
+    assert_eq!(positions, vec![(1, 20.0, 15.0), (2, 1.0, 1.0)]);
+
+    assert_eq!(evaluate("Camera.x()").unwrap(), Object::Number(0.0));
+    assert!(evaluate("Camera.ignore()").unwrap_err().to_string().contains("can only be called from `draw`"));
+}
+
+#[test]
+fn test_type_predicates() {
+    let predicates = ["is_null", "is_number", "is_boolean", "is_string", "is_entity", "is_array", "is_sprite", "is_sound", "is_melody"];
+    let values = [
+        ("null", "is_null"),
+        ("3", "is_number"),
+        ("true", "is_boolean"),
+        ("\"hi\"", "is_string"),
+        ("this", "is_entity"),
+        ("[1, 2]", "is_array"),
+        ("sprite { # }", "is_sprite"),
+        ("sound { 0.5: C }", "is_sound"),
+        ("melody { 0.5: C }", "is_melody"),
+        ("Math", ""),
+    ];
+
+    for (value, expected) in values {
+        for predicate in predicates {
+            let result = evaluate(&format!("({value}).{predicate}()")).unwrap();
+            assert_eq!(result, Object::Boolean(predicate == expected), "{value}.{predicate}()");
+        }
+    }
+
+    // Entity functions shadow the predicates
+    let interpreter = run(r#"
+        entity Ghost {
+            var @result;
+            constructor { @result = this.is_null(); }
+            func is_null() { return true; }
+        }
+        constructor { spawn Ghost; }
+    "#).unwrap();
+    let ghost = interpreter.entities().next().unwrap();
+    assert_eq!(ghost.ivars["result"], Object::Boolean(true));
+
+    assert!(evaluate("(3).is_number(1)").unwrap_err().to_string().contains("has 0 parameters, but 1 arguments were provided"));
+}
+
+#[test]
+fn test_debug() {
+    let mut interpreter = run(r#"
+        entity Player {
+            var @health = 2;
+            tick {
+                @health = Debug.log(@health - 1);
+                if (@health == 0) {
+                    Debug.warn("out of health");
+                }
+                Debug.assert(@health >= 0, "health went negative");
+            }
+        }
+        constructor { spawn Player; }
+    "#).unwrap();
+
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.take_debug_messages(), vec![
+        DebugMessage::Log("1".to_owned()),
+        DebugMessage::Log("0".to_owned()),
+        DebugMessage::Warning("out of health".to_owned()),
+    ]);
+    assert!(interpreter.take_debug_messages().is_empty());
+
+    // The failing assertion stops the rest of the tick from running
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert!(err.to_string().contains("assertion failed: health went negative"));
+
+    let player = interpreter.entities().next().unwrap();
+    assert_eq!(player.ivars["health"], Object::Number(-1.0));
+}
+
+#[test]
+fn test_color() {
+    let interpreter = run(r#"
+        entity Flag {
+            var @colors;
+            constructor {
+                s = Sprite.blank(3, 1)
+                    .set_pixel(0, 0, Color.red())
+                    .set_pixel(1, 0, Color.white())
+                    .set_pixel(2, 0, Color.index(12));
+                @colors = [s.get_pixel(0, 0), s.get_pixel(1, 0), s.get_pixel(2, 0)];
+            }
+        }
+        constructor { spawn Flag; }
+    "#).unwrap();
+    let flag = interpreter.entities().next().unwrap();
+    assert_eq!(flag.ivars["colors"], Object::array([8.0, 7.0, 12.0].map(Object::Number).to_vec()));
+
+    assert_eq!(evaluate("Color.count()").unwrap(), Object::Number(16.0));
+    assert!(evaluate("Color.index(16)").unwrap_err().to_string().contains("colour index must be a number from 0 to 15"));
+
+    // A host with a smaller palette
+    let declarations = parse(r#"
+        entity Result {
+            var @value;
+            constructor { @value = Color.pink(); }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.update_display_config(DisplayConfig { palette_size: 8, ..Default::default() });
+    let Err(err) = interpreter.execute_init() else { panic!() };
+    assert!(err.to_string().contains("palette only has 8 colours"));
+}
+
+#[test]
+fn test_melody() {
+    let mut interpreter = run(r#"
+        const TUNE = melody { 0.1: C, 0.2: D, 0.1: E };
+
+        entity Jukebox {
+            tick {
+                if (Input.z_just_pressed()) { TUNE.play(); }
+                if (Input.x_just_pressed()) { TUNE.loop(); }
+                if (Input.x_released()) { TUNE.stop(); }
+            }
+        }
+        constructor { spawn Jukebox; }
+    "#).unwrap();
+    interpreter.update_time_config(TimeConfig { ticks_per_second: 10.0 });
+
+    let c = Note::new(NoteName::C, false, Note::DEFAULT_OCTAVE);
+    let d = Note::new(NoteName::D, false, Note::DEFAULT_OCTAVE);
+    let e = Note::new(NoteName::E, false, Note::DEFAULT_OCTAVE);
+    let mut tick = |input: InputReport| {
+        interpreter.update_input_report(input);
+        interpreter.execute_tick().unwrap()
+            .into_iter()
+            .map(|tone| tone.note)
+            .collect::<Vec<_>>()
+    };
+
+    // Tones start 0, 0.1 and 0.3 seconds after the tick `play` was called on
+    let z = InputReport { z: true, ..Default::default() };
+    assert_eq!(tick(z.clone()), vec![c]);
+    assert_eq!(tick(z.clone()), vec![d]);
+    assert_eq!(tick(z.clone()), vec![]);
+    assert_eq!(tick(z.clone()), vec![e]);
+    assert_eq!(tick(z.clone()), vec![]);
+
+    // Looping starts again after the last tone, until stopped
+    let x = InputReport { x: true, ..Default::default() };
+    let looped = (0..6).map(|_| tick(x.clone())).collect::<Vec<_>>();
+    assert_eq!(looped, vec![vec![c], vec![d], vec![], vec![e], vec![c], vec![d]]);
+    assert_eq!(tick(Default::default()), vec![]);
+    assert_eq!(tick(Default::default()), vec![]);
+
+    assert!(evaluate("(melody { 0: C }).loop()").unwrap_err().to_string().contains("cannot loop a melody which has no duration"));
+
+    // Tones can't go backwards in time, or last forever
+    assert!(evaluate("(melody { 0.5: C, -0.5: D }).loop()").unwrap_err().to_string().contains("tone duration must be a finite number which isn't negative, but is -0.5"));
+    assert!(evaluate("melody { 1e400: C }").is_err());
+    assert!(evaluate("sound { -1: C }").is_err());
+
+    // However short its tones are, a melody only plays a limited number of them in each tick
+    let mut interpreter = run(r#"
+        entity Jukebox {
+            constructor { (melody { 0.000001: C, 0.000001: D }).loop(); }
+        }
+        constructor { spawn Jukebox; }
+    "#).unwrap();
+    assert_eq!(interpreter.execute_tick().unwrap().len(), 1);
+    for _ in 0..3 {
+        assert_eq!(interpreter.execute_tick().unwrap().len(), 16);
+    }
+}
+
+#[test]
+fn test_entity_get_set() {
+    let mut interpreter = run(r#"
+        entity Box {
+            var @x = 0;
+            var @y = 0;
+        }
+
+        /* Moves any numeric instance variable of a target entity towards a goal */
+        entity Tween {
+            var @target, @ivar, @goal;
+            tick {
+                current = @target.get(@ivar);
+                if (current < @goal) {
+                    @target.set(@ivar, current + 5);
+                }
+            }
+        }
+
+        constructor {
+            box = spawn Box;
+            spawn Tween with { target: box, ivar: "x", goal: 10 };
+        }
+    "#).unwrap();
+
+    for _ in 0..3 {
+        interpreter.execute_tick().unwrap();
+    }
+    let box_entity = interpreter.entities().find(|e| e.kind.name == "Box").unwrap();
+    assert_eq!(box_entity.ivars["x"], Object::Number(10.0));
+    assert_eq!(box_entity.ivars["y"], Object::Number(0.0));
+
+    assert!(evaluate(r#"this.get("missing")"#).unwrap_err().to_string().contains("undeclared instance variable `missing` on entity `Result`"));
+    assert!(evaluate(r#"this.set("missing", 1)"#).unwrap_err().to_string().contains("undeclared instance variable `missing` on entity `Result`"));
+}
+
+#[test]
+fn test_wrap() {
+    for (value, max, expected) in [
+        ("3", "10", 3.0),
+        ("0 - 1", "10", 9.0),
+        ("10", "10", 0.0),
+        ("35", "10", 5.0),
+        ("0 - 35", "10", 5.0),
+        ("0 - 2.5", "10", 7.5),
+    ] {
+        assert_eq!(evaluate(&format!("Math.wrap({value}, {max})")).unwrap(), Object::Number(expected), "wrap({value}, {max})");
+    }
+    assert!(evaluate("Math.wrap(5, 0)").unwrap_err().to_string().contains("non-positive maximum of 0"));
+
+    let declarations = parse(r#"
+        entity Result {
+            var @value;
+            constructor {
+                @value = [Display.wrap_x(0 - 1), Display.wrap_x(130), Display.wrap_y(0 - 100), Display.wrap_y(47)];
+            }
+        }
+        constructor { spawn Result; }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.update_display_config(DisplayConfig { width: 64, height: 48, ..Default::default() });
+    interpreter.execute_init().unwrap();
+
+    let result = interpreter.entities().next().unwrap();
+    assert_eq!(result.ivars["value"], Object::array([63.0, 2.0, 44.0, 47.0].map(Object::Number).to_vec()));
+}
+
+#[test]
+fn test_sound_volume() {
+    let Object::Sound(quiet) = evaluate("sound { 0.1: C vol 0.3 }").unwrap() else { panic!() };
+    assert_eq!(quiet.volume, 0.3);
+
+    let Object::Sound(bend) = evaluate("sound { 0.1: C -> G vol 0.5 }").unwrap() else { panic!() };
+    assert_eq!((bend.end_note, bend.volume), (Some(Note::new(NoteName::G, false, 4)), 0.5));
+
+    // Volumes outside of 0 to 1 are clamped
+    let Object::Sound(loud) = evaluate("sound { 0.1: C vol 2 }").unwrap() else { panic!() };
+    assert_eq!(loud.volume, 1.0);
+    let Object::Sound(silent) = evaluate("(sound { 0.1: C }).with_volume(0 - 1)").unwrap() else { panic!() };
+    assert_eq!(silent.volume, 0.0);
+
+    // Volume is part of a sound's equality
+    let Object::Sound(full) = evaluate("sound { 0.1: C }").unwrap() else { panic!() };
+    assert_eq!(full.volume, Tone::DEFAULT_VOLUME);
+    assert_ne!(full, quiet);
+    assert_eq!(evaluate("(sound { 0.1: C }).with_volume(0.3) == sound { 0.1: C vol 0.3 }").unwrap(), Object::Boolean(true));
+}
+
+#[test]
+fn test_sprite_rows() {
+    let Object::Sprite(tile) = evaluate("Sprite.from_rows([[true, null, 8], [false, 3, null]])").unwrap() else { panic!() };
+    assert_eq!((tile.width, tile.height), (3, 2));
+    assert_eq!(tile.pixels, vec![Pixel::Set(0), Pixel::Clear, Pixel::Set(8), Pixel::Clear, Pixel::Set(3), Pixel::Clear]);
+
+    // Round trip, with `true` and `false` coming back as colour 0 and `null`
+    assert_eq!(
+        evaluate("Sprite.from_rows([[true, null, 8], [false, 3, null]]).rows()").unwrap(),
+        evaluate("[[0, null, 8], [null, 3, null]]").unwrap(),
+    );
+    assert_eq!(
+        evaluate("Sprite.from_rows((sprite { #. .# }).rows()) == sprite { #. .# }").unwrap(),
+        Object::Boolean(true),
+    );
+
+    assert!(evaluate("Sprite.from_rows([[1, 2], [3]])").unwrap_err().to_string().contains("row 0 has 2 pixels and row 1 has 1"));
+    assert!(evaluate("Sprite.from_rows([])").unwrap_err().to_string().contains("at least one non-empty row"));
+    assert!(evaluate("Sprite.from_rows([[16]])").unwrap_err().to_string().contains("colour index from 0 to 15"));
+}
+
+#[test]
+fn test_describe_entities() {
+    let mut interpreter = run(r#"
+        entity Enemy {
+            var @health = 3;
+            var @path = [];
+            constructor {
+                each i in (20) { @path.push(i); }
+            }
+        }
+        entity Logger {
+            var @enemy;
+            constructor {
+                @enemy = spawn Enemy;
+                Debug.log(Enemy.name(), Enemy);
+                Debug.log(@enemy);
+            }
+            tick {
+                Debug.log(@enemy);
+                if (@enemy.alive()) {
+                    destroy @enemy;
+                }
+            }
+        }
+        constructor { spawn Logger; }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+
+    let messages = interpreter.take_debug_messages();
+    assert_eq!(messages, vec![
+        DebugMessage::Log("Enemy Entity Declaration Enemy".to_owned()),
+        DebugMessage::Log("Entity Enemy #2 (health=3, path=[ 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,...)".to_owned()),
+
+        // Destroyed during the first tick
+        DebugMessage::Log("Entity Enemy #2 (health=3, path=[ 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,...)".to_owned()),
+        DebugMessage::Log("destroyed entity #2".to_owned()),
+    ]);
+
+    assert_eq!(evaluate("Result.name()").unwrap(), Object::String("Result".to_owned()));
+}
+
+#[test]
+fn test_input_mouse() {
+    let mut interpreter = run(r#"
+        entity Cursor {
+            var @x, @y, @clicked, @right_clicked;
+            tick {
+                @x = Input.mouse_x();
+                @y = Input.mouse_y();
+                @clicked = Input.mouse_pressed();
+                @right_clicked = Input.mouse_right_pressed();
+                Debug.log(Input);
+            }
+        }
+        constructor { spawn Cursor; }
+    "#).unwrap();
+
+    interpreter.update_input_report(InputReport { mouse_x: 12.0, mouse_y: 7.0, mouse_left: true, z: true, ..Default::default() });
+    interpreter.execute_tick().unwrap();
+
+    let cursor = interpreter.entities().next().unwrap();
+    assert_eq!(cursor.ivars["x"], Object::Number(12.0));
+    assert_eq!(cursor.ivars["y"], Object::Number(7.0));
+    assert_eq!(cursor.ivars["clicked"], Object::Boolean(true));
+    assert_eq!(cursor.ivars["right_clicked"], Object::Boolean(false));
+    assert_eq!(interpreter.take_debug_messages(), vec![DebugMessage::Log("Input (held: [z, mouse_left], mouse at 12, 7)".to_owned())]);
+
+    // The mouse defaults to the top-left corner, with no buttons held
+    assert_eq!(evaluate("[Input.mouse_x(), Input.mouse_y(), Input.mouse_pressed()]").unwrap(), evaluate("[0, 0, false]").unwrap());
+}
+
+#[test]
+fn test_tick_order_is_spawn_order() {
+    for _ in 0..10 {
+        let mut interpreter = run(r#"
+            entity Walker {
+                var @id;
+                tick {
+                    order = Global.get("order");
+                    order.push(@id);
+                    Global.set("order", order);
+                }
+            }
+            entity Runner {
+                var @id;
+                tick {
+                    order = Global.get("order");
+                    order.push(@id);
+                    Global.set("order", order);
+                }
+            }
+            constructor {
+                Global.set("order", []);
+                spawn Runner with { id: 1 };
+                spawn Walker with { id: 2 };
+                spawn Runner with { id: 3 };
+            }
+        "#).unwrap();
+
+        interpreter.execute_tick().unwrap();
+        interpreter.execute_tick().unwrap();
+        assert_eq!(interpreter.globals["order"], Object::array([1.0, 2.0, 3.0, 1.0, 2.0, 3.0].map(Object::Number).to_vec()));
+    }
+}
+
+#[test]
+fn test_draw_layers() {
+    let mut interpreter = run(r#"
+        entity Layered {
+            var @x = 0, @y = 0, @z, @id;
+            draw { return Sprite.blank(@id, 1); }
+        }
+        constructor {
+            spawn Layered with { z: 2, id: 1 };
+            spawn Layered with { z: 0, id: 2 };
+            spawn Layered with { z: 1, id: 3 };
+            spawn Layered with { id: 4 };
+            spawn Layered with { z: 0 - 1, id: 5 };
+        }
+    "#).unwrap();
+
+    // Sorted by layer, with ties (including a missing layer, which is 0) in spawn order
+    let ids = interpreter.execute_draw().unwrap().into_iter()
+        .map(|op| op.sprite.width)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![5, 2, 4, 3, 1]);
+}
+
+#[test]
+fn test_draw_multiple_sprites() {
+    let mut interpreter = run(r#"
+        entity Tank {
+            var @x = 10, @y = 20;
+            draw {
+                return [
+                    [Sprite.blank(4, 2), 0, 1],
+                    [Sprite.blank(1, 3), 2, 0 - 2],
+                ];
+            }
+        }
+        entity Coin {
+            var @x = 1, @y = 2;
+            draw { return Sprite.blank(2, 2); }
+        }
+        constructor {
+            spawn Tank;
+            spawn Coin;
+        }
+    "#).unwrap();
+
+    let ops = interpreter.execute_draw().unwrap().into_iter()
+        .map(|op| (op.sprite.width, op.sprite.height, op.x, op.y))
+        .collect::<Vec<_>>();
+    assert_eq!(ops, vec![(4, 2, 10.0, 21.0), (1, 3, 12.0, 18.0), (2, 2, 1.0, 2.0)]);
+
+    // The collision area covers every part, from the entity's position
+    let tank = interpreter.entities().find(|e| e.kind.name == "Tank").unwrap();
+    assert_eq!(tank.last_drawn_size, Some((4, 3)));
+
+    for (ret, error) in [
+        ("[[sprite { # }, 1]]", "each part returned from `draw` must be an array of a sprite and two numbers"),
+        ("[sprite { # }]", "each part returned from `draw` must be an array of a sprite and two numbers"),
+        ("3", "it must be a sprite, or an array of `[sprite, dx, dy]` parts"),
+        ("[[sprite { # }, 0, 1e400]]", "part of entity `Broken` at offset (0, inf) is too far away to be drawn"),
+    ] {
+        let mut interpreter = run(&format!("
+            entity Broken {{
+                var @x = 0, @y = 0;
+                draw {{ return {ret}; }}
+            }}
+            constructor {{ spawn Broken; }}
+        ")).unwrap();
+        let Err(err) = interpreter.execute_draw() else { panic!() };
+        assert!(err.to_string().contains(error), "{err}");
+    }
+
+    // Adding the offset to the position can also go too far
+    let mut interpreter = run(r#"
+        entity Far {
+            var @x = 1e308, @y = 0;
+            draw { return [[sprite { # }, 1e308, 0]]; }
+        }
+        constructor { spawn Far; }
+    "#).unwrap();
+    let Err(err) = interpreter.execute_draw() else { panic!() };
+    assert!(err.to_string().contains("too far away to be drawn"));
+
+    // Drawing no parts means the entity has no drawn size any more
+    let mut interpreter = run(r#"
+        entity Blinker {
+            var @x = 0, @y = 0, @visible = true;
+            draw {
+                if (@visible) { return [[Sprite.blank(2, 2), 0, 0]]; }
+                return [];
+            }
+        }
+        constructor { spawn Blinker; }
+    "#).unwrap();
+    interpreter.execute_draw().unwrap();
+    assert_eq!(interpreter.entities().next().unwrap().last_drawn_size, Some((2, 2)));
+    let id = *interpreter.entities.keys().next().unwrap();
+    interpreter.entities.get_mut(&id).unwrap().ivars.insert("visible".to_owned(), Object::Boolean(false));
+    assert!(interpreter.execute_draw().unwrap().is_empty());
+    assert_eq!(interpreter.entities().next().unwrap().last_drawn_size, None);
+}
+
+#[test]
+fn test_destroyed_entities_skip_tick_and_draw() {
+    let mut interpreter = run(r#"
+        entity Hunter {
+            var @target;
+            tick {
+                if (@target.alive()) { destroy @target; }
+            }
+        }
+        entity Target {
+            var @x = 0, @y = 0, @ticks = 0;
+            tick {
+                @ticks = @ticks + 1;
+                Global.set("target_ticks", @ticks);
+            }
+            draw { return sprite { # }; }
+        }
+        entity Doomed {
+            var @x = 0, @y = 0;
+            draw { return sprite { ## }; }
+        }
+        constructor {
+            Global.set("target_ticks", 0);
+            target = spawn Target;
+            spawn Hunter with { target: target };
+            doomed = spawn Doomed;
+            destroy doomed;
+        }
+    "#).unwrap();
+
+    // `Doomed` was destroyed from the top-level constructor, so is never drawn
+    let widths = interpreter.execute_draw().unwrap().into_iter().map(|op| op.sprite.width).collect::<Vec<_>>();
+    assert_eq!(widths, vec![1]);
+
+    // The hunter was spawned after the target, so the target ticks once before being destroyed
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["target_ticks"], Object::Number(1.0));
+    assert!(interpreter.entities().all(|e| e.kind.name == "Hunter"));
+    assert!(interpreter.execute_draw().unwrap().is_empty());
+
+    // Spawned before the target this time, so the target never ticks
+    let mut interpreter = run(r#"
+        entity Hunter {
+            var @target;
+            tick {
+                if (@target.alive()) { destroy @target; }
+            }
+        }
+        entity Target {
+            tick { Global.set("target_ticked", true); }
+        }
+        constructor {
+            Global.set("target_ticked", false);
+            hunter = spawn Hunter;
+            hunter@target = spawn Target;
+        }
+    "#).unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["target_ticked"], Object::Boolean(false));
+    assert_eq!(interpreter.entities().count(), 1);
+}
+
+#[test]
+fn test_spawn_during_tick() {
+    let mut interpreter = run(r#"
+        entity Spawner {
+            var @spawned = false;
+            tick {
+                if (@spawned == false) {
+                    spawn Child;
+                    @spawned = true;
+                }
+            }
+        }
+        entity Child {
+            var @x = 0, @y = 0;
+            constructor { Global.set("child_ticks", 0); }
+            tick { Global.set("child_ticks", Global.get("child_ticks") + 1); }
+            draw { return sprite { # }; }
+        }
+        constructor { spawn Spawner; }
+    "#).unwrap();
+
+    // Spawned during the first tick, so the child's constructor runs straight away, but it
+    // doesn't tick or draw until the second
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["child_ticks"], Object::Number(0.0));
+    assert!(interpreter.execute_draw().unwrap().is_empty());
+
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["child_ticks"], Object::Number(1.0));
+    assert_eq!(interpreter.execute_draw().unwrap().len(), 1);
+
+    // Entities spawned by the top-level constructor are drawn before the first tick
+    let mut interpreter = run(r#"
+        entity Child {
+            var @x = 0, @y = 0;
+            draw { return sprite { # }; }
+        }
+        constructor { spawn Child; }
+    "#).unwrap();
+    assert_eq!(interpreter.execute_draw().unwrap().len(), 1);
+
+    // A tick which fails still ends, so entities spawned afterwards aren't treated as if they
+    // were spawned during it
+    let mut interpreter = run(r#"
+        entity Child {
+            var @x = 0, @y = 0;
+            draw { return sprite { # }; }
+        }
+        entity Broken {
+            tick { x = 1 + true; }
+        }
+        constructor {
+            spawn Child;
+            spawn Broken;
+        }
+    "#).unwrap();
+    assert!(interpreter.execute_tick().is_err());
+    interpreter.execute_init().unwrap();
+    assert_eq!(interpreter.execute_draw().unwrap().len(), 2);
+}
+
+#[test]
+fn test_step_limit() {
+    let declarations = parse("
+        entity Looper {
+            tick {
+                each i in (1000000000) { x = i; }
+            }
+        }
+        constructor { spawn Looper; }
+    ").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.set_step_limit(Some(10_000));
+    interpreter.execute_init().unwrap();
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert!(err.to_string().starts_with("runtime error in Looper.tick (line 4)"));
+    assert!(err.message().contains("exceeded the limit of 10000 steps"));
+
+    // The budget is per tick, so a game which does a modest amount of work each tick can keep
+    // running indefinitely
+    let declarations = parse("
+        entity Counter {
+            var @x = 0;
+            var @y = 0;
+            tick {
+                each i in (10) { @x = @x + i; }
+            }
+            draw {
+                return sprite { # };
+            }
+        }
+        constructor {
+            each i in (5) { spawn Counter; }
+        }
+    ").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.set_step_limit(Some(1_000));
+    interpreter.execute_init().unwrap();
+    for _ in 0..100 {
+        interpreter.execute_tick().unwrap();
+        interpreter.execute_draw().unwrap();
+    }
+    assert!(interpreter.entities().all(|entity| entity.ivars["x"] == Object::Number(4500.0)));
+
+    // The top-level constructor has a budget too
+    let declarations = parse("constructor { repeat { } until (false); }").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.set_step_limit(Some(100));
+    let Err(err) = interpreter.execute_init() else { panic!() };
+    assert!(err.to_string().starts_with("runtime error in top-level constructor"));
+    assert!(err.message().contains("exceeded the limit of 100 steps"));
+}
+
+#[test]
+fn test_error_locations() {
+    let declarations = parse("
+        entity Enemy {
+            var @health = 3;
+
+            func hurt(amount) {
+                @health = @health - amount;
+            }
+
+            tick {
+                hurt(1);
+                hurt(true);
+            }
+        }
+        constructor { spawn Enemy; }
+    ").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+
+    assert_eq!(err.trace.len(), 2);
+    assert_eq!(err.trace[0].function.as_deref(), Some("Enemy.hurt"));
+    assert_eq!((err.trace[0].span.line, err.trace[0].span.column), (6, 17));
+    assert_eq!(err.trace[1].function.as_deref(), Some("Enemy.tick"));
+    assert_eq!((err.trace[1].span.line, err.trace[1].span.column), (11, 17));
+    assert_eq!(err.to_string(), format!(
+        "runtime error in Enemy.hurt (line 6): {}\n    called from Enemy.tick (line 11)",
+        err.message(),
+    ));
+
+    // Statements within nested bodies are located too, and so are constructors run by `spawn`
+    let declarations = parse_file_reporting_all("
+        entity Bullet {
+            constructor {
+                if (true) {
+                    x = null + 1;
+                }
+            }
+        }
+        constructor {
+            spawn Bullet;
+        }
+    ", "bullet.game").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    let Err(err) = interpreter.execute_init() else { panic!() };
+    assert_eq!(err.trace[0].function.as_deref(), Some("Bullet.constructor"));
+    assert_eq!(err.trace[0].span.file.as_deref(), Some("bullet.game"));
+    assert!(err.to_string().starts_with("runtime error in Bullet.constructor (bullet.game:5): "));
+    assert!(err.to_string().ends_with("called from top-level constructor (bullet.game:10)"));
+}
+
+#[test]
+fn test_runtime_error_kinds() {
+    fn kind(expr: &str) -> RuntimeErrorKind {
+        let Err(err) = evaluate(expr) else { panic!("`{expr}` should have failed") };
+        err.kind
+    }
+
+    assert_eq!(kind("foo"), RuntimeErrorKind::UndefinedIdentifier);
+    assert_eq!(kind("spawn Nothing"), RuntimeErrorKind::UndefinedIdentifier);
+    assert_eq!(kind("@missing"), RuntimeErrorKind::UndeclaredIvar);
+    assert_eq!(kind("1 + true"), RuntimeErrorKind::TypeMismatch { expected: "number".to_owned(), found: "boolean".to_owned() });
+    assert_eq!(kind("Math.max(1, \"two\")"), RuntimeErrorKind::TypeMismatch { expected: "number".to_owned(), found: "string".to_owned() });
+    assert_eq!(kind("Math.floor(1, 2)"), RuntimeErrorKind::ArityMismatch { expected: 1, found: 2 });
+    assert_eq!(kind("Math.frobnicate()"), RuntimeErrorKind::NoSuchFunction);
+    assert_eq!(kind("Math.sqrt(0 - 1)"), RuntimeErrorKind::InvalidValue);
+    assert_eq!(kind("Debug.assert(false, \"oops\")"), RuntimeErrorKind::AssertionFailed);
+
+    let mut interpreter = run("
+        entity Ghost {
+            tick { destroy this; }
+        }
+        entity Watcher {
+            var @ghost;
+            constructor { @ghost = spawn Ghost; }
+            tick {
+                if (Time.ticks() == 2) { @ghost.frobnicate(); }
+            }
+        }
+        constructor { spawn Watcher; }
+    ").unwrap();
+    interpreter.execute_tick().unwrap();
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::EntityNotFound);
+
+    let Err(err) = run("func stray() {}") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::InvalidDeclaration);
+}
+
+#[test]
+fn test_multiple_top_level_constructors() {
+    // Like the engine does with each file in the game directory
+    let mut declarations = parse("
+        entity Logger {
+            var @label;
+            constructor { Global.set(\"log\", Global.get(\"log\").concat([@label])); }
+        }
+        constructor {
+            Global.set(\"log\", []);
+            spawn Logger with { label: \"first\" };
+        }
+    ").unwrap();
+    declarations.extend(parse("
+        constructor {
+            spawn Logger with { label: \"second\" };
+        }
+    ").unwrap());
+
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    assert_eq!(interpreter.globals["log"], Object::array(vec![
+        Object::String("first".to_owned()),
+        Object::String("second".to_owned()),
+    ]));
+}
+
+#[test]
+fn test_use_before_declaration() {
+    // The mixin is in a "file" which is loaded after the entity using it
+    let mut declarations = parse("
+        entity Player {
+            use Movable;
+            use Named;
+            var @y = 0;
+        }
+        constructor {
+            player = spawn Player;
+            player.move_right();
+            Global.set(\"x\", player@x);
+        }
+    ").unwrap();
+    declarations.extend(parse("
+        entity Movable {
+            use Positioned;
+            func move_right() { @x = @x + 1; }
+        }
+        entity Positioned {
+            var @x = 5;
+        }
+        entity Named {
+            var @name = \"player\";
+        }
+    ").unwrap());
+
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    assert_eq!(interpreter.globals["x"], Object::Number(6.0));
+
+    // Declaring an entity twice is still an error, wherever the declarations are
+    let Err(err) = Interpreter::with_declarations(&parse("
+        entity Movable {}
+        entity Player { use Movable; }
+        entity Movable {}
+    ").unwrap()) else { panic!() };
+    assert!(err.to_string().contains("duplicate entity declaration `Movable`"));
+}
+
+#[test]
+fn test_use_cycles_and_duplicates() {
+    fn declare(source: &str) -> String {
+        let Err(err) = Interpreter::with_declarations(&parse(source).unwrap()) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidDeclaration);
+        err.to_string()
+    }
+
+    assert!(declare("
+        entity A { use B; }
+        entity B { use C; }
+        entity C { use A; }
+    ").contains("circular `use`: A -> B -> C -> A"));
+    assert!(declare("entity A { use A; }").contains("circular `use`: A -> A"));
+
+    // The cycle is reported from wherever it was entered
+    assert!(declare("
+        entity A { use B; }
+        entity B { }
+        entity C { use D; }
+        entity D { use C; }
+    ").contains("circular `use`: C -> D -> C"));
+
+    assert!(declare("
+        entity Movable {
+            var @x = 0;
+            tick { @x = @x + 1; }
+        }
+        entity Player {
+            use Movable;
+            use Movable;
+        }
+    ").contains("entity declaration `Player` uses `Movable` more than once"));
+}
+
+#[test]
+fn test_use_ivar_conflicts() {
+    let source = |body: &str| format!("
+        entity Movable {{
+            var @x = 0;
+            func step() {{ @x = @x + 1; }}
+        }}
+        entity Jumpable {{
+            var @x = 10;
+            func step() {{ }}
+        }}
+        entity Sized {{
+            var @width = 3;
+        }}
+        entity Player {{
+            {body}
+        }}
+        constructor {{ spawn Player; }}
+    ");
+
+    // Either way around
+    let Err(err) = run(&source("var @x; use Movable;")) else { panic!() };
+    assert!(err.to_string().contains("instance variable `x` declared by both `Player` and used entity `Movable`"));
+    let Err(err) = run(&source("use Movable; var @x;")) else { panic!() };
+    assert!(err.to_string().contains("instance variable `x` declared by both `Player` and used entity `Movable`"));
+
+    let Err(err) = run(&source("use Movable; use Jumpable except step;")) else { panic!() };
+    assert!(err.to_string().contains("instance variable `x` is brought in by both `use Movable` and `use Jumpable`"));
+    let Err(err) = run(&source("use Movable; use Jumpable;")) else { panic!() };
+    assert!(err.to_string().contains("function `step` is brought in by both `use Movable` and `use Jumpable`"));
+
+    let interpreter = run(&source("use Movable; use Sized; var @y = 2;")).unwrap();
+    let player = interpreter.entities().next().unwrap();
+    let names = player.kind.ivars.iter().map(|ivar| ivar.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["x", "width", "y"]);
+    assert_eq!(player.ivars["x"], Object::Number(0.0));
+}
+
+#[test]
+fn test_each_scoping() {
+    // Runs some statements in the top-level constructor, then gets the `result` global
+    fn result(statements: &str) -> InterpreterResult<Object> {
+        Ok(run(&format!("constructor {{ {statements} }}"))?.globals["result"].clone())
+    }
+
+    // Loop variables don't outlive the loop
+    let Err(err) = result("each x, i in (3) {} Global.set(\"result\", x);") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::UndefinedIdentifier);
+    let Err(err) = result("each x, i in (3) {} Global.set(\"result\", i);") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::UndefinedIdentifier);
+
+    // Existing locals are restored afterwards
+    assert_eq!(result("
+        x = 10;
+        i = 20;
+        each x, i in ([1, 2, 3]) {}
+        Global.set(\"result\", x + i);
+    ").unwrap(), Object::Number(30.0));
+
+    // Other locals assigned in the loop are still visible
+    assert_eq!(result("
+        each x in (4) { last = x; }
+        Global.set(\"result\", last);
+    ").unwrap(), Object::Number(3.0));
+
+    // Nested loops reusing a name don't interfere with each other
+    assert_eq!(result("
+        digits = 0;
+        each x in (1 to 3) {
+            each x in (5) {}
+            digits = digits * 10 + x;
+        }
+        Global.set(\"result\", digits);
+    ").unwrap(), Object::Number(12.0));
+}
+
+#[test]
+fn test_name_precedence() {
+    fn declaration_error(source: &str) -> String {
+        let Err(err) = Interpreter::with_declarations(&parse(source).unwrap()) else { panic!() };
+        assert_eq!(err.kind, RuntimeErrorKind::InvalidDeclaration);
+        err.message().to_owned()
+    }
+
+    // Assigning to an entity kind or singleton doesn't create an unreadable local
+    let Err(err) = run("
+        entity Score {}
+        constructor { Score = 10; }
+    ") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::InvalidContext);
+    assert!(err.message().contains("cannot assign to `Score`, which is an entity declaration"));
+    let Err(err) = run("constructor { Math = 10; }") else { panic!() };
+    assert!(err.message().contains("cannot assign to `Math`, which is a built-in singleton"));
+
+    // Declarations can't be hidden by something with higher precedence
+    assert_eq!(declaration_error("entity Math {}"), "entity declaration `Math` has the same name as a built-in singleton");
+    assert_eq!(declaration_error("const Input = 1;"), "const `Input` has the same name as a built-in singleton");
+    assert_eq!(declaration_error("entity Player {} const Player = 1;"), "const `Player` has the same name as an entity declaration");
+    assert_eq!(declaration_error("const Player = 1; entity Player {}"), "const `Player` has the same name as an entity declaration");
+
+    // Parameters are checked against every entity kind, including ones declared later, and every const
+    assert_eq!(
+        declaration_error("entity Player { func hit(Enemy) {} } entity Enemy {}"),
+        "parameter `Enemy` of function `hit` has the same name as an entity declaration",
+    );
+    assert_eq!(
+        declaration_error("entity Player { func hit(LIMIT) {} } const LIMIT = 3;"),
+        "parameter `LIMIT` of function `hit` has the same name as a const",
+    );
+    assert_eq!(
+        declaration_error("const LIMIT = 3; entity Player { func hit(LIMIT) {} }"),
+        "parameter `LIMIT` of function `hit` has the same name as a const",
+    );
+
+    // So are the parameters of handlers
+    assert_eq!(
+        declaration_error("entity Player { on_collision(Enemy) {} } entity Enemy {}"),
+        "parameter `Enemy` of `on_collision` has the same name as an entity declaration",
+    );
+    assert_eq!(
+        declaration_error("entity Player { on_collision(Input) {} }"),
+        "parameter `Input` of `on_collision` has the same name as a built-in singleton",
+    );
+    assert_eq!(
+        declaration_error("const NAME = 1; entity Player { on_message(NAME, args) {} }"),
+        "parameter `NAME` of `on_message` has the same name as a const",
+    );
+    assert_eq!(
+        declaration_error("entity Player { on_message(name, ARGS) {} } const ARGS = 1;"),
+        "parameter `ARGS` of `on_message` has the same name as a const",
+    );
+    assert_eq!(
+        declaration_error("entity Player { on_collision(OTHER) {} } const OTHER = 1;"),
+        "parameter `OTHER` of `on_collision` has the same name as a const",
+    );
+
+    // Loop variables are checked when the loop runs
+    let Err(err) = run("
+        entity Enemy {}
+        constructor { each Enemy in (3) {} }
+    ") else { panic!() };
+    assert_eq!(err.message(), "loop variable `Enemy` has the same name as an entity declaration");
+}
+
+#[test]
+fn test_non_finite_numbers() {
+    let Err(err) = evaluate("1 / 0") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+    assert_eq!(err.message(), "cannot divide 1 by zero");
+    assert!(evaluate("0 / 0").is_err());
+    assert_eq!(evaluate("0 / 5").unwrap(), Object::Number(0.0));
+
+    let Err(err) = evaluate("10 ^ 400") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::InvalidValue);
+    assert_eq!(err.message(), "`10 ^ 400` gave inf, which is not a finite number");
+
+    // A NaN from the host doesn't spread any further
+    let declarations = parse("
+        entity Mover {
+            var @x = 0;
+            tick { @x = @x + Host.bad_speed(); }
+        }
+        constructor { spawn Mover; }
+    ").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.register_function("bad_speed", Box::new(|_, _| Ok(Object::Number(f64::NAN))));
+    interpreter.execute_init().unwrap();
+    let Err(err) = interpreter.execute_tick() else { panic!() };
+    assert_eq!(err.message(), "`0 + NaN` gave NaN, which is not a finite number");
+
+    // Games can opt out of the check, but not out of division by zero
+    interpreter.set_finite_number_checks(false);
+    interpreter.execute_tick().unwrap();
+    let Object::Number(x) = interpreter.entities().next().unwrap().ivars["x"] else { panic!() };
+    assert!(x.is_nan());
+    let mut interpreter = Interpreter::with_declarations(&parse("constructor { x = 1 / 0; }").unwrap()).unwrap();
+    interpreter.set_finite_number_checks(false);
+    assert!(interpreter.execute_init().is_err());
+}
+
+#[test]
+fn test_draw_position_errors() {
+    fn draw_error(ivars: &str, constructor: &str) -> (RuntimeErrorKind, String) {
+        let declarations = parse(&format!("
+            entity Bullet {{
+                {ivars}
+                constructor {{ {constructor} }}
+                draw {{ return sprite {{ # }}; }}
+            }}
+            constructor {{ spawn Bullet; }}
+        ")).unwrap();
+        let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+        interpreter.set_finite_number_checks(false);
+        interpreter.execute_init().unwrap();
+        let Err(err) = interpreter.execute_draw() else { panic!() };
+        let message = err.message().to_owned();
+        (err.kind, message)
+    }
+
+    assert_eq!(
+        draw_error("var @x, @y;", "@x = 0 - 10 ^ 400 + 10 ^ 400; @y = 0;"),
+        (RuntimeErrorKind::InvalidValue, "instance variable `x` of entity `Bullet` is NaN, so it can't be drawn".to_owned()),
+    );
+    assert_eq!(
+        draw_error("var @x;", "@x = 0;"),
+        (RuntimeErrorKind::UndeclaredIvar, "entity `Bullet` must declare `y` to be drawn".to_owned()),
+    );
+    let (kind, message) = draw_error("var @x, @y;", "@x = \"left\"; @y = 0;");
+    assert_eq!(kind, RuntimeErrorKind::TypeMismatch { expected: "number".to_owned(), found: "string".to_owned() });
+    assert_eq!(message, "instance variables `x` and `y` of entity `Bullet` must both be numbers to be drawn");
+    assert_eq!(
+        draw_error("var @x = 0, @y = 0, @z;", "@z = 10 ^ 400;"),
+        (RuntimeErrorKind::InvalidValue, "instance variable `z` of entity `Bullet` is inf, so it can't be drawn".to_owned()),
+    );
+}
+
+#[test]
+fn test_array_sharing() {
+    let interpreter = run(r#"
+        entity Inventory {
+            var @items = [], @copy, @looped = 0;
+
+            constructor {
+                /* Changes through a local or function parameter are seen by the ivar */
+                items = @items;
+                items.push("sword");
+                this.add(@items, "shield");
+
+                @copy = @items.slice(0, @items.length());
+                @copy.push("potion");
+
+                each item in (@items) {
+                    @items.push(item);
+                    @looped = @looped + 1;
+                }
+            }
+
+            func add(list, item) {
+                list.push(item);
+            }
+        }
+        constructor { spawn Inventory; }
+    "#).unwrap();
+    let inventory = interpreter.entities().next().unwrap();
+    let items = ["sword", "shield", "sword", "shield"].map(|s| Object::String(s.to_owned())).to_vec();
+    assert_eq!(inventory.ivars["items"], Object::array(items));
+    let Object::Array(copy) = &inventory.ivars["copy"] else { panic!() };
+    assert_eq!(copy.borrow().len(), 3);
+    assert_eq!(inventory.ivars["looped"], Object::Number(2.0));
+
+    // Self-referential arrays can be described and compared without recursing forever
+    let interpreter = run(r#"
+        constructor {
+            inner = [1];
+            outer = [inner, 2];
+            inner.push(outer);
+            outer.push(outer);
+            Global.set("description", outer.join(" "));
+            Global.set("same", outer == outer);
+            Global.set("contains", outer.contains(outer));
+            Global.set("removed", outer.remove_value(outer));
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["description"], Object::String("[ 1, [ [...], 2, [...] ] ] 2 [ [ 1, [...] ], 2, [...] ]".to_owned()));
+    assert_eq!(interpreter.globals["same"], Object::Boolean(true));
+    assert_eq!(interpreter.globals["contains"], Object::Boolean(true));
+    assert_eq!(interpreter.globals["removed"], Object::Boolean(true));
+
+    // Separate arrays which each contain themselves are compared by their items too
+    let interpreter = run(r#"
+        constructor {
+            a = [1];
+            a.push(a);
+            b = [1];
+            b.push(b);
+            c = [2];
+            c.push(c);
+            Global.set("equal", a == b);
+            Global.set("different", a == c);
+            Global.set("contains", [b].contains(a));
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["equal"], Object::Boolean(true));
+    assert_eq!(interpreter.globals["different"], Object::Boolean(false));
+    assert_eq!(interpreter.globals["contains"], Object::Boolean(true));
+}
+
+#[test]
+fn test_messages() {
+    let declarations = parse(r#"
+        entity Door {
+            var @label;
+
+            on_message(name, args) {
+                Global.get("log").push([@label, name, args]);
+                if (name == "ping") {
+                    this.send("pong");
+                }
+            }
+        }
+
+        entity Switch {
+            var @target, @ticks = 0, @sent;
+
+            tick {
+                @ticks = @ticks + 1;
+                if (@ticks == 1) {
+                    @target.send("open", [1, 2]);
+                    @sent = Door.send_all("close");
+                    @target.send("ping");
+
+                    /* Not delivered yet */
+                    Global.set("log_length_in_tick", Global.get("log").length());
+                }
+                if (@ticks == 3) {
+                    @target.send("lost");
+                    destroy @target;
+                }
+                if (@ticks == 4) {
+                    @target.send("after removal");
+                }
+            }
+        }
+
+        constructor {
+            Global.set("log", []);
+            a = spawn Door with { label: "a" };
+            spawn Door with { label: "b" };
+            spawn Switch with { target: a };
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+
+    let entry = |label: &str, name: &str, args: Vec<Object>| Object::array(vec![
+        Object::String(label.to_owned()),
+        Object::String(name.to_owned()),
+        Object::array(args),
+    ]);
+
+    // Direct messages and broadcasts are delivered after ticking, in the order they were sent
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["log_length_in_tick"], Object::Number(0.0));
+    assert_eq!(interpreter.globals["log"], Object::array(vec![
+        entry("a", "open", vec![Object::Number(1.0), Object::Number(2.0)]),
+        entry("a", "close", vec![]),
+        entry("b", "close", vec![]),
+        entry("a", "ping", vec![]),
+    ]));
+    let switch = interpreter.entities().find(|e| e.kind.name == "Switch").unwrap();
+    assert_eq!(switch.ivars["sent"], Object::Number(2.0));
+
+    // A reply sent from `on_message` arrives on the next tick, but messages to an entity which is
+    // destroyed before delivery, or has been removed, are dropped
+    for _ in 0..3 {
+        interpreter.execute_tick().unwrap();
+    }
+    let Object::Array(log) = &interpreter.globals["log"] else { panic!() };
+    let log = log.borrow();
+    assert_eq!(log.len(), 5);
+    assert_eq!(log[4], entry("a", "pong", vec![]));
+
+    // Messages can only be sent to entities which can receive them
+    let Err(err) = run("
+        entity Wall {}
+        constructor { w = spawn Wall; w.send(\"open\"); }
+    ") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::NoSuchFunction);
+    assert_eq!(err.message(), "entity declaration `Wall` has no `on_message` handler to receive message `open`");
+    let Err(err) = run("
+        entity Door { on_message(name, args) {} }
+        constructor { Door.send_all(\"open\", 3); }
+    ") else { panic!() };
+    assert_eq!(err.message(), "message arguments given to `send_all` must be an array");
+}
+
+#[test]
+fn test_tick_priority() {
+    let declarations = parse(r#"
+        entity Recorder {
+            var @order = [];
+        }
+        entity Late {
+            var @recorder;
+            tick priority 1 { @recorder@order.push("late"); }
+        }
+        entity Early {
+            var @recorder, @label;
+            tick priority -1 { @recorder@order.push(@label); }
+        }
+        entity Normal {
+            var @recorder;
+            tick { @recorder@order.push("normal"); }
+        }
+        constructor {
+            r = spawn Recorder;
+            spawn Late with { recorder: r };
+            spawn Early with { recorder: r, label: "early 1" };
+            spawn Normal with { recorder: r };
+            spawn Early with { recorder: r, label: "early 2" };
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    interpreter.execute_tick().unwrap();
+    let recorder = interpreter.entities().next().unwrap();
+    let order = ["early 1", "early 2", "normal", "late"].map(|s| Object::String(s.to_owned())).to_vec();
+    assert_eq!(recorder.ivars["order"], Object::array(order));
+
+    // Merged `tick` handlers must agree on their priority
+    assert!(Interpreter::with_declarations(&parse("
+        entity Mover { tick priority 2 {} }
+        entity Player { use Mover; tick priority 2 {} }
+    ").unwrap()).is_ok());
+    let Err(err) = Interpreter::with_declarations(&parse("
+        entity Mover { tick priority 2 {} }
+        entity Player { use Mover; tick priority 3 {} }
+    ").unwrap()) else { panic!() };
+    assert_eq!(err.message(), "tick handler of `Player` is given both priority 2 and priority 3");
+}
+
+#[test]
+fn test_sleep() {
+    let declarations = parse(r#"
+        entity Enemy {
+            var @x = 0, @y = 0, @ticks = 0, @draws = 0;
+            tick { @ticks = @ticks + 1; }
+            draw {
+                @draws = @draws + 1;
+                return sprite { # };
+            }
+        }
+        constructor {
+            spawn Enemy;
+            spawn Enemy;
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    let counts = |interpreter: &Interpreter, ivar: &str| interpreter.entities()
+        .map(|e| e.ivars[ivar].clone())
+        .collect::<Vec<_>>();
+    let call = |interpreter: &mut Interpreter, target: Object, name: &str| target.call_function(interpreter, name, vec![]).unwrap();
+
+    let first = Object::Entity(interpreter.entities.keys().next().copied().unwrap());
+    call(&mut interpreter, first.clone(), "sleep");
+    assert_eq!(call(&mut interpreter, first.clone(), "is_asleep"), Object::Boolean(true));
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_draw().unwrap();
+    assert_eq!(counts(&interpreter, "ticks"), vec![Object::Number(0.0), Object::Number(1.0)]);
+    assert_eq!(counts(&interpreter, "draws"), vec![Object::Number(1.0), Object::Number(1.0)]);
+
+    call(&mut interpreter, first.clone(), "wake");
+    interpreter.execute_tick().unwrap();
+    assert_eq!(counts(&interpreter, "ticks"), vec![Object::Number(1.0), Object::Number(2.0)]);
+
+    // Only entities which change state are counted
+    let kind = call(&mut interpreter, first.clone(), "kind");
+    call(&mut interpreter, first.clone(), "sleep");
+    assert_eq!(call(&mut interpreter, kind.clone(), "sleep_all"), Object::Number(1.0));
+    interpreter.execute_tick().unwrap();
+    assert_eq!(counts(&interpreter, "ticks"), vec![Object::Number(1.0), Object::Number(2.0)]);
+    assert_eq!(call(&mut interpreter, kind.clone(), "wake_all"), Object::Number(2.0));
+    assert_eq!(call(&mut interpreter, first, "is_asleep"), Object::Boolean(false));
+}
+
+#[test]
+fn test_reset() {
+    let declarations = parse(r#"
+        entity Enemy {
+            var @x = 0, @y = 0, @target;
+            tick {
+                @x = @x + 1;
+                if (@x == 3) {
+                    spawn Enemy with { x: 10, target: this };
+                    Global.set("ticks", Time.ticks());
+                }
+                if (@x == 5) {
+                    Debug.log("restarting");
+                    Game.restart();
+                }
+            }
+        }
+        constructor {
+            Global.set("started", true);
+            first = spawn Enemy;
+            spawn Enemy with { y: 4, target: first };
+        }
+    "#).unwrap();
+    let snapshot = |interpreter: &Interpreter| {
+        let mut globals = interpreter.globals.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        let entities = interpreter.entities.iter()
+            .map(|(id, e)| {
+                let mut ivars = e.ivars.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+                ivars.sort_by(|a, b| a.0.cmp(&b.0));
+                (*id, e.kind.name.clone(), ivars)
+            })
+            .collect::<Vec<_>>();
+        (entities, globals)
+    };
+
+    let mut fresh = Interpreter::with_declarations(&declarations).unwrap();
+    fresh.execute_init().unwrap();
+    let initial = snapshot(&fresh);
+    fresh.execute_tick().unwrap();
+    let after_one_tick = snapshot(&fresh);
+
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    for _ in 0..4 {
+        interpreter.execute_tick().unwrap();
+        assert!(!interpreter.take_restart_request());
+    }
+    assert_eq!(interpreter.entities.len(), 4);
+
+    // The restart is only requested - nothing changes until the host resets
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.entities.len(), 4);
+    assert!(interpreter.take_restart_request());
+    assert!(!interpreter.take_restart_request());
+
+    // Anything the host hasn't collected yet is cleared too
+    interpreter.reset().unwrap();
+    assert!(interpreter.take_debug_messages().is_empty());
+    assert_eq!(snapshot(&interpreter), initial);
+    interpreter.execute_tick().unwrap();
+    assert_eq!(snapshot(&interpreter), after_one_tick);
+    assert_eq!(interpreter.tick_count, 1);
+
+    let Err(err) = evaluate("Game.restart(1)") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::ArityMismatch { expected: 0, found: 1 });
+}
+
+#[test]
+fn test_save_state() {
+    let source = r#"
+        entity Enemy {
+            var @x = 0, @y = 0, @target, @path;
+            tick { @x = @x + 1; }
+            func target_x() { return @target.x(); }
+        }
+        constructor {
+            doomed = spawn Enemy;
+            path = [1, 2];
+            leader = spawn Enemy with { x: 10, target: doomed, path: path };
+            spawn Enemy with { target: leader, path: path };
+            destroy doomed;
+            Global.set("leader", leader);
+        }
+    "#;
+    let declarations = parse(source).unwrap();
+
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+
+    // Round-trip through a real format, like the host would
+    let state = interpreter.save_state();
+    let json = serde_json::to_string(&state).unwrap();
+    let state: SaveState = serde_json::from_str(&json).unwrap();
+    assert_eq!(state, interpreter.save_state());
+
+    let mut loaded = Interpreter::with_declarations(&declarations).unwrap();
+    loaded.execute_init().unwrap();
+    loaded.load_state(state.clone()).unwrap();
+    assert_eq!(loaded.tick_count, 2);
+
+    // The destroyed entity is gone, so the others are given new IDs from 1
+    let ids = loaded.entities.keys().copied().collect::<Vec<_>>();
+    assert_eq!(ids, vec![EntityId(1), EntityId(2)]);
+    let leader = &loaded.entities[&ids[0]];
+    let follower = &loaded.entities[&ids[1]];
+    assert_eq!(leader.ivars["x"], Object::Number(12.0));
+    assert_eq!(follower.ivars["target"], Object::Entity(ids[0]));
+    assert_eq!(loaded.globals["leader"], Object::Entity(ids[0]));
+
+    // The destroyed entity is still destroyed, and doesn't share an ID with a new one
+    let Object::Entity(doomed) = leader.ivars["target"] else { panic!() };
+    assert!(!loaded.entities.contains_key(&doomed));
+
+    // Arrays which were shared are still shared
+    let (Object::Array(a), Object::Array(b)) = (&leader.ivars["path"], &follower.ivars["path"]) else { panic!() };
+    assert!(Rc::ptr_eq(a, b));
+
+    let call = |interpreter: &mut Interpreter, target: Object, name: &str| target.call_function(interpreter, name, vec![]).unwrap();
+    assert_eq!(call(&mut loaded, Object::Entity(ids[1]), "target_x"), Object::Number(12.0));
+
+    loaded.execute_tick().unwrap();
+    assert_eq!(loaded.entities[&ids[1]].ivars["x"], Object::Number(3.0));
+    assert_eq!(loaded.entities_by_kinds["Enemy"].iter().copied().collect::<Vec<_>>(), ids);
+
+    // Declarations which have changed since the game was saved are reported
+    let load_into = |source: &str| {
+        let mut interpreter = Interpreter::with_declarations(&parse(source).unwrap()).unwrap();
+        interpreter.load_state(state.clone())
+    };
+    let Err(err) = load_into("entity Player { var @x, @y; }") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::UndefinedIdentifier);
+    assert_eq!(err.message(), "saved entity #2 was spawned from entity declaration `Enemy`, which no longer exists");
+    let Err(err) = load_into("entity Enemy { var @x, @y, @target; }") else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::UndeclaredIvar);
+    assert_eq!(err.message(), "saved entity #2 has instance variable `path`, but entity `Enemy` no longer declares it");
+
+    // A failed load leaves the running game alone
+    assert!(loaded.load_state(SaveState { entities: vec![], next_entity_id: 1, globals: [("x".to_owned(), SavedValue::Entity(5))].into(), constants: BTreeMap::new(), arrays: vec![], camera: (0.0, 0.0), tick_count: 0 }).is_err());
+    assert_eq!(loaded.entities.len(), 2);
+
+    // Singletons are saved by name, even ones which describe themselves with their contents
+    let declarations = parse(r#"
+        entity Holder {
+            var @input = Input, @global = Global;
+        }
+        constructor {
+            spawn Holder;
+            Global.set("itself", Global);
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    let json = serde_json::to_string(&interpreter.save_state()).unwrap();
+    let mut loaded = Interpreter::with_declarations(&declarations).unwrap();
+    loaded.load_state(serde_json::from_str(&json).unwrap()).unwrap();
+    let holder = loaded.entities().next().unwrap();
+    assert_eq!(holder.ivars["input"], Object::InputSingleton);
+    assert_eq!(holder.ivars["global"], Object::GlobalSingleton);
+    assert_eq!(loaded.globals["itself"], Object::GlobalSingleton);
+}
+
+#[test]
+fn test_snapshots() {
+    let declarations = parse(r#"
+        entity Walker {
+            var @x = 0, @y = 0, @trail = [];
+            tick {
+                @x = @x + Math.random_int(-2, 2);
+                @trail.push(@x);
+                if (Math.random() < 0.3) {
+                    spawn Walker with { x: @x, trail: @trail };
+                }
+            }
+        }
+        constructor {
+            Math.seed(42);
+            spawn Walker;
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+
+    let mut history = SnapshotHistory::with_capacity(20);
+    history.push(interpreter.snapshot());
+    let mut states = vec![interpreter.save_state()];
+    for _ in 0..10 {
+        interpreter.execute_tick().unwrap();
+        history.push(interpreter.snapshot());
+        states.push(interpreter.save_state());
+    }
+    assert_eq!(history.len(), 11);
+
+    // Going back to frame 5 and ticking again plays out exactly as it did the first time,
+    // including random numbers and arrays which have been changed since
+    let frame_5 = history.get(5).unwrap();
+    assert_eq!(frame_5.tick_count(), 5);
+    interpreter.restore(frame_5).unwrap();
+    assert_eq!(interpreter.save_state(), states[5]);
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.save_state(), states[6]);
+
+    // Restoring doesn't use up the snapshot
+    interpreter.restore(history.get(5).unwrap()).unwrap();
+    assert_eq!(interpreter.save_state(), states[5]);
+    history.discard(5);
+    assert_eq!(history.pop().unwrap().tick_count(), 5);
+
+    // Once the history is full, the oldest snapshots are forgotten
+    let mut history = SnapshotHistory::with_capacity(3);
+    for _ in 0..5 {
+        interpreter.execute_tick().unwrap();
+        history.push(interpreter.snapshot());
+    }
+    assert_eq!(history.len(), 3);
+    assert_eq!(history.get(0).unwrap().tick_count(), 10);
+    assert_eq!(history.get(2).unwrap().tick_count(), 8);
+    assert!(history.get(3).is_none());
+
+    // Messages and destroys which are still waiting when the snapshot is taken aren't lost
+    let declarations = parse(r#"
+        entity Target {
+            var @got = 0, @items;
+            on_message(name, args) {
+                @got = @got + 1;
+                @items = args.pop();
+            }
+        }
+        entity Doomed {
+            on_destroy { Global.set("destroyed", true); }
+        }
+        constructor {
+            items = [1, 2];
+            target = spawn Target;
+            target.send("hit", [items]);
+            items.push(3);
+            destroy spawn Doomed;
+        }
+    "#).unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    let snapshot = interpreter.snapshot();
+    interpreter.execute_tick().unwrap();
+    let original = interpreter.save_state();
+
+    interpreter.restore(&snapshot).unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.save_state(), original);
+    let target = interpreter.entities().next().unwrap();
+    assert_eq!(target.ivars["got"], Object::Number(1.0));
+    assert_eq!(target.ivars["items"], Object::array(vec![Object::Number(1.0), Object::Number(2.0), Object::Number(3.0)]));
+    assert_eq!(interpreter.entities.len(), 1);
+    assert_eq!(interpreter.globals["destroyed"], Object::Boolean(true));
+}
+
+#[test]
+fn test_top_level_consts() {
+    let interpreter = run(r#"
+        const SPEED = 2 * 3;
+        entity Ship {
+            var @x = SPEED;
+            func boosted() { return BOOST + @x; }
+        }
+        constructor {
+            ship = spawn Ship;
+            Global.set("x", ship.x());
+            Global.set("boosted", ship.boosted());
+        }
+        const BOOST = SPEED * 2;
+    "#).unwrap();
+    assert_eq!(interpreter.globals["x"], Object::Number(6.0));
+    assert_eq!(interpreter.globals["boosted"], Object::Number(18.0));
+
+    let Err(err) = Interpreter::with_declarations(&parse("const A = 1; const A = 2;").unwrap()) else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::InvalidDeclaration);
+    assert!(err.to_string().contains("duplicate const declaration `A`"));
+
+    let Err(err) = run("const A = 1; entity E { constructor { A = 2; } } constructor { spawn E; }") else { panic!() };
+    assert!(err.to_string().contains("cannot reassign const `A`"));
+}
+
+#[test]
+fn test_const_arrays_are_restored() {
+    let declarations = parse(r#"
+        const LOG = [];
+        const ALIAS = LOG;
+        const SPEED = 2;
+        entity Logger {
+            var @n;
+            tick {
+                LOG.push(1);
+                @n = ALIAS.length();
+            }
+        }
+        constructor { spawn Logger; }
+    "#).unwrap();
+    let n = |interpreter: &Interpreter| interpreter.entities().next().unwrap().ivars["n"].clone();
+
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.execute_init().unwrap();
+    interpreter.execute_tick().unwrap();
+    let snapshot = interpreter.snapshot();
+    let state = interpreter.save_state();
+    interpreter.execute_tick().unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(n(&interpreter), Object::Number(3.0));
+
+    interpreter.restore(&snapshot).unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(n(&interpreter), Object::Number(2.0));
+
+    // Constants which share an array still share it after a reset
+    interpreter.reset().unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(n(&interpreter), Object::Number(1.0));
+
+    // Only constant arrays are saved, and only loaded into constants which are still arrays
+    assert_eq!(state.constants.keys().collect::<Vec<_>>(), vec!["ALIAS", "LOG"]);
+    let mut loaded = Interpreter::with_declarations(&declarations).unwrap();
+    loaded.load_state(serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap()).unwrap();
+    loaded.execute_tick().unwrap();
+    assert_eq!(n(&loaded), Object::Number(2.0));
+
+    let mut changed = Interpreter::with_declarations(&parse("const LOG = 5; const ALIAS = []; entity Logger { var @n; }").unwrap()).unwrap();
+    changed.load_state(state).unwrap();
+    assert_eq!(changed.constants["LOG"], Object::Number(5.0));
+    assert_eq!(changed.constants["ALIAS"], Object::array(vec![Object::Number(1.0)]));
+}
+
+#[test]
+fn test_trailing_commas() {
+    assert_eq!(evaluate("[1, 2, 3,]").unwrap(), evaluate("[1, 2, 3]").unwrap());
+    assert_eq!(evaluate("[\n    1,\n    2,\n]").unwrap(), evaluate("[1, 2]").unwrap());
+    assert_eq!(evaluate("[]").unwrap(), Object::array(vec![]));
+    assert!(parse("constructor { x = [,]; }").is_err());
+
+    let interpreter = run(r#"
+        entity Adder {
+            var @x, @y,;
+            func add(a, b,) { return a + b; }
+        }
+        constructor {
+            adder = spawn Adder;
+            Global.set("sum", adder.add(1, 2,));
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["sum"], Object::Number(3.0));
+    assert_eq!(interpreter.entities().next().unwrap().ivars.len(), 2);
+}
+
+#[test]
+fn test_bare_function_calls() {
+    let mut interpreter = run(r#"
+        entity Player {
+            var @x = 0;
+            tick {
+                move_right();
+                move_right = 100;
+                move_right();
+                @x = @x + double(1);
+            }
+            func move_right() { @x = @x + 1; }
+        }
+        constructor { spawn Player; }
+    "#).unwrap();
+    interpreter.register_function("double", Box::new(|_, arguments| {
+        let [Object::Number(n)] = arguments.as_slice() else { panic!() };
+        Ok(Object::Number(n * 2.0))
+    }));
+    interpreter.execute_tick().unwrap();
+
+    // The local `move_right` doesn't shadow the function
+    assert_eq!(interpreter.entities().next().unwrap().ivars["x"], Object::Number(4.0));
+
+    let err = evaluate("move_left()").unwrap_err();
+    assert!(err.to_string().contains("no function `move_left` on entity `Result`"));
+    let Err(err) = run("constructor { move_left(); }") else { panic!() };
+    assert!(err.to_string().contains("no function `move_left`"));
+}
+
+#[test]
+fn test_palette_sprites() {
+    let Object::Sprite(sprite) = evaluate("sprite { #.0 \n 9af }").unwrap() else { panic!() };
+    assert_eq!((sprite.width, sprite.height), (3, 2));
+    assert_eq!(sprite.pixels, vec![
+        Pixel::Set(0), Pixel::Clear, Pixel::Set(0),
+        Pixel::Set(9), Pixel::Set(10), Pixel::Set(15),
+    ]);
+
+    assert_eq!(evaluate("sprite { 12 \n 34 }.width()").unwrap(), Object::Number(2.0));
+    assert_eq!(evaluate("sprite { 12 \n 34 }.height()").unwrap(), Object::Number(2.0));
+}
+
+#[test]
+fn test_selective_use() {
+    let source = |filter: &str| format!("
+        entity Movable {{
+            var @x = 0, @ticks = 0;
+            tick {{ @ticks = @ticks + 1; }}
+            func move_left() {{ @x = @x - 1; }}
+            func move_right() {{ @x = @x + 1; }}
+        }}
+        entity Player {{
+            use Movable {filter};
+            tick {{ move_right(); }}
+        }}
+        constructor {{ spawn Player; }}
+    ");
+
+    let mut interpreter = run(&source("except tick")).unwrap();
+    interpreter.execute_tick().unwrap();
+    let player = interpreter.entities().next().unwrap();
+    assert_eq!(player.ivars["x"], Object::Number(1.0));
+    assert_eq!(player.ivars["ticks"], Object::Number(0.0));
+    assert!(player.kind.functions.contains_key("move_left"));
+
+    let mut interpreter = run(&source("only move_right")).unwrap();
+    interpreter.execute_tick().unwrap();
+    let player = interpreter.entities().next().unwrap();
+    assert_eq!(player.ivars["ticks"], Object::Number(0.0));
+    assert!(!player.kind.functions.contains_key("move_left"));
+
+    let Err(err) = run(&source("only move_up")) else { panic!() };
+    assert!(err.to_string().contains("no function or handler named `move_up`"));
+
+    // `draw` is copied too, unless it's filtered out
+    let source = |filter: &str| format!("
+        entity Drawn {{
+            var @x = 0, @y = 0;
+            draw {{ return sprite {{ # }}; }}
+        }}
+        entity Player {{
+            use Drawn {filter};
+        }}
+        constructor {{ spawn Player; }}
+    ");
+    let mut interpreter = run(&source("")).unwrap();
+    assert_eq!(interpreter.execute_draw().unwrap().len(), 1);
+    let mut interpreter = run(&source("except draw")).unwrap();
+    assert!(interpreter.execute_draw().unwrap().is_empty());
+}
+
+#[test]
+fn test_use_overrides() {
+    let source = |body: &str| format!("
+        entity Base {{
+            func shoot() {{ return \"base\"; }}
+            func reload() {{ return \"base\"; }}
+        }}
+        entity Other {{
+            func shoot() {{ return \"other\"; }}
+        }}
+        entity Special {{
+            {body}
+        }}
+        constructor {{
+            special = spawn Special;
+            Global.set(\"results\", [special.shoot(), special.reload()]);
+        }}
+    ");
+    let overridden = Object::array(vec![Object::String("special".to_owned()), Object::String("base".to_owned())]);
+
+    let interpreter = run(&source(r#"use Base; func shoot() { return "special"; }"#)).unwrap();
+    assert_eq!(interpreter.globals["results"], overridden);
+
+    let interpreter = run(&source(r#"func shoot() { return "special"; } use Base;"#)).unwrap();
+    assert_eq!(interpreter.globals["results"], overridden);
+
+    let Err(err) = run(&source("use Base; use Other;")) else { panic!() };
+    assert!(err.to_string().contains("brought in by both `use Base` and `use Other`"));
+
+    // Still a conflict, even though the entity declares its own `shoot` too
+    assert!(run(&source(r#"use Base; use Other; func shoot() { return "special"; }"#)).is_err());
+}
+
+#[test]
+fn test_on_destroy() {
+    let mut interpreter = run(r#"
+        entity Explosion {}
+        entity Enemy {
+            var @points = 10, @buddy;
+            tick { destroy this; }
+            on_destroy {
+                Global.set("score", Global.get("score") + @points);
+                spawn Explosion;
+                (sound { 0.1: A }).play();
+
+                /* The buddy destroys this entity right back, which mustn't loop forever */
+                destroy @buddy;
+            }
+        }
+        entity Buddy {
+            var @enemy;
+            on_destroy {
+                Global.set("buddy_destroyed", true);
+                destroy @enemy;
+            }
+        }
+        constructor {
+            Global.set("score", 0);
+            enemy = spawn Enemy;
+            buddy = spawn Buddy;
+            enemy@buddy = buddy;
+            buddy@enemy = enemy;
+        }
+    "#).unwrap();
+    let sounds = interpreter.execute_tick().unwrap();
+
+    assert_eq!(sounds.len(), 1);
+    assert_eq!(interpreter.globals["score"], Object::Number(10.0));
+    assert_eq!(interpreter.globals["buddy_destroyed"], Object::Boolean(true));
+
+    let entities = interpreter.entities().collect::<Vec<_>>();
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].kind.name, "Explosion");
+}
+
+#[test]
+fn test_on_collision() {
+    let source = |enemy_x: f64| format!("
+        entity Bullet {{
+            var @x = 0, @y = 0, @width = 2, @height = 2;
+            on_collision(other) {{
+                Global.set(\"bullet_hits\", Global.get(\"bullet_hits\") + 1);
+                destroy this;
+            }}
+        }}
+        entity Enemy {{
+            var @x = {enemy_x}, @y = 1;
+            draw {{ return sprite {{ ### ### }}; }}
+            on_collision(bullet) {{
+                Global.set(\"enemy_hit_by\", bullet);
+            }}
+        }}
+        constructor {{
+            Global.set(\"bullet_hits\", 0);
+            Global.set(\"enemy_hit_by\", null);
+            Global.set(\"bullet\", spawn Bullet);
+            spawn Enemy;
+        }}
+    ");
+
+    // Enemy has no size until it has been drawn
+    let mut interpreter = run(&source(1.0)).unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["bullet_hits"], Object::Number(0.0));
+
+    interpreter.execute_draw().unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["bullet_hits"], Object::Number(1.0));
+    assert_eq!(interpreter.globals["enemy_hit_by"], interpreter.globals["bullet"]);
+
+    // The bullet was destroyed, so doesn't collide again
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["bullet_hits"], Object::Number(1.0));
+
+    // Touching edges don't count as overlapping
+    let mut interpreter = run(&source(2.0)).unwrap();
+    interpreter.execute_draw().unwrap();
+    interpreter.execute_tick().unwrap();
+    assert_eq!(interpreter.globals["bullet_hits"], Object::Number(0.0));
+    assert_eq!(interpreter.globals["enemy_hit_by"], Object::Null);
+}
+
+#[test]
+fn test_input_handlers() {
+    let mut interpreter = run(r#"
+        entity Ship {
+            var @shots = 0, @releases = 0, @shots_seen_by_tick = 0;
+            on_press z { @shots = @shots + 1; }
+            on_release z { @releases = @releases + 1; }
+            tick { @shots_seen_by_tick = @shots; }
+        }
+        constructor { spawn Ship; }
+    "#).unwrap();
+
+    for z in [true, true, true, false, true] {
+        interpreter.update_input_report(InputReport { z, ..Default::default() });
+        interpreter.execute_tick().unwrap();
+    }
+
+    let ship = interpreter.entities().next().unwrap();
+    assert_eq!(ship.ivars["shots"], Object::Number(2.0));
+    assert_eq!(ship.ivars["releases"], Object::Number(1.0));
+    assert_eq!(ship.ivars["shots_seen_by_tick"], Object::Number(2.0));
+
+    let Err(err) = run("entity Ship { on_press q { } }") else { panic!() };
+    assert!(err.to_string().contains("unknown key `q`"));
+}
+
+#[test]
+fn test_note_frequencies() {
+    fn assert_frequency(note: Note, expected: f64) {
+        assert!((note.frequency() - expected).abs() < 0.01, "{note:?} was {}", note.frequency());
+    }
+
+    assert_frequency(Note::new(NoteName::A, false, 4), 440.0);
+    assert_frequency(Note::new(NoteName::C, false, 4), 261.63);
+    assert_frequency(Note::new(NoteName::C, false, 5), 523.25);
+    assert_frequency(Note::new(NoteName::F, true, 3), 185.0);
+    assert_frequency(Note::new(NoteName::A, false, 0), 27.5);
+
+    let Object::Sound(tone) = evaluate("sound { 0.5: Fs3 -> C5 }").unwrap() else { panic!() };
+    assert_eq!(tone.note, Note::new(NoteName::F, true, 3));
+    assert_eq!(tone.end_note, Some(Note::new(NoteName::C, false, 5)));
+
+    let Object::Sound(tone) = evaluate("sound { 0.5: Cs }").unwrap() else { panic!() };
+    assert_eq!(tone.note, Note::new(NoteName::C, true, Note::DEFAULT_OCTAVE));
+}
+
+#[test]
+fn test_null_coalesce() {
+    assert_eq!(evaluate("null ?? 5").unwrap(), Object::Number(5.0));
+    assert_eq!(evaluate("3 ?? 5").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("false ?? true").unwrap(), Object::Boolean(false));
+    assert_eq!(evaluate("null ?? null ?? 2").unwrap(), Object::Number(2.0));
+
+    // Binds more loosely than arithmetic and comparisons
+    assert_eq!(evaluate("null ?? 1 + 2").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("(null ?? 1) + 2").unwrap(), Object::Number(3.0));
+    assert_eq!(evaluate("null ?? 1 == 1").unwrap(), Object::Boolean(true));
+    assert_eq!(evaluate("@value ?? 0").unwrap(), Object::Number(0.0));
+
+    // The right side isn't evaluated unless needed
+    assert_eq!(evaluate("1 ?? this.missing()").unwrap(), Object::Number(1.0));
+
+    // Undefined locals are still an error, not null
+    let err = evaluate("missing ?? 1").unwrap_err();
+    assert!(err.to_string().contains("undefined identifier `missing`"));
+}
+
+#[test]
+fn test_destructuring_assignment() {
+    let interpreter = run(r#"
+        entity Player {
+            var @x, @y;
+            constructor { [@x, @y] = this.target_position(); }
+            func target_position() { return [3, 4]; }
+        }
+        constructor {
+            [a, b, c,] = [1, 2, 3];
+            Global.set("sum", a + b + c);
+            spawn Player;
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["sum"], Object::Number(6.0));
+
+    let player = interpreter.entities().next().unwrap();
+    assert_eq!((&player.ivars["x"], &player.ivars["y"]), (&Object::Number(3.0), &Object::Number(4.0)));
+
+    let Err(err) = run("constructor { [a, b] = [1, 2, 3]; }") else { panic!() };
+    assert!(err.to_string().contains("array of length 3 into 2 targets"));
+    let Err(err) = run("constructor { [a, b] = 1; }") else { panic!() };
+    assert!(err.to_string().contains("only arrays can be destructured"));
+}
+
+#[test]
+fn test_each_with_index() {
+    let interpreter = run(r#"
+        constructor {
+            total = 0;
+            each item, i in ([10, 20, 30]) { total = total + item * i; }
+            Global.set("array", total);
+
+            total = 0;
+            each n, i in (4) { total = total + n * 10 + i; }
+            Global.set("number", total);
+
+            total = 0;
+            each n, i in (5 to 8) { total = total + i; }
+            Global.set("range", total);
+        }
+    "#).unwrap();
+
+    assert_eq!(interpreter.globals["array"], Object::Number(80.0));
+    assert_eq!(interpreter.globals["number"], Object::Number(66.0));
+    assert_eq!(interpreter.globals["range"], Object::Number(3.0));
+}
+
+#[test]
+fn test_repeat_until() {
+    let interpreter = run(r#"
+        constructor {
+            once = 0;
+            repeat { once = once + 1; } until (true);
+            Global.set("once", once);
+
+            x = 0;
+            repeat { x = x + 3; } until (x >= 10);
+            Global.set("several", x);
+        }
+    "#).unwrap();
+    assert_eq!(interpreter.globals["once"], Object::Number(1.0));
+    assert_eq!(interpreter.globals["several"], Object::Number(12.0));
+
+    let Err(err) = run("constructor { repeat { } until (1); }") else { panic!() };
+    assert!(err.to_string().contains("until-condition must be a boolean"));
+
+    // Without a step limit, loops can run for as long as they need to
+    let interpreter = run("constructor { x = 0; repeat { x = x + 1; } until (x == 150000); Global.set(\"x\", x); }").unwrap();
+    assert_eq!(interpreter.globals["x"], Object::Number(150000.0));
+
+    // A loop which never finishes is stopped by the step limit
+    let declarations = parse("constructor { repeat { } until (false); }").unwrap();
+    let mut interpreter = Interpreter::with_declarations(&declarations).unwrap();
+    interpreter.set_step_limit(Some(1_000));
+    let Err(err) = interpreter.execute_init() else { panic!() };
+    assert_eq!(err.kind, RuntimeErrorKind::StepLimitExceeded);
+}
+
+#[test]
+fn test_hex_sprites() {
+    let Object::Sprite(hex) = evaluate("sprite hex 8 { FF 81 a5 00 }").unwrap() else { panic!() };
+    let Object::Sprite(pixels) = evaluate("sprite {
+        ########
+        #......#
+        #.#..#.#
+        ........
+    }").unwrap() else { panic!() };
+    assert_eq!(hex, pixels);
+
+    let Object::Sprite(narrow) = evaluate("sprite hex 3 { 5 2 }").unwrap() else { panic!() };
+    assert_eq!(narrow, Sprite { width: 3, height: 2, pixels: vec![
+        Pixel::Set(0), Pixel::Clear, Pixel::Set(0),
+        Pixel::Clear, Pixel::Set(0), Pixel::Clear,
+    ] });
+
+    // Row doesn't fit in the width
+    assert!(parse("constructor { s = sprite hex 3 { 5 8 }; }").is_err());
+    assert!(parse("constructor { s = sprite hex 0 { }; }").is_err());
+}
+
+#[test]
+fn test_spawn_with() {
+    let interpreter = run(r#"
+        entity Enemy {
+            var @x = 0, @y = 0, @health = 3, @max_health = @health, @seen_in_constructor;
+            constructor { @seen_in_constructor = @x; }
+        }
+        constructor {
+            offset = 5;
+            Global.set("enemy", spawn Enemy with { x: 10 + offset, health: 7, });
+        }
+    "#).unwrap();
+
+    let enemy = interpreter.entities().next().unwrap();
+    assert_eq!(enemy.ivars["x"], Object::Number(15.0));
+    assert_eq!(enemy.ivars["y"], Object::Number(0.0));
+    assert_eq!(enemy.ivars["health"], Object::Number(7.0));
+    assert_eq!(enemy.ivars["max_health"], Object::Number(7.0));
+    assert_eq!(enemy.ivars["seen_in_constructor"], Object::Number(15.0));
+
+    let Err(err) = run("entity Enemy { var @x; } constructor { spawn Enemy with { z: 1 }; }") else { panic!() };
+    assert!(err.to_string().contains("undeclared instance variable `z` on entity `Enemy`"));
+}
+
+#[test]
+fn test_comparison_chaining_rejected() {
+    let err = parse("constructor { if (0 < x < 10) { } }").unwrap_err();
+    assert_eq!(err.column, 25);
+    assert!(err.expected.contains("can't be chained"));
+
+    let err = parse("constructor { if (0 < x <= y < 10) { } }").unwrap_err();
+    assert_eq!(err.column, 25);
+
+    let err = parse("constructor { if (a == b > c) { } }").unwrap_err();
+    assert!(err.expected.contains("can't be chained"));
+
+    // Comparing the result for equality is still allowed
+    assert!(parse("constructor { if (0 < x == true) { } }").is_ok());
+    assert!(parse("constructor { if (0 < x && x < 10) { } }").is_ok());
+}
+
+#[test]
+fn test_missing_semicolon() {
+    let err = parse("constructor {\n    x = 1\n    y = 2;\n}").unwrap_err();
+    assert_eq!((err.line, err.column), (2, 10));
+    assert_eq!(err.expected, "`;` after statement");
+
+    let err = parse("constructor { this.foo() }").unwrap_err();
+    assert_eq!((err.line, err.column, err.expected.as_str()), (1, 25, "`;` after statement"));
+    let err = parse("entity A { func f() { return 1 } }").unwrap_err();
+    assert_eq!((err.line, err.column, err.expected.as_str()), (1, 31, "`;` after statement"));
+}
+
+#[test]
+fn test_parse_reporting_all() {
+    let errors = parse_reporting_all("
+        entity A {
+            tick {
+                @x = 1
+                @y = ;
+                if (true) { echo 1 }
+                @z = 3;
+            }
+        }
+        constructor {
+            x = 1
+        }
+    ").unwrap_err();
+
+    let positions = errors.iter().map(|e| (e.line, e.column)).collect::<Vec<_>>();
+    assert_eq!(positions, vec![(4, 23), (5, 22), (6, 35), (11, 18)]);
+
+    // Errors which can't be skipped stop the search
+    let errors = parse_reporting_all("entity { } constructor { x = 1 }").unwrap_err();
+    assert_eq!(errors.len(), 1);
+
+    assert!(parse_reporting_all("constructor { x = 1; }").is_ok());
+}